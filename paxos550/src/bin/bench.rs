@@ -0,0 +1,333 @@
+#[macro_use] extern crate clap;
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate tracing;
+extern crate rand;
+extern crate serde_yaml;
+extern crate tokio;
+extern crate paxos550;
+
+use paxos550::client::{AsyncLockClient, LockClientBuilder};
+use paxos550::config::{self, parse_flag};
+use paxos550::errors::*;
+use paxos550::logging::{self, LogFormat};
+use paxos550::locker::Operation;
+use paxos550::message::MessagePayload;
+use paxos550::paxos::{InstanceID, NodeID};
+use paxos550::server::Status;
+
+use clap::{Arg, App};
+use rand::{FromEntropy, Rng};
+use rand::rngs::SmallRng;
+use tokio::net::UdpSocket;
+use tokio::runtime;
+use tokio::time;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const STATUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+quick_main!(run);
+
+fn run() -> Result<()> {
+    let matches = App::new("Paxos550 Lock Service Benchmark")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Runs concurrent clients that lock and unlock keys for a while, and reports throughput, \
+                latencies and the Paxos instances used.")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .help("Cluster configuration file to read the servers from.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("server")
+            .long("server")
+            .help("Server nodes in `id=addr` format. e.g. node1=127.0.0.1:9001. \
+                   Added to, or override, the servers of --config.")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("prefix")
+            .long("prefix")
+            .help("Prefix of the client names and of the keys, so that runs do not meet each other's locks.")
+            .default_value("bench")
+            .takes_value(true))
+        .arg(Arg::with_name("clients")
+            .long("clients")
+            .help("Number of concurrent clients.")
+            .default_value("10")
+            .takes_value(true))
+        .arg(Arg::with_name("duration")
+            .long("duration")
+            .help("Seconds to run for.")
+            .default_value("10")
+            .takes_value(true))
+        .arg(Arg::with_name("keys")
+            .long("keys")
+            .help("Number of keys the clients lock.")
+            .default_value("100")
+            .takes_value(true))
+        .arg(Arg::with_name("workload")
+            .long("workload")
+            .help("How clients choose keys: any key alike, mostly the hot keys, or with a Zipfian \
+                   distribution.")
+            .possible_values(&["uniform", "hot", "zipf"])
+            .default_value("uniform")
+            .takes_value(true))
+        .arg(Arg::with_name("hot-keys")
+            .long("hot-keys")
+            .help("Number of hot keys, for the hot workload.")
+            .default_value("1")
+            .takes_value(true))
+        .arg(Arg::with_name("hot-ratio")
+            .long("hot-ratio")
+            .help("Fraction of the locks that go to the hot keys, for the hot workload.")
+            .default_value("0.9")
+            .takes_value(true))
+        .arg(Arg::with_name("zipf-exponent")
+            .long("zipf-exponent")
+            .help("Exponent of the Zipfian workload. The higher, the more locks go to the first keys.")
+            .default_value("1.0")
+            .takes_value(true))
+        .arg(Arg::with_name("hold")
+            .long("hold")
+            .help("Milliseconds to hold each lock before unlocking it.")
+            .default_value("0")
+            .takes_value(true))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .help("Milliseconds to wait for a server before asking the next one.")
+            .required(false)
+            .takes_value(true))
+        .get_matches();
+
+    logging::init(LogFormat::Text, "warn")?;
+    let members = matches.values_of("server").into_iter().flatten();
+    let servers = config::servers(matches.value_of("config").map(Path::new), members)?;
+    let keys: usize = parse_flag(matches.value_of("keys").unwrap(), "--keys")?;
+    if keys == 0 {
+        bail!("--keys must be positive");
+    }
+    let workload = match matches.value_of("workload").unwrap() {
+        "uniform" => Workload::Uniform,
+        "hot" => {
+            let hot_keys = parse_flag(matches.value_of("hot-keys").unwrap(), "--hot-keys")?;
+            if hot_keys == 0 || hot_keys > keys {
+                bail!("--hot-keys must be between 1 and --keys");
+            }
+            let ratio = parse_flag(matches.value_of("hot-ratio").unwrap(), "--hot-ratio")?;
+            if !(0.0..=1.0).contains(&ratio) {
+                bail!("--hot-ratio must be between 0 and 1");
+            }
+            Workload::Hot { keys: hot_keys, ratio }
+        },
+        _ => Workload::zipf(keys, parse_flag(matches.value_of("zipf-exponent").unwrap(), "--zipf-exponent")?),
+    };
+    let duration = Duration::try_from_secs_f64(parse_flag(matches.value_of("duration").unwrap(), "--duration")?)
+        .ok()
+        .filter(|duration| !duration.is_zero())
+        .ok_or("--duration must be a positive number of seconds")?;
+    let bench = Bench {
+        prefix: matches.value_of("prefix").unwrap().to_string(),
+        clients: parse_flag(matches.value_of("clients").unwrap(), "--clients")?,
+        duration,
+        keys,
+        workload,
+        hold: Duration::from_millis(parse_flag(matches.value_of("hold").unwrap(), "--hold")?),
+        timeout: matches.value_of("timeout").map(|timeout| parse_flag(timeout, "--timeout")).transpose()?
+            .map(Duration::from_millis),
+    };
+    println!("{} clients, {} keys ({}), {:.1?}", bench.clients, keys, matches.value_of("workload").unwrap(),
+             bench.duration);
+
+    let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
+    runtime.block_on(async {
+        let before = highest_decided(&servers).await?;
+        // the clients finish the operations they started before the end
+        let start = Instant::now();
+        let stats = bench.run(&servers).await?;
+        let elapsed = start.elapsed();
+        let after = highest_decided(&servers).await?;
+        stats.print(elapsed, after.saturating_sub(before));
+        Ok(())
+    })
+}
+
+/// How clients choose the keys they lock.
+enum Workload {
+    Uniform,
+    /// `ratio` of the locks go to the first `keys` keys, the rest to any key.
+    Hot { keys: usize, ratio: f64 },
+    /// Key `k` is chosen with a probability proportional to `1 / (k + 1)^s`. Holds the
+    /// cumulative probabilities.
+    Zipf(Vec<f64>),
+}
+
+impl Workload {
+    fn zipf(keys: usize, exponent: f64) -> Workload {
+        let weights: Vec<f64> = (0..keys).map(|k| 1.0 / ((k + 1) as f64).powf(exponent)).collect();
+        let total: f64 = weights.iter().sum();
+        let cumulative = weights.iter().scan(0.0, |sum, weight| {
+            *sum += weight / total;
+            Some(*sum)
+        }).collect();
+        Workload::Zipf(cumulative)
+    }
+
+    fn key<R: Rng>(&self, rng: &mut R, keys: usize) -> usize {
+        match *self {
+            Workload::Uniform => rng.gen_range(0, keys),
+            Workload::Hot { keys: hot_keys, ratio } if rng.gen::<f64>() < ratio => rng.gen_range(0, hot_keys),
+            Workload::Hot { .. } => rng.gen_range(0, keys),
+            Workload::Zipf(ref cumulative) => {
+                let x = rng.gen::<f64>();
+                cumulative.partition_point(|&p| p < x).min(keys - 1)
+            },
+        }
+    }
+}
+
+struct Bench {
+    prefix: String,
+    clients: usize,
+    duration: Duration,
+    keys: usize,
+    workload: Workload,
+    hold: Duration,
+    timeout: Option<Duration>,
+}
+
+impl Bench {
+    /// Runs the clients until the duration is over and adds up what they measured.
+    async fn run(self, servers: &HashMap<NodeID, SocketAddr>) -> Result<Stats> {
+        let bench = Arc::new(self);
+        let deadline = Instant::now() + bench.duration;
+        let mut tasks = Vec::new();
+        for i in 0..bench.clients {
+            let mut builder = LockClientBuilder::new(format!("{}-client{}", bench.prefix, i))
+                .servers(servers.clone());
+            if let Some(timeout) = bench.timeout {
+                builder = builder.timeout(timeout);
+            }
+            let client = builder.build_async().await?;
+            let bench = bench.clone();
+            tasks.push(tokio::spawn(async move {
+                let mut stats = Stats::default();
+                bench.client(client, deadline, &mut stats).await;
+                stats
+            }));
+        }
+        let mut stats = Stats::default();
+        for task in tasks {
+            stats.add(task.await.chain_err(|| "a client failed")?);
+        }
+        Ok(stats)
+    }
+
+    /// Locks and unlocks keys until `deadline`. The locks have no guards, whose renewals would
+    /// add to the instances the locks and unlocks use.
+    async fn client(&self, mut client: AsyncLockClient, deadline: Instant, stats: &mut Stats) {
+        let mut rng = SmallRng::from_entropy();
+        while Instant::now() < deadline {
+            let key = format!("{}-{}", self.prefix, self.workload.key(&mut rng, self.keys));
+            let start = Instant::now();
+            match client.try_acquire(&key).await {
+                Ok(true) => {
+                    stats.lock_latencies.push(start.elapsed());
+                    stats.acquired += 1;
+                    if self.hold > Duration::from_secs(0) {
+                        time::sleep(self.hold).await;
+                    }
+                    let start = Instant::now();
+                    match client.unlock(&key).await {
+                        Ok(()) => stats.unlock_latencies.push(start.elapsed()),
+                        Err(e) => {
+                            warn!(client = %client.id(), %key, error = %e, "cannot unlock");
+                            stats.errors += 1;
+                        },
+                    }
+                },
+                Ok(false) => {
+                    stats.lock_latencies.push(start.elapsed());
+                    stats.denied += 1;
+                },
+                Err(e) => {
+                    warn!(client = %client.id(), %key, error = %e, "cannot lock");
+                    stats.errors += 1;
+                },
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Stats {
+    /// Locks this client took.
+    acquired: usize,
+    /// Locks another client held.
+    denied: usize,
+    /// Locks and unlocks that failed, e.g. because no server answered.
+    errors: usize,
+    lock_latencies: Vec<Duration>,
+    unlock_latencies: Vec<Duration>,
+}
+
+impl Stats {
+    fn add(&mut self, other: Stats) {
+        self.acquired += other.acquired;
+        self.denied += other.denied;
+        self.errors += other.errors;
+        self.lock_latencies.extend(other.lock_latencies);
+        self.unlock_latencies.extend(other.unlock_latencies);
+    }
+
+    fn print(mut self, duration: Duration, instances: InstanceID) {
+        let operations = self.lock_latencies.len() + self.unlock_latencies.len();
+        let locks = self.acquired + self.denied;
+        println!("operations      {} ({:.1}/s)", operations, operations as f64 / duration.as_secs_f64());
+        println!("locks           {} acquired, {} denied ({:.1}% acquired), {} errors",
+                 self.acquired, self.denied, 100.0 * self.acquired as f64 / locks.max(1) as f64, self.errors);
+        println!("lock latency    {}", percentiles(&mut self.lock_latencies));
+        println!("unlock latency  {}", percentiles(&mut self.unlock_latencies));
+        println!("instances       {} ({:.1} operations each)",
+                 instances, operations as f64 / instances.max(1) as f64);
+    }
+}
+
+fn percentiles(latencies: &mut [Duration]) -> String {
+    if latencies.is_empty() {
+        return "-".to_string();
+    }
+    latencies.sort();
+    let at = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+    format!("p50 {:.1?}  p90 {:.1?}  p99 {:.1?}  p99.9 {:.1?}  max {:.1?}",
+            at(0.5), at(0.9), at(0.99), at(0.999), latencies[latencies.len() - 1])
+}
+
+/// The highest instance any server has decided, which tells how many instances a run used.
+async fn highest_decided(servers: &HashMap<NodeID, SocketAddr>) -> Result<InstanceID> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    let request = serde_yaml::to_vec(&MessagePayload::<Operation>::Status)?;
+    for addr in servers.values() {
+        socket.send_to(&request, addr).await?;
+    }
+    let mut buf = vec![0u8; 65536];
+    let mut highest = None;
+    let mut answered = 0;
+    let deadline = time::Instant::now() + STATUS_TIMEOUT;
+    while answered < servers.len() {
+        let size = match time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            Ok(received) => received?.0,
+            Err(_) => break,
+        };
+        if let Ok(status) = serde_yaml::from_slice::<Status>(&buf[..size]) {
+            answered += 1;
+            highest = highest.max(Some(status.highest_decided));
+        }
+    }
+    highest.ok_or_else(|| ErrorKind::Unreachable("no server sent its status".to_string()).into())
+}
@@ -1,212 +1,556 @@
 #[macro_use] extern crate clap;
+#[macro_use] extern crate error_chain;
 extern crate serde_yaml;
-extern crate rand;
-#[macro_use] extern crate log;
-extern crate env_logger;
+#[macro_use] extern crate serde_json;
+#[macro_use] extern crate tracing;
 extern crate rustyline;
 extern crate paxos550;
 
+use paxos550::client::{LockClient, LockClientBuilder, LockGuard, DEFAULT_REPLY_TIMEOUT};
+use paxos550::config::{self, parse_flag};
+use paxos550::errors::*;
+use paxos550::logging::{self, LogFormat};
 use paxos550::message::*;
 use paxos550::paxos::NodeID;
-use paxos550::locker::{Operation, LogEntry};
+use paxos550::locker::Operation;
+use paxos550::server::{FaultPolicy, Status};
 
-use clap::{Arg, App};
-use rand::Rng;
-use rustyline::Editor;
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
+use error_chain::ChainedError;
+use serde_json::Value;
+use rustyline::DefaultEditor;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
 use std::net::SocketAddr;
-use std::net::UdpSocket;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+// exit codes of the commands that run once
+const EXIT_OK: i32 = 0;
+const EXIT_DENIED: i32 = 1;
+const EXIT_UNREACHABLE: i32 = 2;
+const EXIT_ERROR: i32 = 3;
 
 fn print_usage() {
     println!(r#"USAGE:
-    LOCK <key> [server]           Request to lock <key>
-    UNLOCK <key> [server]         Request to unlock <key>
-    LOG [server]                  Query the log applied by the state machine
-    LOCKS [server]                Query what are locked
+    LOCK <key> [server]           Lock <key>, renewing it until UNLOCK or the end of the shell
+    UNLOCK <key> [server]         Unlock <key>
+    RENEW <key> [server]          Extend the lease of the lock of <key>, if this client holds it
+    LOG [server] [options]        Query the log applied by the state machine
+    LOCKS [server] [options]      Query what are locked
+                                  options: key=<key> offset=<n> limit=<n>
     TOTAL [server]                Query the number of paxos instances
+    LOGLEVEL <server> [level]     Query or set the log level of a server, e.g. debug, which
+                                  it only changes if started with --enable-log-level-changes
+    FAULTS <server> [policy]      Query or set the faults a server started with
+                                  --enable-fault-injection injects, e.g.
+                                  FAULTS server1 {{peers: {{server2: {{disconnected: true}}}}}}
+    STATUS                        Query the status of every server
     "#);
 }
 
-fn main() {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Info)
-        .init();
-
-    let matches = App::new("Paxos550 Lock Service Client")
-        .version(crate_version!())
-        .author(crate_authors!())
-        .about("Starts a interactive lock service client.")
-        .arg(Arg::with_name("id")
-            .long("id")
-            .help("Unique client name")
-            .required(true)
-            .takes_value(true))
-        .arg(Arg::with_name("server")
-            .long("server")
-            .help("Server nodes in `id=addr` format. e.g. node1=127.0.0.1:9001")
-            .required(true)
-            .takes_value(true)
-            .multiple(true))
-        .get_matches();
+/// Reads the options of `LOG` and `LOCKS`, and the server if one is given.
+fn parse_query<'a>(args: &[&'a str]) -> Result<(Option<&'a str>, Query)> {
+    let mut server = None;
+    let mut query = Query::default();
+    for arg in args {
+        let mut split = arg.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some("key"), Some(key)) => query.key = Some(key.to_string()),
+            (Some("offset"), Some(offset)) => {
+                query.offset = offset.parse().chain_err(|| format!("invalid offset '{}'", offset))?;
+            },
+            (Some("limit"), Some(limit)) => {
+                query.limit = Some(limit.parse().chain_err(|| format!("invalid limit '{}'", limit))?);
+            },
+            (Some(name), None) if server.is_none() => server = Some(name),
+            _ => bail!("unexpected '{}'", arg),
+        }
+    }
+    Ok((server, query))
+}
 
-    let node_id = matches.value_of("id").unwrap();
-    let mut servers = HashMap::new();
-    for server in matches.values_of("server").unwrap() {
-        let split: Vec<&str> = server.split('=').collect();
-        assert_eq!(split.len(), 2);
-        servers.insert(String::from(split[0]), split[1].parse::<SocketAddr>().unwrap());
+fn print_page_footer<T>(page: &Page<T>) {
+    let end = page.offset + page.items.len();
+    match page.next_offset() {
+        Some(next) => println!("({}..{} of {}, next page at offset={})", page.offset, end, page.total, next),
+        None => println!("({}..{} of {})", page.offset, end, page.total),
     }
-    let servers_vec: Vec<_> = servers.iter().collect();
+}
 
-    info!("Client id: {}", node_id);
-    for (name, addr) in &servers {
-        info!("Server {}: {}", name, addr);
+/// The shell. Commands that name no server go to the cluster, following the leader and failing
+/// over like any `LockClient`, and those that do go to that server alone.
+struct Shell {
+    id: NodeID,
+    servers: HashMap<NodeID, SocketAddr>,
+    timeout: Duration,
+    client: LockClient,
+    /// A client of each server a command named, which does not fail over to the others.
+    server_clients: HashMap<NodeID, LockClient>,
+    /// The locks taken with `LOCK`, renewed until `UNLOCK`.
+    guards: HashMap<String, LockGuard>,
+}
+
+impl Shell {
+    fn new(id: &str, servers: HashMap<NodeID, SocketAddr>, timeout: Duration) -> Result<Shell> {
+        let client = LockClientBuilder::new(id.to_string()).servers(servers.clone()).timeout(timeout).build()?;
+        Ok(Shell {
+            id: id.to_string(),
+            servers,
+            timeout,
+            client,
+            server_clients: HashMap::new(),
+            guards: HashMap::new(),
+        })
     }
 
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    let mut buf = [0u8; 8192];
-    let mut rl = Editor::<()>::new();
-    let prompt = format!("{}> ", node_id);
-    print_usage();
-    loop {
-        let command = if let Ok(line) = rl.readline(&prompt) {
-            line
-        } else {
-            break;
+    /// The client of `server`, or of the cluster if `None`.
+    fn client(&mut self, server: Option<&str>) -> Result<&mut LockClient> {
+        let server = match server {
+            Some(server) => server,
+            None => return Ok(&mut self.client),
         };
-        rl.add_history_entry(command.as_ref());
-        let args: Vec<_> = command.trim_end().split_whitespace().collect();
-        if args.is_empty() {
-            continue;
+        let addr = *self.servers.get(server).ok_or_else(|| format!("cannot find server {}", server))?;
+        if !self.server_clients.contains_key(server) {
+            let client = LockClientBuilder::new(self.id.clone())
+                .server(server.to_string(), addr)
+                .timeout(self.timeout)
+                .attempts(1)
+                .build()?;
+            self.server_clients.insert(server.to_string(), client);
         }
-        let send = |msg, server: Option<&&str>| {
-            let data = serde_yaml::to_vec(&msg).unwrap();
-            let name = if let Some(name) = server {
-                *name
-            } else {
-                let (name, _) = rand::thread_rng().choose(&servers_vec).unwrap();
-                name.as_str()
-            };
-            match servers.get(name) {
-                None => {
-                    println!("cannot find server {}", name);
-                    return false;
-                },
-                Some(addr) => {
-                    info!("sent to {}: {:?}", addr, msg);
-                    if let Err(e) = socket.send_to(&data, addr) {
-                        println!("error: {}", e);
-                    }
-                },
-            }
-            return true;
-        };
+        Ok(self.server_clients.get_mut(server).unwrap())
+    }
+
+    /// Runs a line of the shell.
+    fn execute(&mut self, args: &[&str]) -> Result<()> {
+        let usage = |usage| Error::from(format!("usage: {}", usage));
         match args[0] {
             "LOCK" => {
-                let key = if let Some(&key) = args.get(1) {
-                    key
-                } else {
-                    println!("usage: LOCK <key> [server]");
-                    continue;
-                };
-                let msg: MessagePayload<Operation> = MessagePayload::LockerMessage(
-                    Operation::Lock(key.into(), node_id.into()));
-                send(msg, args.get(2));
+                let key = *args.get(1).ok_or_else(|| usage("LOCK <key> [server]"))?;
+                if self.guards.contains_key(key) {
+                    bail!("{} is locked by this shell already", key);
+                }
+                let client = self.client(args.get(2).copied())?;
+                match client.try_lock(key)? {
+                    Some(guard) => {
+                        println!("locked {} on {}", key, client.server());
+                        self.guards.insert(key.to_string(), guard);
+                    },
+                    None => println!("{} is locked by another client", key),
+                }
             },
             "UNLOCK" => {
-                let key = if let Some(&key) = args.get(1) {
-                    key
-                } else {
-                    println!("usage: UNLOCK <key> [server]");
-                    continue;
-                };
-                let msg: MessagePayload<Operation> = MessagePayload::LockerMessage(
-                    Operation::Unlock(key.into(), node_id.into()));
-                send(msg, args.get(2));
+                let key = *args.get(1).ok_or_else(|| usage("UNLOCK <key> [server]"))?;
+                match self.guards.remove(key) {
+                    // through the client that locked it
+                    Some(guard) => guard.unlock()?,
+                    None => self.client(args.get(2).copied())?.unlock(key)?,
+                }
+                println!("unlocked {}", key);
+            },
+            "RENEW" => {
+                let key = *args.get(1).ok_or_else(|| usage("RENEW <key> [server]"))?;
+                let client = self.client(args.get(2).copied())?;
+                client.renew(key)?;
+                println!("renewed {} on {}", key, client.server());
             },
             "LOG" => {
-                let msg: MessagePayload<Operation> = MessagePayload::PrintLog;
-                if !send(msg, args.get(1)) {
-                    continue;
-                }
-                let mut len = 0;
-                loop {
-                    match socket.recv_from(&mut buf[len..]) {
-                        Ok((size, addr)) => {
-                            len += size;
-                            let data: Result<Vec<LogEntry>, _> = serde_yaml::from_slice(&buf);
-                            if let Ok(res) = data {
-                                println!("Log from {}:", addr);
-                                for entry in &res {
-                                    println!("{:?}", entry);
-                                }
-                                println!("({} LogEntry in total)", res.len());
-                                break;
-                            }
-                        },
-                        Err(e) => {
-                            println!("error: {}", e);
-                            break;
-                        },
-                    }
+                let (server, query) = parse_query(&args[1..])?;
+                let client = self.client(server)?;
+                let page = client.log(query)?;
+                println!("Log from {}:", client.server());
+                for (index, entry) in &page.items {
+                    println!("{}\t{:?}", index, entry);
                 }
+                print_page_footer(&page);
             },
             "LOCKS" => {
-                let msg: MessagePayload<Operation> = MessagePayload::PrintLocks;
-                if !send(msg, args.get(1)) {
-                    continue;
-                }
-                let mut len = 0;
-                loop {
-                    match socket.recv_from(&mut buf[len..]) {
-                        Ok((size, addr)) => {
-                            len += size;
-                            let data: Result<HashMap<String, NodeID>, _> = serde_yaml::from_slice(&buf[..len]);
-                            if let Ok(res) = data {
-                                println!("Locks from {}:", addr);
-                                for (key, node) in &res {
-                                    println!("{}\t=>\t{}", key, node);
-                                }
-                                break;
-                            }
-                        },
-                        Err(e) => {
-                            println!("error: {}", e);
-                            break;
-                        },
-                    }
+                let (server, query) = parse_query(&args[1..])?;
+                let client = self.client(server)?;
+                let page = client.list_locks(query)?;
+                println!("Locks from {}:", client.server());
+                for (key, node) in &page.items {
+                    println!("{}\t=>\t{}", key, node);
                 }
+                print_page_footer(&page);
             },
             "TOTAL" => {
-                let msg: MessagePayload<Operation> = MessagePayload::PrintTotalInstances;
-                if !send(msg, args.get(1)) {
+                let client = self.client(args.get(1).copied())?;
+                let total = client.total_instances()?;
+                println!("Total instances from {}: {}", client.server(), total);
+            },
+            "LOGLEVEL" => {
+                let server = *args.get(1).ok_or_else(|| usage("LOGLEVEL <server> [level]"))?;
+                let level = self.client(Some(server))?.log_level(args.get(2).map(|level| level.to_string()))?;
+                println!("Log level of {}: {}", server, level);
+            },
+            "FAULTS" => {
+                let server = *args.get(1).ok_or_else(|| usage("FAULTS <server> [policy]"))?;
+                let policy = match args.len() {
+                    2 => None,
+                    _ => Some(serde_yaml::from_str::<FaultPolicy>(&args[2..].join(" "))
+                        .chain_err(|| "invalid fault policy")?),
+                };
+                let policy = self.client(Some(server))?.faults(policy)?;
+                let text = serde_yaml::to_string(&policy)?;
+                println!("Faults of {}:\n{}", server, text.trim_start_matches("---\n"));
+            },
+            "STATUS" => self.print_status()?,
+            "HELP" => print_usage(),
+            _ => bail!("unknown command: {:?}", args),
+        }
+        Ok(())
+    }
+
+    /// Asks every server for its status at once and prints a row per server.
+    fn print_status(&mut self) -> Result<()> {
+        let mut names: Vec<_> = self.servers.keys().cloned().collect();
+        names.sort();
+        for name in &names {
+            self.client(Some(name))?;
+        }
+        let statuses: BTreeMap<_, _> = thread::scope(|scope| {
+            let asks: Vec<_> = self.server_clients.iter_mut()
+                .map(|(name, client)| (name, scope.spawn(move || client.status())))
+                .collect();
+            asks.into_iter().filter_map(|(name, ask)| Some((name.clone(), ask.join().ok()?.ok()?))).collect()
+        });
+
+        println!("{:<10} {:<21} {:<8} {:<10} {:>8} {:>8} {:>9} {:>10} {:>10}  PEERS (last heard)",
+                 "NODE", "ADDRESS", "VERSION", "LEADER", "APPLIED", "DECIDED", "UNDECIDED", "OLDEST", "STORAGE");
+        for name in &names {
+            let status: &Status = match statuses.get(name) {
+                Some(status) => status,
+                None => {
+                    println!("{:<10} {:<21} unreachable", name, self.servers[name]);
                     continue;
+                },
+            };
+            let oldest = status.undecided.values().max()
+                .map_or_else(|| "-".to_string(), |age| format!("{:.1?}", age));
+            let storage = status.storage_bytes.map_or_else(|| "memory".to_string(), |bytes| format!("{}B", bytes));
+            let peers: Vec<_> = status.peers.iter().map(|(peer, s)| match s.last_heard {
+                Some(ago) => format!("{} {:.1?}", peer, ago),
+                None => format!("{} never", peer),
+            }).collect();
+            let leader = status.leader.as_ref().map_or("-", |leader| leader.as_str());
+            println!("{:<10} {:<21} {:<8} {:<10} {:>8} {:>8} {:>9} {:>10} {:>10}  {}",
+                     name, self.servers[name], status.version, leader, status.applied, status.highest_decided,
+                     status.undecided.len(), oldest, storage, peers.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// The commands that run once, given on the command line or in a `--file`.
+fn commands() -> Vec<App<'static, 'static>> {
+    let key = Arg::with_name("key")
+        .help("Key of the lock")
+        .required(true);
+    let query = [
+        Arg::with_name("key")
+            .long("key")
+            .help("Only the entries of this key")
+            .takes_value(true),
+        Arg::with_name("offset")
+            .long("offset")
+            .help("Number of entries to skip")
+            .takes_value(true),
+        Arg::with_name("limit")
+            .long("limit")
+            .help("Most entries to print")
+            .takes_value(true),
+    ];
+    vec![
+        SubCommand::with_name("lock")
+            .about("Locks a key for one lease, unless renewed. Exits with 1 if another client holds it.")
+            .arg(key.clone())
+            .arg(Arg::with_name("wait")
+                .long("wait")
+                .help("Waits while another client holds the lock")),
+        SubCommand::with_name("unlock")
+            .about("Unlocks a key. Exits with 1 if this client does not hold it.")
+            .arg(key.clone()),
+        SubCommand::with_name("renew")
+            .about("Extends the lease of a lock of this client. Exits with 1 if it does not hold it.")
+            .arg(key),
+        SubCommand::with_name("locks")
+            .about("Prints the locks and their owners.")
+            .args(&query),
+        SubCommand::with_name("log")
+            .about("Prints the log applied by the state machine.")
+            .args(&query),
+    ]
+}
+
+fn query(args: &ArgMatches) -> Result<Query> {
+    Ok(Query {
+        offset: args.value_of("offset").map(|offset| parse_flag(offset, "--offset")).transpose()?.unwrap_or(0),
+        limit: args.value_of("limit").map(|limit| parse_flag(limit, "--limit")).transpose()?,
+        key: args.value_of("key").map(String::from),
+    })
+}
+
+/// Runs a command that runs once and returns its output and exit code.
+fn execute(client: &mut LockClient, command: &str, args: &ArgMatches) -> (Value, i32) {
+    let mut output = json!({ "command": command });
+    let code = match execute_command(client, command, args, &mut output) {
+        Ok(code) => code,
+        Err(e) => {
+            let (result, code) = match e.kind() {
+                ErrorKind::Unreachable(_) => ("unreachable", EXIT_UNREACHABLE),
+                _ => ("error", EXIT_ERROR),
+            };
+            output["result"] = json!(result);
+            output["error"] = json!(e.to_string());
+            code
+        },
+    };
+    (output, code)
+}
+
+fn execute_command(client: &mut LockClient, command: &str, args: &ArgMatches, output: &mut Value) -> Result<i32> {
+    if let Some(key) = args.value_of("key").filter(|_| command != "locks" && command != "log") {
+        output["key"] = json!(key);
+    }
+    match command {
+        "lock" => {
+            let key = args.value_of("key").unwrap();
+            // no guard: the lock outlives the client, until its lease runs out
+            let acquired = if args.is_present("wait") {
+                match client.acquire(key) {
+                    Ok(()) => true,
+                    Err(ref e) if matches!(e.kind(), ErrorKind::LockHeld(_)) => false,
+                    Err(e) => return Err(e),
                 }
-                let mut len = 0;
-                loop {
-                    match socket.recv_from(&mut buf[len..]) {
-                        Ok((size, addr)) => {
-                            len += size;
-                            let data: Result<usize, _> = serde_yaml::from_slice(&buf[..len]);
-                            if let Ok(res) = data {
-                                println!("Total instances from {}: {}", addr, res);
-                                break;
-                            }
-                        },
-                        Err(e) => {
-                            println!("error: {}", e);
-                            break;
-                        },
-                    }
-                }
+            } else {
+                client.try_acquire(key)?
+            };
+            if acquired {
+                output["result"] = json!("acquired");
+                return Ok(EXIT_OK);
+            }
+            output["result"] = json!("held");
+            // only to show who holds it, so that a server going away now does not change the result
+            let locks = client.list_locks(Query { key: Some(key.to_string()), ..Query::default() });
+            output["owner"] = json!(locks.ok().and_then(|mut locks| locks.items.pop()).map(|(_, owner)| owner));
+            Ok(EXIT_DENIED)
+        },
+        "unlock" | "renew" => {
+            let key = args.value_of("key").unwrap();
+            let (done, result) = if command == "unlock" {
+                ("released", client.unlock(key))
+            } else {
+                ("held", client.renew(key))
+            };
+            match result {
+                Ok(()) => {
+                    output["result"] = json!(done);
+                    Ok(EXIT_OK)
+                },
+                Err(ref e) if matches!(e.kind(), ErrorKind::LockNotHeld(_)) => {
+                    output["result"] = json!("not_held");
+                    Ok(EXIT_DENIED)
+                },
+                Err(e) => Err(e),
+            }
+        },
+        "locks" => {
+            let page = client.list_locks(query(args)?)?;
+            output["locks"] = page.items.iter().map(|(key, owner)| json!({ "key": key, "owner": owner })).collect();
+            output["offset"] = json!(page.offset);
+            output["total"] = json!(page.total);
+            output["next_offset"] = json!(page.next_offset());
+            Ok(EXIT_OK)
+        },
+        "log" => {
+            let page = client.log(query(args)?)?;
+            output["entries"] = page.items.iter().map(|(index, entry)| {
+                let (op, key, client) = match entry.op {
+                    Operation::Lock(ref key, ref client) => ("lock", key, client),
+                    Operation::Unlock(ref key, ref client) => ("unlock", key, client),
+                    Operation::Renew(ref key, ref client) => ("renew", key, client),
+                };
+                json!({ "index": index, "op": op, "key": key, "client": client, "valid": entry.valid })
+            }).collect();
+            output["offset"] = json!(page.offset);
+            output["total"] = json!(page.total);
+            output["next_offset"] = json!(page.next_offset());
+            Ok(EXIT_OK)
+        },
+        _ => bail!("unknown command {}", command),
+    }
+}
+
+/// Runs the commands of a file, `-` for stdin, one per line. Returns the highest exit code.
+fn execute_file(client: &mut LockClient, path: &str) -> Result<i32> {
+    let input: Box<dyn BufRead> = if path == "-" {
+        Box::new(BufReader::new(io::stdin()))
+    } else {
+        Box::new(BufReader::new(File::open(path).chain_err(|| format!("cannot read {}", path))?))
+    };
+    let mut exit_code = EXIT_OK;
+    for line in input.lines() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = App::new("file")
+            .setting(AppSettings::NoBinaryName)
+            .setting(AppSettings::SubcommandRequired)
+            .setting(AppSettings::ColorNever)
+            .subcommands(commands())
+            .get_matches_from_safe(line.split_whitespace());
+        let (output, code) = match parsed {
+            Ok(matches) => match matches.subcommand() {
+                (command, Some(args)) => execute(client, command, args),
+                _ => unreachable!("a command is required"),
             },
-            "HELP" => {
-                print_usage();
+            Err(e) => {
+                // without the usage that follows
+                let message = e.message.split("USAGE:").next().unwrap_or_default();
+                let error = message.trim_start_matches("error:").split_whitespace().collect::<Vec<_>>().join(" ");
+                (json!({ "line": line, "result": "error", "error": error }), EXIT_ERROR)
             },
-            _ => {
-                println!("unknown command: {:?}", args);
-            }
+        };
+        println!("{}", output);
+        exit_code = exit_code.max(code);
+    }
+    Ok(exit_code)
+}
+
+quick_main!(run);
+
+fn run() -> Result<i32> {
+    let matches = App::new("Paxos550 Lock Service Client")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Starts a interactive lock service client, or runs a command and exits with 0 on success, \
+                1 if the lock is held by another client (or not held by this one), 2 if no server answered \
+                and 3 on any other error.")
+        .arg(Arg::with_name("id")
+            .long("id")
+            .help("Unique client name")
+            .required(true)
+            .takes_value(true))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .help("Cluster configuration file to read the servers from.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("server")
+            .long("server")
+            .help("Server nodes in `id=addr` format. e.g. node1=127.0.0.1:9001. \
+                   Added to, or override, the servers of --config.")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("file")
+            .long("file")
+            .help("Runs the commands of a file, or of stdin if `-`, one per line like `lock <key>`, \
+                   and exits with the highest exit code.")
+            .required(false)
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("status")
+            .about("Prints the status of every server and exits."))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .help("Milliseconds to wait for a server before asking the next one.")
+            .required(false)
+            .takes_value(true))
+        .subcommands(commands())
+        .get_matches_safe();
+    let matches = match matches {
+        Ok(matches) => matches,
+        // --help and --version exit with 0
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => {
+            eprintln!("{}", e.message);
+            return Ok(EXIT_ERROR);
+        },
+    };
+
+    let interactive = matches.subcommand_name().is_none() && !matches.is_present("file");
+    logging::init(LogFormat::Text, if interactive { "info" } else { "warn" })?;
+    if interactive || matches.subcommand_name() == Some("status") {
+        return start(&matches).map(|_| EXIT_OK);
+    }
+    // keeps the exit codes of the commands apart from those of other errors
+    match start_once(&matches) {
+        Ok(code) => Ok(code),
+        Err(e) => {
+            eprintln!("{}", e.display_chain());
+            Ok(EXIT_ERROR)
+        },
+    }
+}
+
+/// The servers and the reply timeout the options ask for.
+fn servers_and_timeout(matches: &ArgMatches) -> Result<(HashMap<NodeID, SocketAddr>, Duration)> {
+    let members = matches.values_of("server").into_iter().flatten();
+    let servers = config::servers(matches.value_of("config").map(Path::new), members)?;
+    let timeout = matches.value_of("timeout").map(|timeout| parse_flag(timeout, "--timeout")).transpose()?
+        .map_or(DEFAULT_REPLY_TIMEOUT, Duration::from_millis);
+    Ok((servers, timeout))
+}
+
+/// The client the options ask for.
+fn builder(matches: &ArgMatches) -> Result<LockClientBuilder> {
+    let (servers, timeout) = servers_and_timeout(matches)?;
+    Ok(LockClientBuilder::new(matches.value_of("id").unwrap().to_string()).servers(servers).timeout(timeout))
+}
+
+/// Runs a command, or the commands of a file.
+fn start_once(matches: &ArgMatches) -> Result<i32> {
+    let mut client = builder(matches)?.build()?;
+    match (matches.value_of("file"), matches.subcommand_name()) {
+        (Some(path), None) => return execute_file(&mut client, path),
+        (Some(_), Some(_)) => bail!("--file cannot be used with a command"),
+        _ => (),
+    }
+    match matches.subcommand() {
+        (command, Some(args)) => {
+            let (output, code) = execute(&mut client, command, args);
+            println!("{}", output);
+            Ok(code)
+        },
+        _ => unreachable!("no command"),
+    }
+}
+
+/// Runs the shell, or prints the status of the cluster.
+fn start(matches: &ArgMatches) -> Result<()> {
+    let node_id = matches.value_of("id").unwrap();
+    let (servers, timeout) = servers_and_timeout(matches)?;
+
+    info!(client = node_id, "started");
+    for (name, addr) in &servers {
+        info!(server = %name, %addr, "server");
+    }
+
+    let mut shell = Shell::new(node_id, servers, timeout)?;
+    if matches.subcommand_matches("status").is_some() {
+        return shell.print_status();
+    }
+    let mut rl = DefaultEditor::new().chain_err(|| "cannot start the line editor")?;
+    let prompt = format!("{}> ", node_id);
+    print_usage();
+    while let Ok(command) = rl.readline(&prompt) {
+        let _ = rl.add_history_entry(command.as_str());
+        let args: Vec<_> = command.split_whitespace().collect();
+        if args.is_empty() {
+            continue;
+        }
+        if let Err(e) = shell.execute(&args) {
+            println!("error: {}", e);
         }
     }
-}
\ No newline at end of file
+    // the guards unlock what LOCK took
+    Ok(())
+}
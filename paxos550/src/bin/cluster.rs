@@ -0,0 +1,171 @@
+#[macro_use] extern crate clap;
+#[macro_use] extern crate error_chain;
+extern crate rustyline;
+extern crate serde_yaml;
+extern crate paxos550;
+
+use paxos550::cluster::{Launch, LocalCluster, LocalClusterBuilder};
+use paxos550::config::{parse_flag, ServerConfig};
+use paxos550::errors::*;
+use paxos550::logging::{self, LogFormat};
+use paxos550::server::FaultPolicy;
+
+use clap::{Arg, App};
+use rustyline::DefaultEditor;
+
+use std::env;
+use std::path::PathBuf;
+
+quick_main!(run);
+
+fn print_usage() {
+    println!(r#"USAGE:
+    STATUS                          Show the servers and the partition
+    KILL <server>                   Stop a server
+    RESTART <server>                Start a killed server again with the Paxos state it saved
+    PARTITION <server,...> ...      Split the servers into groups that cannot reach each other,
+                                    the servers in no group making up one more
+    HEAL                            Let every server reach every other again
+    PAUSE <server>                  Hold what a server receives, as if its process was stopped
+    RESUME <server>                 Let a paused server go on
+    FAULTS <server> [policy]        Show or set the faults of a server, e.g.
+                                    FAULTS server1 {{default: {{drop_rate: 0.1, max_delay_ms: 50}}}}
+    QUIT                            Stop every server and exit
+    "#);
+}
+
+fn run() -> Result<()> {
+    let matches = App::new("Paxos550 Local Cluster")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Starts servers on free localhost ports, writes their configuration for clients, and \
+                kills, restarts, pauses and partitions them from a prompt.")
+        .arg(Arg::with_name("servers")
+            .long("servers")
+            .help("Number of servers.")
+            .default_value("3")
+            .takes_value(true))
+        .arg(Arg::with_name("processes")
+            .long("processes")
+            .help("Runs each server as a child process of the `server` binary next to this one, \
+                   instead of in this process."))
+        .arg(Arg::with_name("dir")
+            .long("dir")
+            .help("Directory to write the configuration, the logs and the storage of the servers to. \
+                   A temporary one, removed on exit, if not given.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .help("Milliseconds to wait for each phase of Paxos.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("redirect-clients")
+            .long("redirect-clients")
+            .help("Redirects the operations of clients to the leader."))
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .help("Log level of the servers that run in this process. RUST_LOG overrides it.")
+            .default_value("warn")
+            .takes_value(true))
+        .get_matches();
+
+    logging::init(LogFormat::Text, matches.value_of("log-level").unwrap())?;
+    let mut server = ServerConfig {
+        redirect_clients: matches.is_present("redirect-clients"),
+        ..ServerConfig::default()
+    };
+    if let Some(timeout) = matches.value_of("timeout") {
+        server.timeout_ms = parse_flag(timeout, "--timeout")?;
+    }
+    let mut builder = LocalClusterBuilder::new(parse_flag(matches.value_of("servers").unwrap(), "--servers")?)
+        .server_config(server);
+    if matches.is_present("processes") {
+        let binary = env::current_exe()?.with_file_name(format!("server{}", env::consts::EXE_SUFFIX));
+        builder = builder.launch(Launch::Processes(binary));
+    }
+    if let Some(dir) = matches.value_of("dir") {
+        builder = builder.dir(PathBuf::from(dir));
+    }
+    let mut cluster = builder.start()?;
+
+    let path = cluster.config_path().display().to_string();
+    println!("Started {} servers. Clients can connect with:", cluster.ids().len());
+    println!("    client --config {} --id client1", path);
+    print_usage();
+    let mut rl = DefaultEditor::new().chain_err(|| "cannot start the line editor")?;
+    while let Ok(command) = rl.readline("cluster> ") {
+        let _ = rl.add_history_entry(command.as_str());
+        let args: Vec<_> = command.split_whitespace().collect();
+        let result = match args.first().map(|command| command.to_uppercase()).as_deref() {
+            None => continue,
+            Some("STATUS") => {
+                print_status(&mut cluster);
+                Ok(())
+            },
+            Some("KILL") => match args.get(1) {
+                Some(id) => cluster.kill(id),
+                None => Err("usage: KILL <server>".into()),
+            },
+            Some("RESTART") => match args.get(1) {
+                Some(id) => cluster.restart(id),
+                None => Err("usage: RESTART <server>".into()),
+            },
+            Some("PARTITION") if args.len() > 1 => {
+                let groups: Vec<Vec<String>> = args[1..].iter()
+                    .map(|group| group.split(',').filter(|id| !id.is_empty()).map(String::from).collect())
+                    .collect();
+                cluster.partition(&groups)
+            },
+            Some("PARTITION") => Err("usage: PARTITION <server,...> ...".into()),
+            Some("HEAL") => cluster.heal(),
+            Some("PAUSE") => match args.get(1) {
+                Some(id) => cluster.pause(id),
+                None => Err("usage: PAUSE <server>".into()),
+            },
+            Some("RESUME") => match args.get(1) {
+                Some(id) => cluster.resume(id),
+                None => Err("usage: RESUME <server>".into()),
+            },
+            Some("FAULTS") => match args.get(1) {
+                Some(id) if args.len() > 2 => serde_yaml::from_str(&args[2..].join(" "))
+                    .map_err(|e| format!("invalid fault policy: {}", e).into())
+                    .and_then(|faults| cluster.set_faults(id, faults)),
+                Some(id) => cluster.config().node(id).map(|_| print_faults(&cluster.faults(id))),
+                None => Err("usage: FAULTS <server> [policy]".into()),
+            },
+            Some("QUIT") | Some("EXIT") => break,
+            Some("HELP") => {
+                print_usage();
+                Ok(())
+            },
+            Some(_) => Err(format!("unknown command: {:?}", args).into()),
+        };
+        if let Err(e) = result {
+            println!("error: {}", e);
+        }
+    }
+    // Ctrl-C and Ctrl-D end the prompt as well
+    println!("Stopping the servers");
+    cluster.shutdown()
+}
+
+fn print_status(cluster: &mut LocalCluster) {
+    println!("{:<10} {:<21} {:<8}  UNREACHABLE PEERS", "SERVER", "ADDRESS", "STATE");
+    for id in cluster.ids() {
+        let state = match (cluster.is_running(&id), cluster.faults(&id).paused) {
+            (false, _) => "stopped",
+            (true, true) => "paused",
+            (true, false) => "running",
+        };
+        let disconnected = cluster.disconnected(&id).join(", ");
+        println!("{:<10} {:<21} {:<8}  {}", id, cluster.address(&id).unwrap(), state, disconnected);
+    }
+}
+
+fn print_faults(faults: &FaultPolicy) {
+    match serde_yaml::to_string(faults) {
+        Ok(text) => print!("{}", text.trim_start_matches("---\n")),
+        Err(e) => println!("error: {}", e),
+    }
+}
@@ -0,0 +1,244 @@
+#[macro_use] extern crate clap;
+#[macro_use] extern crate error_chain;
+extern crate paxos550;
+
+use paxos550::client::{LockClient, LockClientBuilder};
+use paxos550::config::{self, parse_flag, Config};
+use paxos550::errors::*;
+use paxos550::locker::{self, Digest, LogEntry, Locker, Operation, DEFAULT_LEASE};
+use paxos550::logging::{self, LogFormat};
+use paxos550::message::Query;
+use paxos550::paxos::NodeID;
+
+use clap::{Arg, App, ArgMatches};
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::Duration;
+
+// exit codes
+const EXIT_OK: i32 = 0;
+const EXIT_DIVERGED: i32 = 1;
+const EXIT_UNREACHABLE: i32 = 2;
+const EXIT_ERROR: i32 = 3;
+
+/// Entries fetched at a time to replay a log.
+const REPLAY_PAGE: usize = 1000;
+
+quick_main!(run);
+
+fn run() -> Result<i32> {
+    let matches = App::new("Paxos550 Log Diff")
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about("Checks that the servers have the same log and the same locks, and shows the first \
+                entry on which their logs diverge. Exits with 0 if they agree, 1 if they do not, 2 if \
+                a server did not answer and 3 on any other error.")
+        .arg(Arg::with_name("config")
+            .long("config")
+            .help("Cluster configuration file to read the servers from.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("server")
+            .long("server")
+            .help("Server nodes in `id=addr` format. e.g. node1=127.0.0.1:9001. \
+                   Added to, or override, the servers of --config.")
+            .required(false)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("replay")
+            .long("replay")
+            .help("Also fetches the whole log of every server and checks that applying it gives the \
+                   validity it records and the locks the server has."))
+        .arg(Arg::with_name("lease")
+            .long("lease")
+            .help("Milliseconds a lock lasts on the servers, to replay their logs with. Overrides the \
+                   lease of --config, and is 10000 without either.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .help("Milliseconds to wait for a server.")
+            .default_value("1000")
+            .takes_value(true))
+        .get_matches();
+
+    // the servers that do not answer are reported below, without the warnings of the client
+    logging::init(LogFormat::Text, "error")?;
+    match diff(&matches) {
+        Ok(code) => Ok(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            Ok(EXIT_ERROR)
+        },
+    }
+}
+
+/// A server that answered, with the digest of its whole log.
+struct Replica {
+    id: NodeID,
+    client: LockClient,
+    digest: Digest,
+}
+
+fn diff(matches: &ArgMatches) -> Result<i32> {
+    let timeout = Duration::from_millis(parse_flag(matches.value_of("timeout").unwrap(), "--timeout")?);
+    let mut replicas = Vec::new();
+    let mut unreachable = false;
+    println!("{:<10} {:>8}  {:<16}  LOCKS", "SERVER", "ENTRIES", "LOG");
+    let members = matches.values_of("server").into_iter().flatten();
+    let servers: BTreeMap<_, _> = config::servers(matches.value_of("config").map(Path::new), members)?
+        .into_iter()
+        .collect();
+    for (id, addr) in servers {
+        let mut client = LockClientBuilder::new("logdiff".to_string())
+            .server(id.clone(), addr)
+            .timeout(timeout)
+            .attempts(2)
+            .build()?;
+        match client.digest(0, usize::MAX) {
+            Ok(digest) => {
+                println!("{:<10} {:>8}  {:016x}  {:016x}", id, digest.entries, digest.log, digest.locks);
+                replicas.push(Replica { id, client, digest });
+            },
+            Err(Error(ErrorKind::Unreachable(_), _)) => {
+                println!("{:<10} {:>8}  unreachable", id, "-");
+                unreachable = true;
+            },
+            Err(e) => return Err(e),
+        }
+    }
+    if replicas.is_empty() {
+        return Ok(EXIT_UNREACHABLE);
+    }
+
+    let mut agree = compare_logs(&mut replicas)?;
+    agree &= compare_locks(&replicas);
+    if matches.is_present("replay") {
+        let lease = lease(matches)?;
+        for replica in &mut replicas {
+            agree &= replay(replica, lease)?;
+        }
+    }
+    Ok(match (agree, unreachable) {
+        (false, _) => EXIT_DIVERGED,
+        (true, true) => EXIT_UNREACHABLE,
+        (true, false) => EXIT_OK,
+    })
+}
+
+/// Bisects on the digests of the entries the logs have in common to find the first one on which
+/// they disagree, and shows it as every replica has it. Returns whether the logs agree.
+fn compare_logs(replicas: &mut [Replica]) -> Result<bool> {
+    let common = replicas.iter().map(|replica| replica.digest.entries).min().unwrap();
+    if prefixes_agree(replicas, common)? {
+        println!("The logs agree on their first {} entries.", common);
+        for replica in replicas.iter().filter(|replica| replica.digest.entries > common) {
+            println!("    {} has {} more", replica.id, replica.digest.entries - common);
+        }
+        return Ok(true);
+    }
+    // the first `agreed` entries are the same everywhere, the first `differ` are not
+    let (mut agreed, mut differ) = (0, common);
+    while differ - agreed > 1 {
+        let middle = agreed + (differ - agreed) / 2;
+        if prefixes_agree(replicas, middle)? {
+            agreed = middle;
+        } else {
+            differ = middle;
+        }
+    }
+    let index = differ - 1;
+    println!("The logs diverge at entry {}:", index);
+    let mut versions: Vec<(Option<LogEntry>, Vec<&str>)> = Vec::new();
+    for replica in replicas.iter_mut() {
+        let query = Query { offset: index, limit: Some(1), key: None };
+        let entry = replica.client.log(query)?.items.pop().map(|(_, entry)| entry);
+        match versions.iter_mut().find(|(version, _)| *version == entry) {
+            Some((_, ids)) => ids.push(&replica.id),
+            None => versions.push((entry, vec![&replica.id])),
+        }
+    }
+    for (entry, ids) in versions {
+        let entry = entry.map_or("missing".to_string(), |entry| describe(&entry.op, entry.valid));
+        println!("    {}: {}", ids.join(", "), entry);
+    }
+    Ok(false)
+}
+
+fn prefixes_agree(replicas: &mut [Replica], end: usize) -> Result<bool> {
+    let mut hashes = Vec::new();
+    for replica in replicas.iter_mut() {
+        hashes.push(replica.client.digest(0, end)?.log);
+    }
+    Ok(hashes.windows(2).all(|pair| pair[0] == pair[1]))
+}
+
+/// Checks that replicas with the same log have the same locks.
+fn compare_locks(replicas: &[Replica]) -> bool {
+    let mut by_log: BTreeMap<(usize, u64), Vec<&Replica>> = BTreeMap::new();
+    for replica in replicas {
+        by_log.entry((replica.digest.entries, replica.digest.log)).or_default().push(replica);
+    }
+    let mut agree = true;
+    for ((entries, _), group) in by_log {
+        if group.iter().any(|replica| replica.digest.locks != group[0].digest.locks) {
+            let locks: Vec<_> = group.iter()
+                .map(|replica| format!("{} {:016x}", replica.id, replica.digest.locks))
+                .collect();
+            println!("The locks differ after the same {} entries: {}", entries, locks.join(", "));
+            agree = false;
+        }
+    }
+    if agree {
+        println!("The locks agree on the servers with the same log.");
+    }
+    agree
+}
+
+/// The lease of the locks on the servers, from `--lease`, or else `--config`. A log only replays
+/// to the same locks with the same lease.
+fn lease(matches: &ArgMatches) -> Result<Duration> {
+    let lease = match (matches.value_of("lease"), matches.value_of("config")) {
+        (Some(lease), _) => Duration::from_millis(parse_flag(lease, "--lease")?),
+        (None, Some(path)) => Config::load(Path::new(path))?.server.lease(),
+        (None, None) => DEFAULT_LEASE,
+    };
+    if lease.is_zero() {
+        bail!("--lease must be positive");
+    }
+    Ok(lease)
+}
+
+/// Applies the log of `replica` to a new `Locker` with `lease`, and checks that each entry is as
+/// valid as the log says and that the locks end up as the server has them.
+fn replay(replica: &mut Replica, lease: Duration) -> Result<bool> {
+    let entries = replica.digest.entries;
+    let mut locker = Locker::with_lease(lease);
+    while locker.log().len() < entries {
+        let offset = locker.log().len();
+        let query = Query { offset, limit: Some(REPLAY_PAGE.min(entries - offset)), key: None };
+        let page = replica.client.log(query)?;
+        if page.items.is_empty() {
+            bail!("{} returned no entries from {}", replica.id, offset);
+        }
+        for (index, entry) in page.items {
+            if locker.append_log(&entry.op, entry.time_ms) != entry.valid {
+                println!("{} logged entry {} as {}, but it applies as {}", replica.id, index,
+                         describe(&entry.op, entry.valid), describe(&entry.op, !entry.valid));
+                return Ok(false);
+            }
+        }
+    }
+    if locker::hash_locks(locker.locks())? != replica.digest.locks {
+        println!("The locks of {} are not what its log gives", replica.id);
+        return Ok(false);
+    }
+    println!("The log of {} replays to its locks.", replica.id);
+    Ok(true)
+}
+
+fn describe(op: &Operation, valid: bool) -> String {
+    format!("{:?}, {}", op, if valid { "valid" } else { "invalid" })
+}
@@ -1,295 +1,50 @@
-extern crate tokio;
-#[macro_use] extern crate futures;
 #[macro_use] extern crate clap;
-extern crate serde_yaml;
-#[macro_use] extern crate log;
-extern crate env_logger;
+#[macro_use] extern crate error_chain;
+#[macro_use] extern crate tracing;
 extern crate paxos550;
 
-use paxos550::paxos::*;
-use paxos550::locker;
+use paxos550::config::{self, parse_flag, Config};
+use paxos550::env;
 use paxos550::errors::*;
-use paxos550::network::message::*;
+use paxos550::logging::{self, LogFormat};
+use paxos550::server::ServerBuilder;
 
-use tokio::prelude::*;
-use tokio::net::UdpSocket;
-use tokio::timer::Delay;
-use tokio::runtime::Runtime;
 use clap::{Arg, App};
 
+#[cfg(unix)]
+use std::io;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::fd::AsFd;
+use std::path::Path;
 use std::time::Duration;
-use std::time::Instant;
-use std::net::SocketAddr;
-use std::collections::HashMap;
-use std::collections::VecDeque;
-use std::ptr;
 
-const MAX_UDP_SIZE: usize = 1500 - 20 - 8;
-const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
-
-pub struct Server {
-    node_id: NodeID,
-    socket: UdpSocket,
-    peers: HashMap<String, SocketAddr>,
-
-    init: bool,
-    runtime: &'static mut Runtime,
-    buf: [u8; MAX_UDP_SIZE],
-    messages_to_send: VecDeque<MessageInfo<locker::Operation>>,
-    paxos: Vec<PaxosInstance<locker::Operation>>,
-    locker: locker::Locker,
-    next_log_to_apply: usize,
-}
-
-static mut GLOBAL_SERVER: *mut Server = ptr::null_mut();
-static mut GLOBAL_RUNTIME: *mut Runtime = ptr::null_mut();
-
-fn on_timeout(msg: PaxosMessage<locker::Operation>, timeout: Duration) -> Result<()> {
-    let server = unsafe { &mut *GLOBAL_SERVER };
-    {
-        let instance = &mut server.paxos[msg.instance_id];
-        instance.on_timeout(msg.message, timeout)?;
-        instance.collect_messages_to_send(&mut server.messages_to_send);
-    }
-    loop {
-        match server.send_messages() {
-            Ok(Async::Ready(())) => (),
-            Ok(Async::NotReady) => return Ok(()),
-            Err(e) => return Err(e),
-        }
-    }
-}
-
-pub unsafe fn set_global_server(server: &mut Server) {
-    // FIXME so ugly. why tokio requires futures to be 'static to be spawned?
-    GLOBAL_SERVER = server;
-}
-
-pub unsafe fn set_global_runtime(runtime: &mut Runtime) {
-    // FIXME so ugly.
-    GLOBAL_RUNTIME = runtime;
-}
-
-impl Server {
-    pub fn new(node_id: NodeID, socket: UdpSocket, mut peers: HashMap<String, SocketAddr>) -> Server {
-        peers.insert(node_id.clone(), socket.local_addr().unwrap());
-        let empty_instance = PaxosInstance::new(
-            node_id.clone(), 0, peers.len(), Duration::default());
-        Server {
-            node_id,
-            socket,
-            peers,
-            init: true,
-            runtime: unsafe { &mut *GLOBAL_RUNTIME },
-            buf: [0u8; MAX_UDP_SIZE],
-            messages_to_send: VecDeque::new(),
-            paxos: vec![empty_instance],
-            locker: locker::Locker::new(),
-            next_log_to_apply: 1
-        }
-    }
-
-    fn setup_timeout_trigger(&mut self, now: Instant, message: MessageInfo<locker::Operation>) {
-        // setup timeout trigger
-        if let Some(timeout) = message.timeout {
-            if let MessagePayload::PaxosMessage(msg) = message.payload {
-                let deadline = now + timeout;
-                let timer = Delay::new(deadline)
-                    .map_err(|e| e.into())
-                    .and_then(move |_| {
-                        on_timeout(msg, timeout)
-                    })
-                    .map_err(|e: Error| println!("timer error {}", e));
-                self.runtime.spawn(timer);
-            }
-        }
-    }
-
-    pub fn send_messages(&mut self) -> Poll<(), Error> {
-        let mut not_ready = true;
-        let mut retry_queue = VecDeque::new();
-        let now = Instant::now();
-        while let Some(message) = self.messages_to_send.pop_front() {
-            let target_name = match message.target {
-                MessageTarget::Broadcast => {
-                    // break broadcast messages into peer-to-peer messages
-                    for (name, _) in &self.peers {
-                        self.messages_to_send.push_front(MessageInfo {
-                            payload: message.payload.clone(),
-                            target: MessageTarget::Node(name.clone()),
-                            timeout: None  // clear timeout here
-                        })
-                    }
-
-                    // setup timeout trigger
-                    self.setup_timeout_trigger(now, message);
-
-                    // process the peer-to-peer messages
-                    continue;
-                },
-                MessageTarget::Node(ref x) => x.clone(),
-            };
-
-            // setup timeout trigger
-            self.setup_timeout_trigger(now, message.clone());
-
-            debug!("send message to {}: {:?}", target_name, message.payload);
-
-            // send messages to self
-            if *target_name == self.node_id {
-                self.receive_message(message.payload, "0.0.0.0:0".parse().unwrap())?;  // FIXME don't need addr here
-                continue;  // process the next message
-            }
-
-            // send messages
-            let data = serde_yaml::to_vec(&message.payload)?;
-            let addr = self.peers.get(&target_name)
-                .ok_or_else(|| Error::from("cannot find the peer"))?;
-            match self.socket.poll_send_to(&data, addr) {
-                Ok(Async::Ready(size)) => {
-                    // FIXME write can be incomplete
-                    assert_eq!(size, data.len());
-                    not_ready = false
-                },
-                Ok(Async::NotReady) => {
-                    retry_queue.push_back(message.clone()); // FIXME clone() ugly.
-                },
-                Err(e) => return Err(e.into()),
-            }
-        }
-
-        // add back messages to retry
-        self.messages_to_send.append(&mut retry_queue);
-        Ok(if not_ready { Async::NotReady } else { Async::Ready(()) })
-    }
-
-    fn receive_message(&mut self, message: MessagePayload<locker::Operation>, addr: SocketAddr) -> Poll<(), Error> {
-        debug!("got message from {}: {:?}", addr, message);
-        match message {
-            MessagePayload::PaxosMessage(ref msg) => {
-                // create all the missing instances
-                let next_instance_id = self.paxos.len();
-                for instance_id in next_instance_id ..= msg.instance_id {
-                    let mut instance = PaxosInstance::new(
-                        self.node_id.clone(), instance_id, self.peers.len(), DEFAULT_TIMEOUT);
-                    self.paxos.push(instance);
-                }
-
-                // handle the message
-                let apply_log;
-                {
-                    let instance = &mut self.paxos[msg.instance_id];
-                    match instance.receive_message(&msg.message) {
-                        None => apply_log = false,
-                        Some(v) => {
-                            apply_log = true;
-                            info!("Reached consensus on Instance {}: {:?}", msg.instance_id, v);
-                        },
-                    }
-                    instance.collect_messages_to_send(&mut self.messages_to_send);
-                }
-
-                // update the locker when the learner learns the value for the first time
-                if apply_log {
-                    let total_instances = self.paxos.len() - 1;
-                    while self.next_log_to_apply <= total_instances {
-                        let inst = &mut self.paxos[self.next_log_to_apply];
-                        if let Some(v) = inst.value() {
-                            info!("Applying the log of Instance {}: {:?}", self.next_log_to_apply, v);
-                            self.locker.append_log(&v);
-                            self.next_log_to_apply += 1;
-                        } else {
-                            break;
-                        }
-                    }
-                }
-            },
-            MessagePayload::LockerMessage(op) => {
-                let instance_id = self.paxos.len();
-                let mut instance = PaxosInstance::new(
-                    self.node_id.clone(), instance_id, self.peers.len(), DEFAULT_TIMEOUT);
-                instance.start_proposing(op);
-                instance.collect_messages_to_send(&mut self.messages_to_send);
-                self.paxos.push(instance);
-            },
-            MessagePayload::PrintLog => {
-                // FIXME unify send message
-                let data = serde_yaml::to_vec(self.locker.log())?;
-                match self.socket.poll_send_to(&data, &addr) {
-                    Ok(Async::Ready(_)) => return Ok(Async::Ready(())),  // FIXME write can be incomplete
-                    Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Err(e) => return Err(e.into()),
-                }
-            },
-            MessagePayload::PrintLocks => {
-                let data = serde_yaml::to_vec(self.locker.locks())?;
-                match self.socket.poll_send_to(&data, &addr) {
-                    Ok(Async::Ready(_)) => return Ok(Async::Ready(())),  // FIXME write can be incomplete
-                    Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Err(e) => return Err(e.into()),
-                }
-            },
-            MessagePayload::PrintTotalInstances => {
-                let total_instances = self.paxos.len() - 1;
-                let data = serde_yaml::to_vec(&total_instances)?;
-                match self.socket.poll_send_to(&data, &addr) {
-                    Ok(Async::Ready(_)) => return Ok(Async::Ready(())),  // FIXME write can be incomplete
-                    Ok(Async::NotReady) => return Ok(Async::NotReady),
-                    Err(e) => return Err(e.into()),
-                }
-            }
-        }
-        Ok(Async::Ready(()))
-    }
-}
-
-impl Future for Server {
-    type Item = ();
-    type Error = Error;
-
-    fn poll(&mut self) -> Poll<(), Error> {
-        if self.init {
-            unsafe { set_global_server(self); }  // FIXME ugly
-            self.init = false;
-        }
-        debug!("poll");
-        loop {
-            debug!("poll > send");
-            let mut not_ready = true;
-
-            // try to send.
-            match self.send_messages() {
-                Ok(Async::Ready(())) => not_ready = false,
-                Ok(Async::NotReady) => (),
-                Err(e) => return Err(e)
-            }
-
-            debug!("poll > receive");
-            // send is not ready. try to receive.
-            let (size, addr) = try_ready!(self.socket.poll_recv_from(&mut self.buf));  // FIXME read can be incomplete
-            let message: MessagePayload<locker::Operation> = serde_yaml::from_slice(&self.buf[..size]).unwrap();
-            match self.receive_message(message, addr) {
-                Ok(Async::Ready(())) => not_ready = false,
-                Ok(Async::NotReady) => (),
-                Err(e) => return Err(e)
-            }
-
-            if not_ready {
-                return Ok(Async::NotReady);
-            }
-        }
-    }
-}
-
-fn main() {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Debug)
-        .init();
+quick_main!(run);
 
+fn run() -> Result<()> {
     let matches = App::new("Paxos550 Lock Service Server and Paxos Server")
         .version(crate_version!())
         .author(crate_authors!())
         .about("Starts a server that runs paxos and serves clients' locker requests.")
+        .arg(Arg::with_name("log-level")
+            .long("log-level")
+            .help("Log level filter, e.g. debug or info,paxos550::paxos=trace. RUST_LOG overrides it. \
+                   Clients can change it with the LOGLEVEL command.")
+            .required(false)
+            .default_value("info")
+            .takes_value(true))
+        .arg(Arg::with_name("log-format")
+            .long("log-format")
+            .help("Log as text or as JSON lines.")
+            .required(false)
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .takes_value(true))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .help("Cluster configuration file. Flags override the values in the file.")
+            .required(false)
+            .takes_value(true))
         .arg(Arg::with_name("id")
             .long("id")
             .help("Paxos NodeID")
@@ -297,37 +52,178 @@ fn main() {
             .takes_value(true))
         .arg(Arg::with_name("listen")
             .long("listen")
-            .help("Listening address. e.g. 0.0.0.0:9000")
-            .required(true)
+            .help("Listening address. e.g. 0.0.0.0:9000. Required without --config.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("listen-stdin")
+            .long("listen-stdin")
+            .help("Serve on the UDP socket given as standard input, bound by the program that started \
+                   this server, instead of binding the listening address. Unix only."))
+        .arg(Arg::with_name("metrics")
+            .long("metrics")
+            .help("Address to serve Prometheus metrics on at /metrics. e.g. 127.0.0.1:9100")
+            .required(false)
             .takes_value(true))
         .arg(Arg::with_name("peer")
             .long("peer")
             .help("Paxos peer nodes in `id=addr` format. e.g. node1=127.0.0.1:9001")
             .required(false)
             .takes_value(true)
-            .multiple(true))
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("timeout")
+            .long("timeout")
+            .help("Milliseconds before a Paxos phase is retried.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("prepare-timeout")
+            .long("prepare-timeout")
+            .help("Milliseconds to wait for a majority of Promises. Overrides --timeout.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("propose-timeout")
+            .long("propose-timeout")
+            .help("Milliseconds to wait for a majority of Accepteds. Overrides --timeout.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("learn-timeout")
+            .long("learn-timeout")
+            .help("Milliseconds to wait for the chosen value. Overrides --timeout.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("max-backoff")
+            .long("max-backoff")
+            .help("Maximum milliseconds a retry waits after backing off.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("adaptive-timeouts")
+            .long("adaptive-timeouts")
+            .help("Derive the timeouts from the measured round-trip times to the peers."))
+        .arg(Arg::with_name("redirect-clients")
+            .long("redirect-clients")
+            .help("Redirect the operations of clients to the leader, the server with the lowest ID that answers."))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .help("Seed for proposal IDs and back-off timeouts, to replay a run. Random if not set.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("batch-size")
+            .long("batch-size")
+            .help("Maximum number of operations proposed in one Paxos instance.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("batch-delay")
+            .long("batch-delay")
+            .help("Milliseconds an operation waits for others to join its batch.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("window")
+            .long("window")
+            .help("Maximum number of undecided Paxos instances this server proposes at a time.")
+            .required(false)
+            .takes_value(true))
+        .arg(Arg::with_name("enable-log-level-changes")
+            .long("enable-log-level-changes")
+            .help("Let clients change the log level of this server with the LOGLEVEL command."))
+        .arg(Arg::with_name("enable-fault-injection")
+            .long("enable-fault-injection")
+            .help("Let clients make this server drop, delay or pause its traffic with the FAULTS command. \
+                   For chaos tests only."))
+        .arg(Arg::with_name("lease")
+            .long("lease")
+            .help("Milliseconds a lock is held after it was taken or last renewed. The same on every server.")
+            .required(false)
+            .takes_value(true))
         .get_matches();
 
+    let format: LogFormat = matches.value_of("log-format").unwrap().parse()?;
+    let log_level = logging::init(format, matches.value_of("log-level").unwrap())?;
+
     let node_id = matches.value_of("id").unwrap();
-    let listen: SocketAddr = matches.value_of("listen").unwrap().parse().unwrap();
-    let mut peers = HashMap::new();
-    if let Some(peers_str) = matches.values_of("peer") {
-        for peer in peers_str {
-            let split: Vec<&str> = peer.split('=').collect();
-            assert_eq!(split.len(), 2);
-            peers.insert(String::from(split[0]), split[1].parse::<SocketAddr>().unwrap());
+    let listen = match matches.value_of("listen") {
+        Some(listen) => Some(parse_flag(listen, "--listen")?),
+        None => None,
+    };
+    let mut builder = match (matches.value_of("config"), listen) {
+        (Some(path), listen) => {
+            let builder = ServerBuilder::from_config(&Config::load(Path::new(path))?, node_id)?;
+            match listen {
+                Some(listen) => builder.listen(listen),
+                None => builder,
+            }
+        },
+        (None, Some(listen)) => ServerBuilder::new(node_id.to_string(), listen),
+        (None, None) => bail!("either --config or --listen is required"),
+    };
+    builder = builder.log_level(log_level);
+    if matches.is_present("listen-stdin") {
+        builder = builder.socket(stdin_socket()?);
+    }
+    if let Some(metrics) = matches.value_of("metrics") {
+        builder = builder.metrics(Some(parse_flag(metrics, "--metrics")?));
+    }
+    if let Some(peers) = matches.values_of("peer") {
+        for peer in peers {
+            let (id, addr) = config::parse_member(peer)?;
+            builder = builder.peer(id, addr);
         }
     }
-    let socket = UdpSocket::bind(&listen).unwrap();
-    info!("Server {} listening on: {}", node_id, socket.local_addr().unwrap());
-    for (name, addr) in &peers {
-        info!("Peer {}: {}", name, addr);
+    if let Some(timeout) = matches.value_of("timeout") {
+        builder = builder.timeout(Duration::from_millis(parse_flag(timeout, "--timeout")?));
+    }
+    if let Some(timeout) = matches.value_of("prepare-timeout") {
+        builder = builder.prepare_timeout(Duration::from_millis(parse_flag(timeout, "--prepare-timeout")?));
+    }
+    if let Some(timeout) = matches.value_of("propose-timeout") {
+        builder = builder.propose_timeout(Duration::from_millis(parse_flag(timeout, "--propose-timeout")?));
+    }
+    if let Some(timeout) = matches.value_of("learn-timeout") {
+        builder = builder.learn_timeout(Duration::from_millis(parse_flag(timeout, "--learn-timeout")?));
+    }
+    if let Some(max_backoff) = matches.value_of("max-backoff") {
+        builder = builder.max_backoff(Duration::from_millis(parse_flag(max_backoff, "--max-backoff")?));
+    }
+    if matches.is_present("adaptive-timeouts") {
+        builder = builder.adaptive_timeouts(true);
     }
+    if matches.is_present("redirect-clients") {
+        builder = builder.redirect_clients(true);
+    }
+    if matches.is_present("enable-log-level-changes") {
+        builder = builder.log_level_changes(true);
+    }
+    if matches.is_present("enable-fault-injection") {
+        builder = builder.fault_injection(true);
+    }
+    if let Some(seed) = matches.value_of("seed") {
+        builder = builder.random(env::seeded_random(parse_flag(seed, "--seed")?));
+    }
+    if let Some(batch_size) = matches.value_of("batch-size") {
+        builder = builder.batch_size(parse_flag(batch_size, "--batch-size")?);
+    }
+    if let Some(batch_delay) = matches.value_of("batch-delay") {
+        builder = builder.batch_delay(Duration::from_millis(parse_flag(batch_delay, "--batch-delay")?));
+    }
+    if let Some(window) = matches.value_of("window") {
+        builder = builder.window(parse_flag(window, "--window")?);
+    }
+    if let Some(lease) = matches.value_of("lease") {
+        builder = builder.lease(Duration::from_millis(parse_flag(lease, "--lease")?));
+    }
+    let mut server = builder.build()?;
+    info!(node = node_id, listen = %server.local_addr(), "server started");
+    server.start()?;
+    server.wait()
+}
 
-    let mut runtime = tokio::runtime::Builder::new()
-        .core_threads(1).build().unwrap();
-    unsafe { set_global_runtime(&mut runtime); }
-    let server = Server::new(node_id.to_string(), socket, peers);
-    runtime.spawn(server.map_err(|e| error!("error: {}", e)));
-    runtime.shutdown_on_idle().wait().unwrap();
-}
\ No newline at end of file
+/// The socket a launcher passed as standard input.
+#[cfg(unix)]
+fn stdin_socket() -> Result<UdpSocket> {
+    let fd = io::stdin().as_fd().try_clone_to_owned().chain_err(|| "standard input is not a socket")?;
+    Ok(UdpSocket::from(fd))
+}
+
+#[cfg(not(unix))]
+fn stdin_socket() -> Result<UdpSocket> {
+    bail!("--listen-stdin needs a unix system")
+}
@@ -0,0 +1,708 @@
+//! A client of the lock service for Rust programs.
+//!
+//! `LockClientBuilder` builds a blocking `LockClient`, or an `AsyncLockClient` for programs that
+//! run on tokio. Requests go to one server at a time, until it redirects the client to the leader
+//! or does not answer in time, in which case the request is sent again to the next server. A
+//! client and its guards share one socket, on which they take turns.
+//!
+//! ```no_run
+//! use paxos550::client::LockClientBuilder;
+//!
+//! let mut client = LockClientBuilder::new("client1".to_string())
+//!     .server("node1".to_string(), "127.0.0.1:9001".parse().unwrap())
+//!     .server("node2".to_string(), "127.0.0.1:9002".parse().unwrap())
+//!     .build()
+//!     .unwrap();
+//! let guard = client.lock("key").unwrap();
+//! // the lock is released when the guard is dropped, or explicitly:
+//! guard.unlock().unwrap();
+//! ```
+//!
+//! Locks are leases, which expire unless they are renewed in time. While a guard is alive, a
+//! background task renews its lock every renew interval, which should be well within the lease of
+//! the servers. The guard is marked as lost when a renewal fails.
+
+use crate::config::Config;
+use crate::errors::*;
+use crate::locker::{Digest, LogEntry, Operation};
+use crate::network::message::*;
+use crate::paxos::NodeID;
+use crate::server::{FaultPolicy, Status};
+
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use tokio::net::UdpSocket;
+use tokio::runtime::{self, Handle, Runtime};
+use tokio::sync::{self as async_sync, oneshot, watch};
+use tokio::time;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+pub const DEFAULT_ATTEMPTS: usize = 3;
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_RENEW_INTERVAL: Duration = Duration::from_secs(1);
+
+// as large as any datagram, for the chunks of replies and whatever else arrives
+const MAX_UDP_SIZE: usize = 65535 - 20 - 8;
+
+pub struct LockClientBuilder {
+    id: NodeID,
+    servers: HashMap<NodeID, SocketAddr>,
+    timeout: Duration,
+    attempts: usize,
+    retry_interval: Duration,
+    lock_timeout: Duration,
+    renew_interval: Duration,
+}
+
+impl LockClientBuilder {
+    /// A client that locks keys on behalf of `id`.
+    pub fn new(id: NodeID) -> LockClientBuilder {
+        LockClientBuilder {
+            id,
+            servers: HashMap::new(),
+            timeout: DEFAULT_REPLY_TIMEOUT,
+            attempts: DEFAULT_ATTEMPTS,
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            renew_interval: DEFAULT_RENEW_INTERVAL,
+        }
+    }
+
+    /// A client of the servers of `config`.
+    pub fn from_config(config: &Config, id: NodeID) -> LockClientBuilder {
+        LockClientBuilder::new(id).servers(config.members())
+    }
+
+    pub fn server(mut self, node_id: NodeID, addr: SocketAddr) -> LockClientBuilder {
+        self.servers.insert(node_id, addr);
+        self
+    }
+
+    pub fn servers(mut self, servers: HashMap<NodeID, SocketAddr>) -> LockClientBuilder {
+        self.servers.extend(servers);
+        self
+    }
+
+    /// How long to wait for a server to answer before asking the next one.
+    pub fn timeout(mut self, timeout: Duration) -> LockClientBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many servers to ask in turn before giving up with `ErrorKind::Unreachable`.
+    pub fn attempts(mut self, attempts: usize) -> LockClientBuilder {
+        self.attempts = attempts;
+        self
+    }
+
+    /// How long `lock` waits before trying again to take a lock held by another client.
+    pub fn retry_interval(mut self, retry_interval: Duration) -> LockClientBuilder {
+        self.retry_interval = retry_interval;
+        self
+    }
+
+    /// How long `lock` keeps trying before giving up with `ErrorKind::LockHeld`.
+    pub fn lock_timeout(mut self, lock_timeout: Duration) -> LockClientBuilder {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// How often a guard renews its lock, which has to be less than the lease of the servers.
+    pub fn renew_interval(mut self, renew_interval: Duration) -> LockClientBuilder {
+        self.renew_interval = renew_interval;
+        self
+    }
+
+    /// A blocking client, with a runtime of its own whose thread renews the locks of guards.
+    pub fn build(self) -> Result<LockClient> {
+        let runtime = runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build()?;
+        let runtime = ClientRuntime(Some(runtime));
+        let client = wait(&runtime, self.build_async())?;
+        Ok(LockClient { runtime: Arc::new(runtime), client })
+    }
+
+    /// A client for tasks of a tokio runtime.
+    pub async fn build_async(self) -> Result<AsyncLockClient> {
+        if self.servers.is_empty() {
+            bail!(ErrorKind::InvalidConfig("no servers".to_string()));
+        }
+        if self.attempts == 0 {
+            bail!(ErrorKind::InvalidConfig("attempts must be positive".to_string()));
+        }
+        let mut servers: Vec<_> = self.servers.into_iter().collect();
+        servers.sort();
+        let next_server = rand::thread_rng().gen_range(0, servers.len());
+        let connection = Connection {
+            socket: UdpSocket::bind("0.0.0.0:0").await?,
+            route: Mutex::new(Route { servers, next_server }),
+            next_request: AtomicU64::new(rand::random()),
+            buf: async_sync::Mutex::new(vec![0u8; MAX_UDP_SIZE]),
+        };
+        Ok(AsyncLockClient {
+            id: self.id,
+            connection: Arc::new(connection),
+            timeout: self.timeout,
+            attempts: self.attempts,
+            retry_interval: self.retry_interval,
+            lock_timeout: self.lock_timeout,
+            renew_interval: self.renew_interval,
+        })
+    }
+}
+
+/// What a client, its clones and its guards share.
+struct Connection {
+    socket: UdpSocket,
+    route: Mutex<Route>,
+    next_request: AtomicU64,
+    /// Held for a whole exchange, so that one does not take the replies of another.
+    buf: async_sync::Mutex<Vec<u8>>,
+}
+
+struct Route {
+    servers: Vec<(NodeID, SocketAddr)>,
+    /// The server requests go to, until it does not answer.
+    next_server: usize,
+}
+
+impl Connection {
+    fn route(&self) -> MutexGuard<'_, Route> {
+        self.route.lock().unwrap()
+    }
+
+    fn next_request(&self) -> u64 {
+        self.next_request.fetch_add(1, Ordering::Relaxed).wrapping_add(1)
+    }
+}
+
+/// Clones share the socket and the current server of the client they are cloned from.
+#[derive(Clone)]
+pub struct AsyncLockClient {
+    id: NodeID,
+    connection: Arc<Connection>,
+    timeout: Duration,
+    attempts: usize,
+    retry_interval: Duration,
+    lock_timeout: Duration,
+    renew_interval: Duration,
+}
+
+impl AsyncLockClient {
+    pub fn id(&self) -> &NodeID {
+        &self.id
+    }
+
+    /// The server requests go to, which is the leader once a server redirected the client.
+    pub fn server(&self) -> NodeID {
+        let route = self.connection.route();
+        route.servers[route.next_server].0.clone()
+    }
+
+    /// Takes the lock of `key`, waiting for as long as the lock timeout while another client
+    /// holds it.
+    pub async fn lock(&mut self, key: &str) -> Result<AsyncLockGuard> {
+        self.acquire(key).await?;
+        self.guard(key).await
+    }
+
+    /// Takes the lock of `key` unless another client holds it.
+    pub async fn try_lock(&mut self, key: &str) -> Result<Option<AsyncLockGuard>> {
+        if self.try_acquire(key).await? {
+            return Ok(Some(self.guard(key).await?));
+        }
+        Ok(None)
+    }
+
+    /// Like `lock`, but without a guard: the lock lasts for one lease, unless it is renewed or
+    /// unlocked before.
+    pub async fn acquire(&mut self, key: &str) -> Result<()> {
+        let deadline = Instant::now() + self.lock_timeout;
+        loop {
+            if self.try_acquire(key).await? {
+                return Ok(());
+            }
+            if Instant::now() + self.retry_interval > deadline {
+                bail!(ErrorKind::LockHeld(key.to_string()));
+            }
+            time::sleep(self.retry_interval).await;
+        }
+    }
+
+    /// Like `try_lock`, but without a guard. Returns whether it took the lock.
+    pub async fn try_acquire(&mut self, key: &str) -> Result<bool> {
+        self.apply(Operation::Lock(key.to_string(), self.id.clone())).await
+    }
+
+    /// Unlocks `key` without a guard, e.g. a lock left behind by an earlier run of the client.
+    pub async fn unlock(&mut self, key: &str) -> Result<()> {
+        if !self.apply(Operation::Unlock(key.to_string(), self.id.clone())).await? {
+            bail!(ErrorKind::LockNotHeld(key.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Fails with `ErrorKind::LockNotHeld` unless this client holds the lock of `key`.
+    pub async fn renew(&mut self, key: &str) -> Result<()> {
+        if !self.apply(Operation::Renew(key.to_string(), self.id.clone())).await? {
+            bail!(ErrorKind::LockNotHeld(key.to_string()));
+        }
+        Ok(())
+    }
+
+    /// The locks that match `query`, sorted by key, with their owners.
+    pub async fn list_locks(&mut self, query: Query) -> Result<Page<(String, NodeID)>> {
+        self.query(MessagePayload::PrintLocks, query).await
+    }
+
+    /// The entries of the log that match `query`, with their index in the log.
+    pub async fn log(&mut self, query: Query) -> Result<Page<(usize, LogEntry)>> {
+        self.query(MessagePayload::PrintLog, query).await
+    }
+
+    /// The digest of entries `start..end` of the log of the current server, and of its locks.
+    pub async fn digest(&mut self, start: usize, end: usize) -> Result<Digest> {
+        self.ask(MessagePayload::Digest(start, end), |reply| {
+            serde_yaml::from_slice::<Digest>(reply).ok().filter(|digest| digest.start == start && digest.end == end)
+        }).await
+    }
+
+    /// The number of Paxos instances of the current server.
+    pub async fn total_instances(&mut self) -> Result<usize> {
+        self.ask(MessagePayload::PrintTotalInstances, |reply| serde_yaml::from_slice(reply).ok()).await
+    }
+
+    pub async fn status(&mut self) -> Result<Status> {
+        self.ask(MessagePayload::Status, |reply| serde_yaml::from_slice(reply).ok()).await
+    }
+
+    /// Sets the log level of the current server to a filter like `debug`, or only asks for it if
+    /// `None`, and returns the level in effect.
+    pub async fn log_level(&mut self, level: Option<String>) -> Result<String> {
+        let reply: std::result::Result<String, String> =
+            self.ask(MessagePayload::LogLevel(level), |reply| serde_yaml::from_slice(reply).ok()).await?;
+        reply.map_err(Error::from)
+    }
+
+    /// Sets the faults the current server injects, or only asks for them if `None`, and returns
+    /// the policy in effect.
+    pub async fn faults(&mut self, policy: Option<FaultPolicy>) -> Result<FaultPolicy> {
+        let reply: std::result::Result<FaultPolicy, String> =
+            self.ask(MessagePayload::Faults(policy), |reply| serde_yaml::from_slice(reply).ok()).await?;
+        reply.map_err(Error::from)
+    }
+
+    /// A guard of the lock of `key`, whose renewals go through the socket of this client.
+    async fn guard(&self, key: &str) -> Result<AsyncLockGuard> {
+        let (lost, lost_receiver) = watch::channel(false);
+        let (release, release_receiver) = oneshot::channel();
+        tokio::spawn(keep_lock(self.clone(), key.to_string(), lost, release_receiver));
+        Ok(AsyncLockGuard { key: key.to_string(), lost: lost_receiver, release })
+    }
+
+    /// Proposes `op` and returns whether it was valid.
+    async fn apply(&mut self, op: Operation) -> Result<bool> {
+        // every attempt has the same ID, so that the reply to any of them will do, and the state
+        // machine applies only one of them
+        let request = self.connection.next_request();
+        let data = serde_yaml::to_vec(&MessagePayload::<Operation>::LockerMessage(request, op.clone()))?;
+        for _ in 0..self.attempts {
+            let reply = self.exchange(&data, |reply| {
+                serde_yaml::from_slice::<Applied>(reply).ok()
+                    .filter(|applied| applied.request == request && applied.entry.op == op)
+            }).await?;
+            if let Some(applied) = reply {
+                return Ok(applied.entry.valid);
+            }
+        }
+        Err(self.unreachable())
+    }
+
+    /// Sends `request` until a server answers it with a reply that `accept` takes.
+    async fn ask<R, F>(&mut self, request: MessagePayload<Operation>, accept: F) -> Result<R>
+        where F: Fn(&[u8]) -> Option<R>
+    {
+        let data = serde_yaml::to_vec(&request)?;
+        for _ in 0..self.attempts {
+            if let Some(reply) = self.exchange(&data, &accept).await? {
+                return Ok(reply);
+            }
+        }
+        Err(self.unreachable())
+    }
+
+    async fn query<T, F>(&mut self, request: F, query: Query) -> Result<Page<T>>
+        where T: DeserializeOwned, F: Fn(u64, Query) -> MessagePayload<Operation>
+    {
+        for _ in 0..self.attempts {
+            // chunks of the reply to an earlier attempt must not mix with these
+            let id = self.connection.next_request();
+            let data = serde_yaml::to_vec(&request(id, query.clone()))?;
+            if let Some(text) = self.fetch_chunks(&data, Reassembly::new(id)).await? {
+                return Ok(serde_yaml::from_str(&text)?);
+            }
+            self.next_server();
+        }
+        Err(self.unreachable())
+    }
+
+    /// Sends `data` to the current server and puts the `Chunk`s of its reply together. Waits for
+    /// as long as the timeout after each datagram, and once they stop coming asks again for the
+    /// chunks that got lost, until none of them arrives.
+    async fn fetch_chunks(&mut self, data: &[u8], mut reassembly: Reassembly) -> Result<Option<String>> {
+        let connection = &*self.connection;
+        let mut buf = connection.buf.lock().await;
+        let (server, addr) = {
+            let route = connection.route();
+            route.servers[route.next_server].clone()
+        };
+        let mut request = data.to_vec();
+        loop {
+            connection.socket.send_to(&request, addr).await?;
+            let received = reassembly.progress().0;
+            loop {
+                match time::timeout(self.timeout, connection.socket.recv_from(&mut buf)).await {
+                    Ok(Ok((_, from))) if from != addr => trace!(%from, "dropped a reply from another server"),
+                    Ok(Ok((size, _))) => {
+                        let chunk = serde_yaml::from_slice::<Chunk>(&buf[..size]).ok();
+                        if let Some(text) = chunk.and_then(|chunk| reassembly.add(chunk)) {
+                            return Ok(Some(text));
+                        }
+                    },
+                    Ok(Err(e)) => warn!(%server, error = %e, "cannot receive"),
+                    Err(_) => break,
+                }
+            }
+            if reassembly.progress().0 == received {
+                warn!(%server, %addr, timeout = ?self.timeout, "no reply, trying the next server");
+                return Ok(None);
+            }
+            let missing = reassembly.missing();
+            debug!(%server, missing = missing.len(), "asking again for lost chunks");
+            request = serde_yaml::to_vec(&MessagePayload::<Operation>::Resend(reassembly.request(), missing))?;
+        }
+    }
+
+    /// Sends `data` to the current server and waits for a datagram from it that `accept` takes,
+    /// following the redirects of servers that are not the leader. Moves on to the next server if
+    /// none arrives in time.
+    async fn exchange<R, F>(&mut self, data: &[u8], mut accept: F) -> Result<Option<R>>
+        where F: FnMut(&[u8]) -> Option<R>
+    {
+        let connection = &*self.connection;
+        let mut buf = connection.buf.lock().await;
+        // servers that disagree on the leader could send the request back and forth
+        let mut redirects_left = connection.route().servers.len();
+        'send: loop {
+            let (server, addr) = {
+                let route = connection.route();
+                route.servers[route.next_server].clone()
+            };
+            connection.socket.send_to(data, addr).await?;
+            let deadline = time::Instant::now() + self.timeout;
+            loop {
+                match time::timeout_at(deadline, connection.socket.recv_from(&mut buf)).await {
+                    // late replies of servers asked before are dropped
+                    Ok(Ok((_, from))) if from != addr => trace!(%from, "dropped a reply from another server"),
+                    Ok(Ok((size, _))) => {
+                        if let Some(reply) = accept(&buf[..size]) {
+                            return Ok(Some(reply));
+                        }
+                        let redirect = serde_yaml::from_slice::<MessagePayload<Operation>>(&buf[..size]);
+                        if let Ok(MessagePayload::Redirect(leader, leader_addr)) = redirect {
+                            if redirects_left > 0 {
+                                redirects_left -= 1;
+                                connection.route().follow(leader, leader_addr);
+                                continue 'send;
+                            }
+                        }
+                    },
+                    Ok(Err(e)) => warn!(%server, error = %e, "cannot receive"),
+                    Err(_) => break,
+                }
+            }
+            warn!(%server, %addr, timeout = ?self.timeout, "no reply, trying the next server");
+            self.next_server();
+            return Ok(None);
+        }
+    }
+
+    fn next_server(&self) {
+        let mut route = self.connection.route();
+        route.next_server = (route.next_server + 1) % route.servers.len();
+    }
+
+    fn unreachable(&self) -> Error {
+        ErrorKind::Unreachable(format!("no reply after {} attempts", self.attempts)).into()
+    }
+}
+
+impl Route {
+    /// Sends the requests to `leader` from now on, adding it to the servers if it is new.
+    fn follow(&mut self, leader: NodeID, addr: SocketAddr) {
+        debug!(%leader, %addr, "redirected to the leader");
+        self.next_server = match self.servers.iter().position(|(name, _)| *name == leader) {
+            Some(index) => index,
+            None => {
+                self.servers.push((leader, addr));
+                self.servers.len() - 1
+            },
+        };
+    }
+}
+
+/// What a guard asks of its renewal task when it goes away.
+enum Release {
+    /// Unlock, and send the result.
+    Unlock(oneshot::Sender<Result<()>>),
+    /// Leave the lock held.
+    Leak,
+}
+
+/// Renews the lock of `key` until its guard is unlocked, dropped or leaked, and then unlocks it
+/// unless it was leaked.
+async fn keep_lock(mut client: AsyncLockClient, key: String, lost: watch::Sender<bool>,
+                   mut release: oneshot::Receiver<Release>) {
+    let interval = client.renew_interval;
+    let mut renewals = time::interval_at(time::Instant::now() + interval, interval);
+    renewals.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let reply = loop {
+        tokio::select! {
+            reply = &mut release => break reply.ok(),
+            _ = renewals.tick(), if !*lost.borrow() => {
+                if let Err(e) = client.renew(&key).await {
+                    warn!(%key, error = %e, "lock lost");
+                    let _ = lost.send(true);
+                }
+            },
+        }
+    };
+    if let Some(Release::Leak) = reply {
+        return;
+    }
+    let result = client.unlock(&key).await;
+    match reply {
+        Some(Release::Unlock(reply)) => {
+            let _ = reply.send(result);
+        },
+        _ => {
+            if let Err(e) = result {
+                warn!(%key, error = %e, "cannot unlock the lock of a dropped guard");
+            }
+        },
+    }
+}
+
+/// Holds a lock until it is unlocked or dropped. Dropping it unlocks in the background, while
+/// `unlock` waits for the result.
+pub struct AsyncLockGuard {
+    key: String,
+    lost: watch::Receiver<bool>,
+    release: oneshot::Sender<Release>,
+}
+
+impl AsyncLockGuard {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Whether a renewal failed, in which case the client may not hold the lock any more.
+    pub fn is_lost(&self) -> bool {
+        *self.lost.borrow()
+    }
+
+    /// Waits until a renewal fails.
+    pub async fn lost(&mut self) {
+        // an error means the renewals stopped, which is as bad
+        let _ = self.lost.wait_for(|lost| *lost).await;
+    }
+
+    pub async fn unlock(self) -> Result<()> {
+        let stopped = || Error::from("the renewals of the lock stopped");
+        let (reply, result) = oneshot::channel();
+        self.release.send(Release::Unlock(reply)).map_err(|_| stopped())?;
+        result.await.map_err(|_| stopped())?
+    }
+
+    /// Stops renewing the lock and leaves it held, e.g. for another process to unlock.
+    pub fn leak(self) {
+        let _ = self.release.send(Release::Leak);
+    }
+}
+
+/// The runtime of a blocking client. Dropping a runtime from async code panics, so there it is
+/// shut down in the background instead.
+struct ClientRuntime(Option<Runtime>);
+
+impl ClientRuntime {
+    fn handle(&self) -> &Handle {
+        self.0.as_ref().unwrap().handle()
+    }
+}
+
+impl Drop for ClientRuntime {
+    fn drop(&mut self) {
+        if let (Some(runtime), Ok(_)) = (self.0.take(), Handle::try_current()) {
+            runtime.shutdown_background();
+        }
+    }
+}
+
+/// Runs `future` on `runtime` and blocks until it is done. Unlike `Runtime::block_on`, this does
+/// not panic when called from async code, though it still blocks the thread.
+fn wait<T, F>(runtime: &ClientRuntime, future: F) -> Result<T>
+    where T: Send + 'static, F: Future<Output = Result<T>> + Send + 'static
+{
+    let (reply, result) = mpsc::channel();
+    runtime.handle().spawn(async move {
+        let _ = reply.send(future.await);
+    });
+    result.recv().map_err(|_| Error::from("the runtime of the client stopped"))?
+}
+
+/// Blocks on the requests of an `AsyncLockClient`, which run on a runtime of its own. Should not
+/// be used from async code, whose thread it blocks.
+pub struct LockClient {
+    runtime: Arc<ClientRuntime>,
+    client: AsyncLockClient,
+}
+
+impl LockClient {
+    pub fn id(&self) -> &NodeID {
+        self.client.id()
+    }
+
+    pub fn server(&self) -> NodeID {
+        self.client.server()
+    }
+
+    /// Runs `request` with a clone of the client, which shares its socket.
+    fn run<T, F, R>(&self, request: F) -> Result<T>
+        where T: Send + 'static, F: FnOnce(AsyncLockClient) -> R, R: Future<Output = Result<T>> + Send + 'static
+    {
+        wait(&self.runtime, request(self.client.clone()))
+    }
+
+    fn guard(&self, guard: AsyncLockGuard) -> LockGuard {
+        LockGuard { held: Some((self.runtime.clone(), guard)) }
+    }
+
+    /// See `AsyncLockClient::lock`.
+    pub fn lock(&mut self, key: &str) -> Result<LockGuard> {
+        let key = key.to_string();
+        let guard = self.run(|mut client| async move { client.lock(&key).await })?;
+        Ok(self.guard(guard))
+    }
+
+    pub fn try_lock(&mut self, key: &str) -> Result<Option<LockGuard>> {
+        let key = key.to_string();
+        let guard = self.run(|mut client| async move { client.try_lock(&key).await })?;
+        Ok(guard.map(|guard| self.guard(guard)))
+    }
+
+    pub fn acquire(&mut self, key: &str) -> Result<()> {
+        let key = key.to_string();
+        self.run(|mut client| async move { client.acquire(&key).await })
+    }
+
+    pub fn try_acquire(&mut self, key: &str) -> Result<bool> {
+        let key = key.to_string();
+        self.run(|mut client| async move { client.try_acquire(&key).await })
+    }
+
+    pub fn unlock(&mut self, key: &str) -> Result<()> {
+        let key = key.to_string();
+        self.run(|mut client| async move { client.unlock(&key).await })
+    }
+
+    pub fn renew(&mut self, key: &str) -> Result<()> {
+        let key = key.to_string();
+        self.run(|mut client| async move { client.renew(&key).await })
+    }
+
+    pub fn list_locks(&mut self, query: Query) -> Result<Page<(String, NodeID)>> {
+        self.run(|mut client| async move { client.list_locks(query).await })
+    }
+
+    pub fn log(&mut self, query: Query) -> Result<Page<(usize, LogEntry)>> {
+        self.run(|mut client| async move { client.log(query).await })
+    }
+
+    pub fn digest(&mut self, start: usize, end: usize) -> Result<Digest> {
+        self.run(move |mut client| async move { client.digest(start, end).await })
+    }
+
+    pub fn total_instances(&mut self) -> Result<usize> {
+        self.run(|mut client| async move { client.total_instances().await })
+    }
+
+    pub fn status(&mut self) -> Result<Status> {
+        self.run(|mut client| async move { client.status().await })
+    }
+
+    pub fn log_level(&mut self, level: Option<String>) -> Result<String> {
+        self.run(|mut client| async move { client.log_level(level).await })
+    }
+
+    pub fn faults(&mut self, policy: Option<FaultPolicy>) -> Result<FaultPolicy> {
+        self.run(|mut client| async move { client.faults(policy).await })
+    }
+}
+
+/// Holds a lock until it is unlocked or dropped. Dropping it waits until the lock is released,
+/// except in async code, where it unlocks in the background.
+pub struct LockGuard {
+    /// `None` once unlocked.
+    held: Option<(Arc<ClientRuntime>, AsyncLockGuard)>,
+}
+
+impl LockGuard {
+    pub fn key(&self) -> &str {
+        self.held.as_ref().map_or("", |(_, guard)| guard.key())
+    }
+
+    /// See `AsyncLockGuard::is_lost`.
+    pub fn is_lost(&self) -> bool {
+        self.held.as_ref().is_some_and(|(_, guard)| guard.is_lost())
+    }
+
+    pub fn unlock(mut self) -> Result<()> {
+        match self.held.take() {
+            Some((runtime, guard)) => wait(&runtime, guard.unlock()),
+            None => Ok(()),
+        }
+    }
+
+    /// See `AsyncLockGuard::leak`.
+    pub fn leak(mut self) {
+        if let Some((_, guard)) = self.held.take() {
+            guard.leak();
+        }
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some((runtime, guard)) = self.held.take() {
+            let unlock = move || {
+                let key = guard.key().to_string();
+                if let Err(e) = wait(&runtime, guard.unlock()) {
+                    warn!(%key, error = %e, "cannot unlock the lock of a dropped guard");
+                }
+            };
+            // async code must not block, and the runtime may go with the guard
+            match Handle::try_current() {
+                Ok(_) => drop(thread::spawn(unlock)),
+                Err(_) => unlock(),
+            }
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! A cluster of servers on localhost, for people trying the lock service and for tests.
+//!
+//! `LocalClusterBuilder` binds a socket on a free port for every server, writes the configuration
+//! of the cluster to a file that clients can read, and starts every server on its socket, either on
+//! a thread of this process or as a child process of the `server` binary. `LocalCluster` kills,
+//! restarts, pauses and partitions the servers, and stops all of them when it is dropped.
+//!
+//! ```no_run
+//! use paxos550::cluster::LocalClusterBuilder;
+//!
+//! let mut cluster = LocalClusterBuilder::new(3).start().unwrap();
+//! println!("clients can use {}", cluster.config_path().display());
+//! // server1 cannot reach the other two until the partition heals
+//! cluster.partition(&[vec!["server1".to_string()]]).unwrap();
+//! cluster.heal().unwrap();
+//! cluster.kill("server2").unwrap();
+//! cluster.restart("server2").unwrap();
+//! ```
+//!
+//! Partitions, pauses and lossy links are made by the servers themselves, which follow the
+//! `FaultPolicy` the cluster sends them with `MessagePayload::Faults`, which the cluster enables in
+//! their configuration.
+//!
+//! Each server keeps what it promised, accepted and decided in `<id>.storage` in the directory of
+//! the cluster, so that `LocalCluster::restart` brings a killed server back with its promises and
+//! its log. It catches up on the instances decided while it was down as it hears of them.
+
+use crate::config::{Config, NodeConfig, ServerConfig};
+use crate::errors::*;
+use crate::locker::Operation;
+use crate::network::message::MessagePayload;
+use crate::paxos::NodeID;
+use crate::server::{FaultPolicy, ServerBuilder, ServerHandle};
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+#[cfg(unix)]
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+// how long a server has to answer the launcher, e.g. while its process starts
+const READY_TIMEOUT: Duration = Duration::from_secs(10);
+const ADMIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where the servers of a `LocalCluster` run.
+#[derive(Clone, Debug)]
+pub enum Launch {
+    /// On threads of this process.
+    InProcess,
+    /// As child processes of the `server` binary at this path, on unix systems. Each one gets its
+    /// socket as standard input and logs to `<id>.log` in the directory of the cluster.
+    Processes(PathBuf),
+}
+
+pub struct LocalClusterBuilder {
+    size: usize,
+    launch: Launch,
+    server: ServerConfig,
+    dir: Option<PathBuf>,
+}
+
+impl LocalClusterBuilder {
+    /// A cluster of `size` servers named `server1`, `server2`… that run in this process.
+    pub fn new(size: usize) -> LocalClusterBuilder {
+        LocalClusterBuilder {
+            size,
+            launch: Launch::InProcess,
+            server: ServerConfig::default(),
+            dir: None,
+        }
+    }
+
+    pub fn launch(mut self, launch: Launch) -> LocalClusterBuilder {
+        self.launch = launch;
+        self
+    }
+
+    /// The settings of every server, as in the `server` section of a configuration file. Fault
+    /// injection is always enabled.
+    pub fn server_config(mut self, server: ServerConfig) -> LocalClusterBuilder {
+        self.server = server;
+        self
+    }
+
+    /// Where to write the configuration file, the logs of child processes and the storage of the
+    /// servers, which carry on from what they saved in a cluster that was started there before.
+    /// Without one, a new directory is made in the temporary directory of the system, and removed
+    /// when the cluster stops.
+    pub fn dir(mut self, dir: PathBuf) -> LocalClusterBuilder {
+        self.dir = Some(dir);
+        self
+    }
+
+    /// Writes the configuration file and starts every server.
+    pub fn start(self) -> Result<LocalCluster> {
+        if self.size == 0 {
+            bail!(ErrorKind::InvalidConfig("a cluster needs at least one server".to_string()));
+        }
+        let (dir, temporary) = match self.dir {
+            Some(dir) => (dir, false),
+            None => {
+                let name = format!("paxos550-cluster-{}-{:08x}", process::id(), rand::random::<u32>());
+                (env::temp_dir().join(name), true)
+            },
+        };
+        fs::create_dir_all(&dir).chain_err(|| format!("cannot create {}", dir.display()))?;
+
+        // the servers get the sockets bound here, so that no other program can take their ports
+        let sockets = (0..self.size).map(|_| UdpSocket::bind("127.0.0.1:0")).collect::<io::Result<Vec<_>>>()?;
+        let mut nodes = Vec::new();
+        for (i, socket) in sockets.iter().enumerate() {
+            let id = format!("server{}", i + 1);
+            nodes.push(NodeConfig {
+                storage: Some(dir.join(format!("{}.storage", id))),
+                id,
+                address: socket.local_addr()?,
+                listen: None,
+                metrics: None,
+            });
+        }
+        let server = ServerConfig { fault_injection: true, ..self.server };
+        let config = Config { nodes, server };
+        config.validate()?;
+        let config_path = dir.join("cluster.yaml");
+        fs::write(&config_path, serde_yaml::to_string(&config)?)
+            .chain_err(|| format!("cannot write {}", config_path.display()))?;
+
+        let mut cluster = LocalCluster {
+            config,
+            config_path,
+            dir,
+            temporary,
+            launch: self.launch,
+            servers: BTreeMap::new(),
+            sockets: BTreeMap::new(),
+            faults: BTreeMap::new(),
+        };
+        for (id, socket) in cluster.ids().into_iter().zip(sockets) {
+            cluster.sockets.insert(id.clone(), socket);
+            cluster.launch(&id)?;
+        }
+        Ok(cluster)
+    }
+}
+
+enum Server {
+    Thread(Box<ServerHandle>),
+    Process(Child),
+}
+
+/// Servers started by `LocalClusterBuilder`. Dropping the cluster stops them.
+pub struct LocalCluster {
+    config: Config,
+    config_path: PathBuf,
+    dir: PathBuf,
+    /// Whether `dir` is removed when the cluster stops.
+    temporary: bool,
+    launch: Launch,
+    /// The servers that were started and not killed since.
+    servers: BTreeMap<NodeID, Server>,
+    /// The socket of each server, which the cluster holds on to while the server is down, so
+    /// that it comes back on the same port.
+    sockets: BTreeMap<NodeID, UdpSocket>,
+    /// The faults of each server, kept while it is killed.
+    faults: BTreeMap<NodeID, FaultPolicy>,
+}
+
+impl LocalCluster {
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The configuration file, for clients and for servers started by hand.
+    pub fn config_path(&self) -> &Path {
+        &self.config_path
+    }
+
+    pub fn ids(&self) -> Vec<NodeID> {
+        self.config.nodes.iter().map(|node| node.id.clone()).collect()
+    }
+
+    pub fn address(&self, id: &str) -> Result<SocketAddr> {
+        Ok(self.config.node(id)?.address)
+    }
+
+    /// Whether server `id` runs. A child process may have exited on its own.
+    pub fn is_running(&mut self, id: &str) -> bool {
+        let exited = match self.servers.get_mut(id) {
+            None => return false,
+            Some(Server::Thread(_)) => false,
+            Some(Server::Process(child)) => !matches!(child.try_wait(), Ok(None)),
+        };
+        if exited {
+            self.servers.remove(id);
+        }
+        !exited
+    }
+
+    /// The peers server `id` cannot reach, because of a partition or its faults.
+    pub fn disconnected(&self, id: &str) -> Vec<NodeID> {
+        let faults = self.faults(id);
+        self.ids().into_iter().filter(|peer| peer != id && faults.link(peer).disconnected).collect()
+    }
+
+    pub fn faults(&self, id: &str) -> FaultPolicy {
+        self.faults.get(id).cloned().unwrap_or_default()
+    }
+
+    /// Replaces the faults of server `id`, including the links a partition cut.
+    pub fn set_faults(&mut self, id: &str, faults: FaultPolicy) -> Result<()> {
+        self.config.node(id)?;
+        faults.validate()?;
+        self.faults.insert(id.to_string(), faults);
+        if self.is_running(id) {
+            self.send_faults(id)?;
+        }
+        Ok(())
+    }
+
+    /// Holds what server `id` receives and the timeouts that fire until it is resumed, as if its
+    /// process was stopped.
+    pub fn pause(&mut self, id: &str) -> Result<()> {
+        let faults = FaultPolicy { paused: true, ..self.faults(id) };
+        self.set_faults(id, faults)
+    }
+
+    pub fn resume(&mut self, id: &str) -> Result<()> {
+        let faults = FaultPolicy { paused: false, ..self.faults(id) };
+        self.set_faults(id, faults)
+    }
+
+    /// Stops server `id`. A server in this process shuts down, a child process is killed.
+    pub fn kill(&mut self, id: &str) -> Result<()> {
+        self.config.node(id)?;
+        match self.servers.remove(id) {
+            Some(Server::Thread(mut handle)) => handle.shutdown(),
+            Some(Server::Process(mut child)) => {
+                // fails if the process exited already, which is as good
+                let _ = child.kill();
+                child.wait()?;
+                Ok(())
+            },
+            None => bail!("{} is not running", id),
+        }
+    }
+
+    /// Starts server `id` again after it was killed or exited, on the same port and with the
+    /// promises, accepted values and log it saved, and the faults it had.
+    pub fn restart(&mut self, id: &str) -> Result<()> {
+        self.config.node(id)?;
+        if self.is_running(id) {
+            bail!("{} is running", id);
+        }
+        self.launch(id)
+    }
+
+    /// Starts server `id` on its socket, and waits until it answers with its faults.
+    fn launch(&mut self, id: &str) -> Result<()> {
+        let socket = self.sockets[id].try_clone()?;
+        let server = match self.launch {
+            Launch::InProcess => {
+                let mut handle = ServerBuilder::from_config(&self.config, id)?.socket(socket).build()?;
+                handle.start()?;
+                Server::Thread(Box::new(handle))
+            },
+            Launch::Processes(ref binary) => {
+                let log_path = self.dir.join(format!("{}.log", id));
+                let log = OpenOptions::new().create(true).append(true).open(&log_path)
+                    .chain_err(|| format!("cannot open {}", log_path.display()))?;
+                let child = Command::new(binary)
+                    .arg("--config").arg(&self.config_path)
+                    .arg("--id").arg(id)
+                    .arg("--listen-stdin")
+                    .stdin(socket_stdio(socket)?)
+                    .stdout(log.try_clone()?)
+                    .stderr(log)
+                    .spawn()
+                    .chain_err(|| format!("cannot run {}", binary.display()))?;
+                Server::Process(child)
+            },
+        };
+        self.servers.insert(id.to_string(), server);
+        self.send_faults(id)
+    }
+
+    /// Splits the servers into `groups` that cannot reach each other. The servers in no group make
+    /// up one more group.
+    pub fn partition(&mut self, groups: &[Vec<NodeID>]) -> Result<()> {
+        let mut group_of = BTreeMap::new();
+        for (i, group) in groups.iter().enumerate() {
+            for id in group {
+                self.config.node(id)?;
+                if group_of.insert(id.clone(), i).is_some() {
+                    bail!("{} is in two groups", id);
+                }
+            }
+        }
+        let rest = groups.len();
+        let group = |id: &NodeID| *group_of.get(id).unwrap_or(&rest);
+        self.cut(|id, peer| group(id) != group(peer))
+    }
+
+    /// Lets every server reach every other again. Other faults stay.
+    pub fn heal(&mut self) -> Result<()> {
+        self.cut(|_, _| false)
+    }
+
+    /// Stops every server, and removes the directory of the cluster if it was made for it.
+    pub fn shutdown(&mut self) -> Result<()> {
+        let ids: Vec<_> = self.servers.keys().cloned().collect();
+        // the other servers are stopped even if one fails to
+        let results: Vec<_> = ids.iter().map(|id| self.kill(id)).collect();
+        if self.temporary {
+            self.temporary = false;
+            fs::remove_dir_all(&self.dir).chain_err(|| format!("cannot remove {}", self.dir.display()))?;
+        }
+        results.into_iter().collect()
+    }
+
+    /// Disconnects every server from the peers `cut` returns true for, and connects it to the
+    /// others.
+    fn cut<F: Fn(&NodeID, &NodeID) -> bool>(&mut self, cut: F) -> Result<()> {
+        let ids = self.ids();
+        for id in &ids {
+            let mut faults = self.faults(id);
+            for peer in ids.iter().filter(|peer| *peer != id) {
+                let mut link = faults.link(peer).clone();
+                link.disconnected = cut(id, peer);
+                faults.peers.insert(peer.clone(), link);
+            }
+            let default = faults.default.clone();
+            faults.peers.retain(|_, link| *link != default);
+            self.set_faults(id, faults)?;
+        }
+        Ok(())
+    }
+
+    /// Sends server `id` its faults, and waits until it answers, which also tells when a child
+    /// process is ready.
+    fn send_faults(&self, id: &str) -> Result<()> {
+        let addr = self.address(id)?;
+        let socket = UdpSocket::bind("127.0.0.1:0")?;
+        socket.set_read_timeout(Some(ADMIN_INTERVAL))?;
+        let request = serde_yaml::to_vec(&MessagePayload::<Operation>::Faults(Some(self.faults(id))))?;
+        let mut buf = vec![0u8; 65536];
+        let deadline = Instant::now() + READY_TIMEOUT;
+        while Instant::now() < deadline {
+            socket.send_to(&request, addr)?;
+            match socket.recv_from(&mut buf) {
+                Ok((size, from)) if from == addr => {
+                    match serde_yaml::from_slice::<std::result::Result<FaultPolicy, String>>(&buf[..size]) {
+                        Ok(Ok(_)) => return Ok(()),
+                        Ok(Err(e)) => bail!("{} refused its faults: {}", id, e),
+                        Err(_) => (),
+                    }
+                },
+                // the request waits in the socket of the server until it runs
+                _ => (),
+            }
+        }
+        bail!(ErrorKind::Unreachable(format!("{} at {} did not answer", id, addr)))
+    }
+}
+
+/// Passes `socket` as the standard input of a child process, for `server --listen-stdin`.
+#[cfg(unix)]
+fn socket_stdio(socket: UdpSocket) -> Result<Stdio> {
+    Ok(Stdio::from(OwnedFd::from(socket)))
+}
+
+#[cfg(not(unix))]
+fn socket_stdio(_socket: UdpSocket) -> Result<Stdio> {
+    bail!("servers run as child processes on unix systems only")
+}
+
+impl Drop for LocalCluster {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            error!(error = %e, "cannot stop the cluster");
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Cluster configuration shared by the server and the client.
+//!
+//! ```yaml
+//! nodes:
+//!   - id: node1
+//!     address: 127.0.0.1:9001
+//!   - id: node2
+//!     address: 127.0.0.1:9002
+//!     listen: 0.0.0.0:9002        # defaults to `address`
+//!     storage: /var/lib/node2.log # keeps the Paxos state and the log across restarts
+//!     metrics: 127.0.0.1:9102     # serve Prometheus metrics at http://127.0.0.1:9102/metrics
+//! server:                         # every field is optional
+//!   timeout_ms: 1000              # of every phase, unless set below
+//!   prepare_timeout_ms: 1000
+//!   propose_timeout_ms: 1000
+//!   learn_timeout_ms: 1000
+//!   max_backoff_ms: 16000
+//!   adaptive_timeouts: false      # derive the timeouts from the round-trip times
+//!   redirect_clients: false       # send clients to the leader
+//!   batch_size: 64
+//!   batch_delay_ms: 5
+//!   window: 8
+//!   lease_ms: 10000               # of the locks, the same on every server
+//!   log_level_changes: false      # obey `MessagePayload::LogLevel` that sets a level
+//!   fault_injection: false        # obey `MessagePayload::Faults`, for chaos tests only
+//! ```
+
+use crate::errors::*;
+use crate::locker::DEFAULT_LEASE;
+use crate::paxos::NodeID;
+use crate::server::{DEFAULT_BATCH_DELAY, DEFAULT_BATCH_SIZE, DEFAULT_MAX_BACKOFF, DEFAULT_TIMEOUT, DEFAULT_WINDOW};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub nodes: Vec<NodeConfig>,
+    #[serde(default)]
+    pub server: ServerConfig,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    pub id: NodeID,
+    /// Where the other nodes and the clients reach this node.
+    pub address: SocketAddr,
+    /// Where the node binds, if not `address`.
+    #[serde(default)]
+    pub listen: Option<SocketAddr>,
+    /// File to keep what the node promised, accepted and decided in, read back when it starts.
+    #[serde(default)]
+    pub storage: Option<PathBuf>,
+    /// Where to serve Prometheus metrics over HTTP.
+    #[serde(default)]
+    pub metrics: Option<SocketAddr>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub timeout_ms: u64,
+    pub prepare_timeout_ms: Option<u64>,
+    pub propose_timeout_ms: Option<u64>,
+    pub learn_timeout_ms: Option<u64>,
+    pub max_backoff_ms: u64,
+    pub adaptive_timeouts: bool,
+    pub redirect_clients: bool,
+    pub batch_size: usize,
+    pub batch_delay_ms: u64,
+    pub window: usize,
+    pub lease_ms: u64,
+    pub log_level_changes: bool,
+    pub fault_injection: bool,
+}
+
+impl Default for ServerConfig {
+    fn default() -> ServerConfig {
+        ServerConfig {
+            timeout_ms: DEFAULT_TIMEOUT.as_millis() as u64,
+            prepare_timeout_ms: None,
+            propose_timeout_ms: None,
+            learn_timeout_ms: None,
+            max_backoff_ms: DEFAULT_MAX_BACKOFF.as_millis() as u64,
+            adaptive_timeouts: false,
+            redirect_clients: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+            batch_delay_ms: DEFAULT_BATCH_DELAY.as_millis() as u64,
+            window: DEFAULT_WINDOW,
+            lease_ms: DEFAULT_LEASE.as_millis() as u64,
+            log_level_changes: false,
+            fault_injection: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub fn prepare_timeout(&self) -> Option<Duration> {
+        self.prepare_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn propose_timeout(&self) -> Option<Duration> {
+        self.propose_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn learn_timeout(&self) -> Option<Duration> {
+        self.learn_timeout_ms.map(Duration::from_millis)
+    }
+
+    pub fn max_backoff(&self) -> Duration {
+        Duration::from_millis(self.max_backoff_ms)
+    }
+
+    pub fn batch_delay(&self) -> Duration {
+        Duration::from_millis(self.batch_delay_ms)
+    }
+
+    pub fn lease(&self) -> Duration {
+        Duration::from_millis(self.lease_ms)
+    }
+}
+
+impl Config {
+    /// Reads and validates a YAML configuration file.
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = fs::read_to_string(path)
+            .chain_err(|| format!("cannot read config file {}", path.display()))?;
+        Config::parse(&text).chain_err(|| format!("invalid config file {}", path.display()))
+    }
+
+    pub fn parse(text: &str) -> Result<Config> {
+        let config: Config = serde_yaml::from_str(text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.nodes.is_empty() {
+            bail!(ErrorKind::InvalidConfig("no nodes".to_string()));
+        }
+        let mut ids = HashSet::new();
+        let mut addresses = HashSet::new();
+        for node in &self.nodes {
+            if !ids.insert(&node.id) {
+                bail!(ErrorKind::InvalidConfig(format!("node {} is listed twice", node.id)));
+            }
+            if !addresses.insert(node.address) {
+                bail!(ErrorKind::InvalidConfig(format!("address {} is used by two nodes", node.address)));
+            }
+        }
+        let server = &self.server;
+        let phase_timeouts = [server.prepare_timeout_ms, server.propose_timeout_ms, server.learn_timeout_ms];
+        if server.timeout_ms == 0 || phase_timeouts.contains(&Some(0)) || server.max_backoff_ms == 0 || server.lease_ms == 0 {
+            bail!(ErrorKind::InvalidConfig("timeouts and lease must be positive".to_string()));
+        }
+        if server.batch_size == 0 || server.window == 0 {
+            bail!(ErrorKind::InvalidConfig("batch_size and window must be positive".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn node(&self, id: &str) -> Result<&NodeConfig> {
+        self.nodes.iter().find(|n| n.id == id).ok_or_else(|| {
+            let ids: Vec<_> = self.nodes.iter().map(|n| n.id.as_str()).collect();
+            ErrorKind::InvalidConfig(format!("no node {} in the config, known nodes: {}", id, ids.join(", "))).into()
+        })
+    }
+
+    /// Addresses of all the nodes.
+    pub fn members(&self) -> HashMap<NodeID, SocketAddr> {
+        self.nodes.iter().map(|n| (n.id.clone(), n.address)).collect()
+    }
+
+    /// Addresses of all the nodes but `id`.
+    pub fn peers(&self, id: &str) -> HashMap<NodeID, SocketAddr> {
+        let mut members = self.members();
+        members.remove(id);
+        members
+    }
+}
+
+/// The servers of the config file at `path`, if any, with the `id=addr` `members` added to them
+/// or overriding them, as the programs take them from `--config` and `--server`.
+pub fn servers<'a, I>(path: Option<&Path>, members: I) -> Result<HashMap<NodeID, SocketAddr>>
+    where I: IntoIterator<Item = &'a str>
+{
+    let mut servers = match path {
+        Some(path) => Config::load(path)?.members(),
+        None => HashMap::new(),
+    };
+    for member in members {
+        let (id, addr) = parse_member(member)?;
+        servers.insert(id, addr);
+    }
+    if servers.is_empty() {
+        bail!("no servers, use --config or --server");
+    }
+    Ok(servers)
+}
+
+/// Parses the value of a command-line flag, naming the flag if it is invalid.
+pub fn parse_flag<T: FromStr>(value: &str, flag: &str) -> Result<T> {
+    value.parse().map_err(|_| format!("invalid value '{}' for {}", value, flag).into())
+}
+
+/// Parses a node given on the command line as `id=addr`, e.g. `node1=127.0.0.1:9001`.
+pub fn parse_member(member: &str) -> Result<(NodeID, SocketAddr)> {
+    let invalid = || ErrorKind::InvalidConfig(format!("'{}' is not in `id=addr` format", member));
+    let mut split = member.splitn(2, '=');
+    let id = split.next().filter(|id| !id.is_empty()).ok_or_else(invalid)?;
+    let addr = split.next().ok_or_else(invalid)?;
+    let addr = addr.parse().chain_err(invalid)?;
+    Ok((id.to_string(), addr))
+}
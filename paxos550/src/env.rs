@@ -0,0 +1,140 @@
+//! Sources of time and randomness.
+//!
+//! The library never reads the system clock or the thread-local RNG directly. Components take a
+//! `Clock` and a `Random` instead, so that a run can be replayed exactly from a seed.
+
+use rand::{FromEntropy, RngCore, SeedableRng};
+use rand::rngs::StdRng;
+use tokio::sync::watch;
+use tokio::time;
+use std::future::{self, Future};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send {
+    fn now(&self) -> Instant;
+
+    /// The time of day, which the leases of locks are measured in.
+    fn system_time(&self) -> SystemTime;
+
+    /// Completes once `now()` reaches `deadline`. Every timer of a server waits on this.
+    fn sleep_until(&self, deadline: Instant) -> Sleep;
+}
+
+/// A wait on a `Clock`, which does not borrow it.
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Random number source handed to the Paxos components.
+pub type Random = Box<dyn RandomSource>;
+
+/// An RNG that can be cloned behind a `Box`, so that the components holding it can be cloned.
+pub trait RandomSource: RngCore + Send {
+    fn clone_box(&self) -> Random;
+}
+
+impl<R: RngCore + Send + Clone + 'static> RandomSource for R {
+    fn clone_box(&self) -> Random {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Random {
+    fn clone(&self) -> Random {
+        (**self).clone_box()
+    }
+}
+
+/// The real clock.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        Box::pin(time::sleep_until(deadline.into()))
+    }
+}
+
+/// A clock that only moves when it is told to. Clones share the same time, and the timers
+/// waiting on it fire when it is moved past their deadline.
+#[derive(Clone, Debug)]
+pub struct ManualClock {
+    start: Instant,
+    elapsed: Arc<watch::Sender<Duration>>,
+}
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock {
+            start: Instant::now(),
+            elapsed: Arc::new(watch::Sender::new(Duration::default())),
+        }
+    }
+
+    /// Time since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.borrow()
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.send_modify(|elapsed| *elapsed += duration);
+    }
+
+    /// Moves the clock to `elapsed` after its creation. The clock never goes backwards.
+    pub fn set_elapsed(&self, elapsed: Duration) {
+        self.elapsed.send_if_modified(|current| {
+            let later = elapsed > *current;
+            if later {
+                *current = elapsed;
+            }
+            later
+        });
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> ManualClock {
+        ManualClock::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+
+    /// Starts at the UNIX epoch, so that runs are the same whenever they happen.
+    fn system_time(&self) -> SystemTime {
+        UNIX_EPOCH + self.elapsed()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Sleep {
+        let (start, mut elapsed) = (self.start, self.elapsed.subscribe());
+        Box::pin(async move {
+            while start + *elapsed.borrow_and_update() < deadline {
+                // the clock cannot move any more once every clone is gone
+                if elapsed.changed().await.is_err() {
+                    future::pending::<()>().await;
+                }
+            }
+        })
+    }
+}
+
+/// A random source seeded from the operating system.
+pub fn system_random() -> Random {
+    Box::new(StdRng::from_entropy())
+}
+
+/// A deterministic random source. The same seed always produces the same sequence.
+pub fn seeded_random(seed: u64) -> Random {
+    Box::new(StdRng::seed_from_u64(seed))
+}
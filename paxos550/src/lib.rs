@@ -3,16 +3,24 @@ extern crate tokio;
 #[macro_use] extern crate error_chain;
 #[macro_use] extern crate serde_derive;
 extern crate serde_yaml;
-#[macro_use] extern crate log;
+extern crate serde_json;
+#[macro_use] extern crate tracing;
 
+pub mod client;
+pub mod cluster;
+pub mod config;
+pub mod env;
 pub mod paxos;
 pub mod locker;
+pub mod logging;
 pub mod network;
+pub mod server;
+pub mod sim;
 
 pub mod errors {
+    use serde_json;
     use serde_yaml;
     use std;
-    use tokio;
 
     error_chain! {
         errors {
@@ -20,13 +28,37 @@ pub mod errors {
                 description("instance not exists")
                 display("instance not exists: '{}'", instance_id)
             }
+            SafetyViolation(instance_id: usize, detail: String) {
+                description("nodes learned different values")
+                display("safety violation on instance '{}': {}", instance_id, detail)
+            }
+            NotLinearizable(detail: String) {
+                description("history is not linearizable")
+                display("history is not linearizable: {}", detail)
+            }
+            InvalidConfig(detail: String) {
+                description("invalid configuration")
+                display("invalid configuration: {}", detail)
+            }
+            Unreachable(detail: String) {
+                description("no server answered")
+                display("no server answered: {}", detail)
+            }
+            LockHeld(key: String) {
+                description("lock is held by another client")
+                display("lock '{}' is held by another client", key)
+            }
+            LockNotHeld(key: String) {
+                description("lock is not held by this client")
+                display("lock '{}' is not held by this client", key)
+            }
         }
         foreign_links {
             SerdeError(serde_yaml::Error);
+            JsonError(serde_json::Error);
             IoError(std::io::Error);
-            TokioTimerError(tokio::timer::Error);
         }
     }
 }
 
-pub use network::*;
+pub use crate::network::*;
@@ -1,40 +1,138 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::time::Duration;
 use std::vec::Vec;
 
-use paxos::NodeID;
+use crate::errors::*;
+use crate::paxos::NodeID;
+
+use serde::Serialize;
+
+/// How long a lock is held after it was taken or last renewed.
+pub const DEFAULT_LEASE: Duration = Duration::from_secs(10);
 
 #[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 pub enum Operation {
+    /// Valid if the key is not locked, or is locked by the node already, whose lease it extends.
     Lock(String, NodeID),
-    Unlock(String, NodeID)
+    Unlock(String, NodeID),
+    /// Valid only if the node holds the lock, whose lease it extends.
+    Renew(String, NodeID),
+}
+
+impl Operation {
+    pub fn key(&self) -> &str {
+        match self {
+            Operation::Lock(key, _) | Operation::Unlock(key, _) | Operation::Renew(key, _) => key,
+        }
+    }
+
+    /// The client the operation is on behalf of.
+    pub fn client(&self) -> &NodeID {
+        match self {
+            Operation::Lock(_, node) | Operation::Unlock(_, node) | Operation::Renew(_, node) => node,
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
 pub struct LogEntry {
-    op: Operation,
-    valid: bool,
+    pub op: Operation,
+    pub valid: bool,
+    /// The time the operation was applied at, in milliseconds since the UNIX epoch.
+    pub time_ms: u64,
 }
 
+/// The locks, as leases. A lock expires `lease` after it was taken or last renewed, unless it is
+/// unlocked before. Time only moves with the operations: each carries the time the server that
+/// proposed it got it, so every replica expires the same locks at the same point of the log.
+/// Servers whose clocks are off by a good part of the lease cut the leases short or make them last.
+///
+/// Clients send a request again to another server when they get no answer, so two copies of it
+/// can be decided. `apply_request` lets each request take effect once.
 pub struct Locker {
     locks: HashMap<String, NodeID>,
-    log: Vec<LogEntry>
+    /// When each lock expires, in milliseconds since the UNIX epoch.
+    expiries: HashMap<String, u64>,
+    by_expiry: BTreeSet<(u64, String)>,
+    lease_ms: u64,
+    /// The latest time of the operations so far, so that time never goes back.
+    time_ms: u64,
+    log: Vec<LogEntry>,
+    /// The operation and the result of each request applied in the last two leases, by client
+    /// and ID.
+    requests: HashMap<(NodeID, u64), (Operation, bool)>,
+    /// The same requests with their time, oldest first, to forget them.
+    request_times: VecDeque<(u64, NodeID, u64)>,
 }
 
 impl Locker {
     pub fn new() -> Locker {
+        Locker::with_lease(DEFAULT_LEASE)
+    }
+
+    pub fn with_lease(lease: Duration) -> Locker {
         Locker {
             locks: HashMap::new(),
-            log: Vec::new()
+            expiries: HashMap::new(),
+            by_expiry: BTreeSet::new(),
+            lease_ms: lease.as_millis() as u64,
+            time_ms: 0,
+            log: Vec::new(),
+            requests: HashMap::new(),
+            request_times: VecDeque::new(),
         }
     }
 
-    pub fn append_log(&mut self, op: &Operation) {
+    /// Applies request `id` of the client of `op` like `append_log`, unless a copy of it was
+    /// applied already, in which case it returns the result of that copy and changes nothing.
+    /// A request older than a lease is invalid and not applied either. Requests are remembered
+    /// for two leases, so as long as a client sends its copies within a lease, the copies that
+    /// come after the first are either recognized or too old. ID 0 is for operations no client
+    /// waits for, which are always applied.
+    pub fn apply_request(&mut self, id: u64, op: &Operation, time_ms: u64) -> bool {
+        if id == 0 {
+            return self.append_log(op, time_ms);
+        }
+        let request = (op.client().clone(), id);
+        if let Some((applied, valid)) = self.requests.get(&request) {
+            if applied == op {
+                debug!(id, ?op, "request applied already");
+                return *valid;
+            }
+        }
+        if time_ms.saturating_add(self.lease_ms) < self.time_ms {
+            debug!(id, ?op, time_ms, "request too old to apply");
+            return false;
+        }
+        let valid = self.append_log(op, time_ms);
+        self.requests.insert(request.clone(), (op.clone(), valid));
+        self.request_times.push_back((time_ms, request.0, request.1));
+        self.forget_requests();
+        valid
+    }
+
+    fn forget_requests(&mut self) {
+        while let Some(&(time_ms, _, _)) = self.request_times.front() {
+            if time_ms.saturating_add(2 * self.lease_ms) >= self.time_ms {
+                break;
+            }
+            let (_, client, id) = self.request_times.pop_front().unwrap();
+            self.requests.remove(&(client, id));
+        }
+    }
+
+    /// Applies `op` at `time_ms` and returns whether it was valid, i.e. whether the lock was
+    /// acquired, released or still held. The locks whose lease ended by then expire first.
+    pub fn append_log(&mut self, op: &Operation, time_ms: u64) -> bool {
+        self.time_ms = self.time_ms.max(time_ms);
+        self.expire();
         let mut valid = false;
         match op {
-            Operation::Lock(ref key, ref value) => {
-                if !self.locks.contains_key(key) {
-                    self.locks.insert(key.clone(), value.clone());
-                    valid = true;
+            Operation::Lock(ref key, ref node) => {
+                valid = self.locks.get(key).is_none_or(|owner| owner == node);
+                if valid {
+                    self.locks.insert(key.clone(), node.clone());
+                    self.extend_lease(key);
                 }
             },
             Operation::Unlock(ref key, ref node) => {
@@ -43,18 +141,97 @@ impl Locker {
                     _ => ()
                 }
                 if valid {
-                    self.locks.remove(key);  // FIXME ugly.
+                    self.release(key);
+                }
+            },
+            Operation::Renew(ref key, ref node) => {
+                valid = self.locks.get(key) == Some(node);
+                if valid {
+                    self.extend_lease(key);
                 }
             },
         }
-        self.log.push(LogEntry { op: op.clone(), valid });
+        self.log.push(LogEntry { op: op.clone(), valid, time_ms });
+        valid
+    }
+
+    fn extend_lease(&mut self, key: &str) {
+        let expiry = self.time_ms.saturating_add(self.lease_ms);
+        if let Some(old) = self.expiries.insert(key.to_string(), expiry) {
+            self.by_expiry.remove(&(old, key.to_string()));
+        }
+        self.by_expiry.insert((expiry, key.to_string()));
+    }
+
+    fn release(&mut self, key: &str) {
+        self.locks.remove(key);
+        if let Some(expiry) = self.expiries.remove(key) {
+            self.by_expiry.remove(&(expiry, key.to_string()));
+        }
+    }
+
+    fn expire(&mut self) {
+        while let Some((expiry, key)) = self.by_expiry.first().cloned() {
+            if expiry > self.time_ms {
+                break;
+            }
+            debug!(%key, owner = ?self.locks.get(&key), "lease expired");
+            self.release(&key);
+        }
     }
 
     pub fn log(&self) -> &Vec<LogEntry> {
         &self.log
     }
 
+    /// The locks as of the last operation. Those whose lease has ended since expire with the
+    /// next one.
     pub fn locks(&self) -> &HashMap<String, NodeID> {
         &self.locks
     }
 }
+
+impl Default for Locker {
+    fn default() -> Locker {
+        Locker::new()
+    }
+}
+
+/// Digests of entries `start..end` of the log of a replica, or of as many of them as there are,
+/// and of its locks, to compare replicas without sending them.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Digest {
+    /// Number of entries in the log, after which the locks are what they are.
+    pub entries: usize,
+    pub start: usize,
+    pub end: usize,
+    pub log: u64,
+    pub locks: u64,
+}
+
+impl Digest {
+    pub fn new(log: &[LogEntry], locks: &HashMap<String, NodeID>, start: usize, end: usize) -> Result<Digest> {
+        let end_entry = end.min(log.len());
+        let range = start.min(end_entry)..end_entry;
+        Ok(Digest { entries: log.len(), start, end, log: hash(&log[range])?, locks: hash_locks(locks)? })
+    }
+}
+
+/// FNV-1a hash of the YAML of `items`. Unlike `std::hash` it is the same on every build, so
+/// replicas that run different versions can compare it.
+pub fn hash<T: Serialize, I: IntoIterator<Item = T>>(items: I) -> Result<u64> {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for item in items {
+        for byte in serde_yaml::to_vec(&item)? {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    Ok(hash)
+}
+
+/// Hash of `locks` sorted by key.
+pub fn hash_locks(locks: &HashMap<String, NodeID>) -> Result<u64> {
+    let mut locks: Vec<_> = locks.iter().collect();
+    locks.sort();
+    hash(locks)
+}
@@ -0,0 +1,74 @@
+//! Structured logging for the binaries, as text or JSON lines, with a level that can be changed
+//! while the program runs.
+//!
+//! Messages handled by a `PaxosInstance` are logged in a `receive` span with the instance,
+//! kind of message, proposal and peer. Client requests get a `request` ID in the server's logs.
+
+use crate::errors::*;
+
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Layer, Registry};
+
+use std::io;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<LogFormat> {
+        match format {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("unknown log format '{}', expected text or json", format),
+        }
+    }
+}
+
+/// Changes which events are logged, e.g. `debug` or `info,paxos550::paxos=trace`.
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogLevel {
+    pub fn set(&self, filter: &str) -> Result<()> {
+        let filter = parse_filter(filter)?;
+        self.handle.reload(filter).map_err(|e| Error::from(e.to_string()))
+    }
+
+    pub fn get(&self) -> Result<String> {
+        self.handle.with_current(|filter| filter.to_string()).map_err(|e| Error::from(e.to_string()))
+    }
+}
+
+fn parse_filter(filter: &str) -> Result<EnvFilter> {
+    EnvFilter::try_new(filter).chain_err(|| format!("invalid log level '{}'", filter))
+}
+
+/// A subscriber that writes to `writer`, starting with the `filter` level.
+pub fn subscriber<W>(format: LogFormat, filter: &str, writer: W) -> Result<(impl Subscriber + Send + Sync, LogLevel)>
+    where W: for<'a> MakeWriter<'a> + Send + Sync + 'static
+{
+    let (filter, handle) = reload::Layer::new(parse_filter(filter)?);
+    let output = match format {
+        LogFormat::Text => fmt::layer().with_writer(writer).boxed(),
+        LogFormat::Json => fmt::layer().json().with_current_span(true).with_span_list(true).with_writer(writer).boxed(),
+    };
+    Ok((Registry::default().with(filter).with(output), LogLevel { handle }))
+}
+
+/// Logs to stderr for the rest of the program. `RUST_LOG` overrides `default_filter`.
+pub fn init(format: LogFormat, default_filter: &str) -> Result<LogLevel> {
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| default_filter.to_string());
+    let (subscriber, level) = subscriber(format, &filter, io::stderr)?;
+    tracing::subscriber::set_global_default(subscriber).chain_err(|| "logging is already set up")?;
+    Ok(level)
+}
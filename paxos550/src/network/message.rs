@@ -1,14 +1,49 @@
-use paxos;
-use locker;
+use crate::paxos;
+use crate::locker;
+use crate::server;
+use std::net::SocketAddr;
 use std::time::Duration;
 
+/// Most bytes of text a `Chunk` carries, so that a chunk fits in a small datagram once encoded.
+pub const CHUNK_SIZE: usize = 4096;
+/// Most bytes of YAML text a reply sent in `Chunk`s can have. Larger pages are not sent.
+pub const MAX_REPLY_SIZE: usize = 16 << 20;
+/// Most chunks a reply can be split into.
+pub const MAX_CHUNKS: usize = MAX_REPLY_SIZE / CHUNK_SIZE;
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 pub enum MessagePayload<T> {
     PaxosMessage(paxos::PaxosMessage<T>),
-    LockerMessage(locker::Operation),
-    PrintLog,
-    PrintLocks,
+    /// Proposes an operation. The server replies with an `Applied` of the request ID given here,
+    /// once the operation is applied.
+    LockerMessage(u64, locker::Operation),
+    /// Answers a `LockerMessage` sent to a server that is not the leader with the ID and address
+    /// of the leader.
+    Redirect(paxos::NodeID, SocketAddr),
+    /// Asks for entries of the log, and `PrintLocks` for locks sorted by key. The server replies
+    /// with a `Page` split in `Chunk`s of the request ID given here, unless the page is larger
+    /// than `MAX_REPLY_SIZE` and has to be asked for with a `Query::limit`.
+    PrintLog(u64, Query),
+    PrintLocks(u64, Query),
+    /// Asks again for the chunks with these indexes of the reply to a request, which did not
+    /// arrive. The server keeps the last few replies it sent in chunks, for the address it sent
+    /// them to.
+    Resend(u64, Vec<usize>),
     PrintTotalInstances,
+    /// Asks for the `locker::Digest` of entries `start..end` of the log and of the locks.
+    Digest(usize, usize),
+    /// Asks for the `server::Status` of the server.
+    Status,
+    /// Sets the log level of the server to a filter like `debug`, or just asks for it if `None`.
+    /// The server replies with the level in effect or an error.
+    LogLevel(Option<String>),
+    /// Sets the faults the server injects into its traffic with peers, or just asks for them if
+    /// `None`. The server replies with the policy in effect or an error.
+    Faults(Option<server::FaultPolicy>),
+    /// Measures the round-trip time between servers for adaptive timeouts. `Pong` echoes the
+    /// sequence number of the `Ping`. Both carry the ID of their sender.
+    Ping(paxos::NodeID, u64),
+    Pong(paxos::NodeID, u64),
 }
 
 #[derive(Clone, Eq, PartialEq, Debug)]
@@ -23,3 +58,137 @@ pub struct MessageInfo<T> {
     pub target: MessageTarget,
     pub timeout: Option<Duration>,
 }
+
+/// Answers a `LockerMessage` with the ID of its request, and the entry its operation got in the log.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Applied {
+    pub request: u64,
+    pub entry: locker::LogEntry,
+}
+
+/// Which entries of the log, or which locks, a client asks for.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Query {
+    /// Number of matching entries to skip.
+    pub offset: usize,
+    /// Most entries to return, or all of them if `None`.
+    pub limit: Option<usize>,
+    /// Only the entries of this key.
+    pub key: Option<String>,
+}
+
+impl Query {
+    pub fn matches(&self, key: &str) -> bool {
+        self.key.as_ref().is_none_or(|k| k == key)
+    }
+
+    /// The page of `matching` the query asks for.
+    pub fn page<T, I: IntoIterator<Item = T>>(&self, matching: I) -> Page<T> {
+        let mut items = Vec::new();
+        let mut total = 0;
+        for item in matching {
+            if total >= self.offset && self.limit.is_none_or(|limit| items.len() < limit) {
+                items.push(item);
+            }
+            total += 1;
+        }
+        Page { offset: self.offset, total, items }
+    }
+}
+
+/// Entries `offset..offset + items.len()` of the `total` entries that match a `Query`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Page<T> {
+    pub offset: usize,
+    pub total: usize,
+    pub items: Vec<T>,
+}
+
+impl<T> Page<T> {
+    /// Offset of the next page, if there are entries left.
+    pub fn next_offset(&self) -> Option<usize> {
+        let end = self.offset + self.items.len();
+        if end < self.total { Some(end) } else { None }
+    }
+}
+
+/// Part `index` of `count` of the YAML text of a reply too large for one datagram.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct Chunk {
+    pub request: u64,
+    pub index: usize,
+    pub count: usize,
+    pub text: String,
+}
+
+impl Chunk {
+    /// Splits `text` into chunks of at most `CHUNK_SIZE` bytes.
+    pub fn split(request: u64, text: &str) -> Vec<Chunk> {
+        let mut parts = Vec::new();
+        let mut rest = text;
+        loop {
+            let mut end = rest.len().min(CHUNK_SIZE);
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let (part, tail) = rest.split_at(end);
+            parts.push(part);
+            rest = tail;
+            if rest.is_empty() {
+                break;
+            }
+        }
+        let count = parts.len();
+        parts.into_iter().enumerate()
+            .map(|(index, text)| Chunk { request, index, count, text: text.to_string() })
+            .collect()
+    }
+}
+
+/// Puts the chunks of one request back together, in whatever order they arrive.
+pub struct Reassembly {
+    request: u64,
+    parts: Vec<Option<String>>,
+    missing: usize,
+}
+
+impl Reassembly {
+    pub fn new(request: u64) -> Reassembly {
+        Reassembly { request, parts: Vec::new(), missing: 0 }
+    }
+
+    /// Adds a chunk and returns the whole text once every chunk arrived. Chunks of other
+    /// requests, duplicates, and chunks of more than `MAX_CHUNKS`, are ignored.
+    pub fn add(&mut self, chunk: Chunk) -> Option<String> {
+        if chunk.request != self.request || chunk.index >= chunk.count || chunk.count > MAX_CHUNKS {
+            return None;
+        }
+        if self.parts.is_empty() {
+            self.parts = vec![None; chunk.count];
+            self.missing = chunk.count;
+        }
+        if chunk.count != self.parts.len() || self.parts[chunk.index].is_some() {
+            return None;
+        }
+        self.parts[chunk.index] = Some(chunk.text);
+        self.missing -= 1;
+        if self.missing > 0 {
+            return None;
+        }
+        Some(self.parts.drain(..).map(Option::unwrap).collect())
+    }
+
+    pub fn request(&self) -> u64 {
+        self.request
+    }
+
+    /// Indexes of the chunks that did not arrive yet, once the first one did.
+    pub fn missing(&self) -> Vec<usize> {
+        self.parts.iter().enumerate().filter(|(_, part)| part.is_none()).map(|(index, _)| index).collect()
+    }
+
+    /// Number of chunks received so far, and expected in all.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.parts.len() - self.missing, self.parts.len())
+    }
+}
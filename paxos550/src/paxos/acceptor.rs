@@ -1,5 +1,15 @@
 use super::common::*;
 
+/// What an acceptor must not forget: the highest proposal it promised, and the highest one it
+/// accepted with its value. A node that lost it could accept a value other than the chosen one.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Debug)]
+pub struct AcceptorState<T> {
+    pub promised: ProposalID,
+    pub accepted: ProposalID,
+    pub value: Option<T>,
+}
+
+#[derive(Clone, Hash)]
 pub struct Acceptor<T> {
     _instance_id: InstanceID,
     acceptor_id: NodeID,
@@ -11,7 +21,7 @@ pub struct Acceptor<T> {
 
 impl<T: Clone + Eq> Acceptor<T> {
     pub fn new(instance_id: InstanceID, acceptor_id: NodeID) -> Acceptor<T> {
-        let highest_accepted_proposal_id = ProposalID::new(0, acceptor_id.clone());
+        let highest_accepted_proposal_id = ProposalID::initial(acceptor_id.clone());
         Acceptor {
             _instance_id: instance_id,
             acceptor_id,
@@ -38,7 +48,7 @@ impl<T: Clone + Eq> Acceptor<T> {
 
     pub fn receive_propose(&mut self, propose: &ProposeMessage<T>) -> Option<AcceptedMessage> {
         if propose.proposal_id >= self.highest_promised_proposal_id
-            && (!self.reached_consensus || self.value.as_ref().unwrap() == &propose.value) {
+            && (!self.reached_consensus || self.value.as_ref() == Some(&propose.value)) {
             self.highest_promised_proposal_id = propose.proposal_id.clone();
             self.highest_accepted_proposal_id = propose.proposal_id.clone();
             self.value = Some(propose.value.clone());
@@ -59,6 +69,21 @@ impl<T: Clone + Eq> Acceptor<T> {
         self.highest_accepted_proposal_id.clone()
     }
 
+    pub fn state(&self) -> AcceptorState<T> {
+        AcceptorState {
+            promised: self.highest_promised_proposal_id.clone(),
+            accepted: self.highest_accepted_proposal_id.clone(),
+            value: self.value.clone(),
+        }
+    }
+
+    /// Takes back the promises and the accepted value of an earlier run of the node.
+    pub fn restore(&mut self, state: AcceptorState<T>) {
+        self.highest_promised_proposal_id = state.promised;
+        self.highest_accepted_proposal_id = state.accepted;
+        self.value = state.value;
+    }
+
     pub fn set_reached_consensus(&mut self) {
         self.reached_consensus = true;
     }
@@ -1,4 +1,4 @@
-use rand;
+use rand::Rng;
 
 pub type NodeID = String;  // TODO: maybe consider &str?
 pub type InstanceID = usize;
@@ -69,6 +69,54 @@ pub enum PaxosInstanceMessage<T> {
 //    Consensus(ConsensusMessage<T>),
 }
 
+impl<T> PaxosInstanceMessage<T> {
+    /// The name of the message type, for logs and metrics.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            PaxosInstanceMessage::Prepare(_) => "prepare",
+            PaxosInstanceMessage::Promise(_) => "promise",
+            PaxosInstanceMessage::Propose(_) => "propose",
+            PaxosInstanceMessage::Accepted(_) => "accepted",
+            PaxosInstanceMessage::Learn(_) => "learn",
+            PaxosInstanceMessage::Value(_) => "value",
+        }
+    }
+
+    /// The node that sent the message.
+    pub fn sender(&self) -> &NodeID {
+        match *self {
+            PaxosInstanceMessage::Prepare(ref m) => &m.proposer_id,
+            PaxosInstanceMessage::Promise(ref m) => &m.acceptor_id,
+            PaxosInstanceMessage::Propose(ref m) => &m.proposer_id,
+            PaxosInstanceMessage::Accepted(ref m) => &m.acceptor_id,
+            PaxosInstanceMessage::Learn(ref m) => &m.learner_id,
+            PaxosInstanceMessage::Value(ref m) => &m.learner_id,
+        }
+    }
+
+    /// The value the message carries, if any.
+    pub fn value(&self) -> Option<&T> {
+        match *self {
+            PaxosInstanceMessage::Promise(ref m) => m.last_accepted_value.as_ref(),
+            PaxosInstanceMessage::Propose(ref m) => Some(&m.value),
+            PaxosInstanceMessage::Value(ref m) => Some(&m.chosen_value),
+            _ => None,
+        }
+    }
+
+    /// The proposal the message is about. A `Learn` is about none in particular.
+    pub fn proposal_id(&self) -> Option<&ProposalID> {
+        match *self {
+            PaxosInstanceMessage::Prepare(ref m) => Some(&m.proposal_id),
+            PaxosInstanceMessage::Promise(ref m) => Some(&m.proposal_id),
+            PaxosInstanceMessage::Propose(ref m) => Some(&m.proposal_id),
+            PaxosInstanceMessage::Accepted(ref m) => Some(&m.proposal_id),
+            PaxosInstanceMessage::Learn(_) => None,
+            PaxosInstanceMessage::Value(ref m) => Some(&m.chosen_proposal_id),
+        }
+    }
+}
+
 #[derive(Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Debug)]
 pub struct PaxosMessage<T> {
     pub instance_id: InstanceID,
@@ -76,8 +124,13 @@ pub struct PaxosMessage<T> {
 }
 
 impl ProposalID {
-    pub fn new(round: u64, proposer_id: NodeID) -> ProposalID {
-        ProposalID(round, rand::random(), proposer_id)
+    pub fn new<R: Rng + ?Sized>(round: u64, proposer_id: NodeID, rng: &mut R) -> ProposalID {
+        ProposalID(round, rng.gen(), proposer_id)
+    }
+
+    /// The round 0 ID, which is lower than any real proposal.
+    pub fn initial(node_id: NodeID) -> ProposalID {
+        ProposalID(0, 0, node_id)
     }
 
     pub fn round(&self) -> u64 {
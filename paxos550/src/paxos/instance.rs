@@ -1,40 +1,87 @@
-use super::{Proposer, Acceptor, Learner};
+use super::{Proposer, Acceptor, AcceptorState, Learner};
 use super::common::*;
-use errors::*;
-use network::message;
+use crate::errors::*;
+use crate::network::message;
+use crate::env::{self, Random};
 
-use rand;
 use rand::Rng;
 use std::collections::VecDeque;
 use std::time::Duration;
 use std::collections::HashSet;
-use std::hash::Hash;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::fmt::Debug;
 
+/// How long a proposer waits for the replies of each phase before it retries. Each phase backs off
+/// on its own: every retry of a phase waits up to twice as long as its last one, but never longer
+/// than `max_backoff`.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug)]
+pub struct Timeouts {
+    /// For a majority of `Promise`s.
+    pub prepare: Duration,
+    /// For a majority of `Accepted`s.
+    pub propose: Duration,
+    /// For a `Value`.
+    pub learn: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Timeouts {
+    pub fn uniform(timeout: Duration, max_backoff: Duration) -> Timeouts {
+        Timeouts { prepare: timeout, propose: timeout, learn: timeout, max_backoff }
+    }
+}
+
+/// The same timeout for every phase, with no limit on the back-off.
+impl From<Duration> for Timeouts {
+    fn from(timeout: Duration) -> Timeouts {
+        Timeouts::uniform(timeout, Duration::MAX)
+    }
+}
+
+#[derive(Clone)]
 pub struct PaxosInstance<T> {
     node_id: NodeID,
     instance_id: InstanceID,
-    timeout: Duration,
+    /// The timeouts of the phases, each backed off by its own retries.
+    timeouts: Timeouts,
     messages_to_send: VecDeque<message::MessageInfo<T>>,
 
     proposer: Proposer<T>,
     acceptor: Acceptor<T>,
+    /// Whether the acceptor answered a `Prepare` or a `Propose` since `take_acceptor_state`.
+    acceptor_changed: bool,
     learner: Learner<T>,
     waiting_reply: HashSet<PaxosInstanceMessage<T>>,
+    random: Random,
+    rounds: usize,
+    /// IDs of the client requests in the latest value this node saw, for the logs.
+    requests: Vec<u64>,
     value: Option<T>,  // TODO make the canonical copy only exists once (either in Instance, or the  three component)
 }
 
 impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
-    pub fn new(node_id: NodeID, instance_id: InstanceID, cluster_size: usize, timeout: Duration) -> PaxosInstance<T> {
+    pub fn new(node_id: NodeID, instance_id: InstanceID, cluster_size: usize,
+               timeouts: impl Into<Timeouts>) -> PaxosInstance<T> {
+        PaxosInstance::with_random(node_id, instance_id, cluster_size, timeouts, env::system_random())
+    }
+
+    /// Creates an instance that draws proposal IDs and back-off timeouts from `random`.
+    pub fn with_random(node_id: NodeID, instance_id: InstanceID, cluster_size: usize,
+                       timeouts: impl Into<Timeouts>, random: Random) -> PaxosInstance<T> {
         PaxosInstance {
             node_id: node_id.clone(),  // FIXME remove clone()
             instance_id,
-            timeout,
+            timeouts: timeouts.into(),
             messages_to_send: VecDeque::new(),
             proposer: Proposer::new(instance_id, node_id.clone(), cluster_size),
             acceptor: Acceptor::new(instance_id, node_id.clone()),
+            acceptor_changed: false,
             learner: Learner::new(instance_id, node_id.clone(), cluster_size),
             waiting_reply: HashSet::new(),
+            random,
+            rounds: 0,
+            requests: Vec::new(),
             value: None
         }
     }
@@ -53,22 +100,23 @@ impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
         self.messages_to_send.push_back(message::MessageInfo {
             payload: message::MessagePayload::PaxosMessage(PaxosMessage {
                 instance_id: self.instance_id,
-                message: message
+                message
             }),
             target,
             timeout
         });
     }
 
-    fn backoff_timeout(&self, timeout: Duration) -> Duration {
-        let old = timeout.as_secs() * 1000 + timeout.subsec_millis() as u64;
-        let backoff = rand::thread_rng().gen_range(0, old);
-        Duration::from_millis(old + backoff)
+    fn backoff_timeout(&mut self, timeout: Duration) -> Duration {
+        let old = timeout.as_millis() as u64;
+        let backoff = if old > 0 { self.random.gen_range(0, old) } else { 0 };
+        // a timeout configured above `max_backoff` does not shrink
+        Duration::from_millis(old + backoff).min(self.timeouts.max_backoff.max(timeout))
     }
 
     pub fn start_proposing(&mut self, value: T) {
         self.proposer.set_value(value);
-        let timeout = self.timeout;  // need NLL
+        let timeout = self.timeouts.prepare;
         self.do_prepare(timeout);
     }
 
@@ -76,6 +124,39 @@ impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
         self.value.as_ref()
     }
 
+    /// Records the IDs of the client requests in the value that the instance is handling, which
+    /// the spans of its messages carry.
+    pub fn set_requests(&mut self, requests: Vec<u64>) {
+        self.requests = requests;
+    }
+
+    /// The state of the acceptor if it promised or accepted anything since the last call, which
+    /// has to be saved before its answers are sent for the node to keep its word after a restart.
+    pub fn take_acceptor_state(&mut self) -> Option<AcceptorState<T>> {
+        if !self.acceptor_changed {
+            return None;
+        }
+        self.acceptor_changed = false;
+        Some(self.acceptor.state())
+    }
+
+    /// Takes back the state the acceptor had in an earlier run of the node.
+    pub fn restore_acceptor(&mut self, state: AcceptorState<T>) {
+        self.acceptor.restore(state);
+    }
+
+    /// Takes back the value the node learned in an earlier run.
+    pub fn restore_value(&mut self, value: T) {
+        self.learner.set_chosen_value(value.clone());
+        self.acceptor.set_reached_consensus();
+        self.value = Some(value);
+    }
+
+    /// Number of Prepare rounds this node started for the instance.
+    pub fn rounds(&self) -> usize {
+        self.rounds
+    }
+
 //    pub fn start_recovery(&mut self) {
 //        let msg = PaxosInstanceMessage::Recovery(RecoveryMessage {
 //            node_id: self.node_id.clone()
@@ -85,16 +166,22 @@ impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
 //    }
 
     fn do_prepare(&mut self, timeout: Duration) {
-        let msg = PaxosInstanceMessage::Prepare(self.proposer.prepare());
+        self.rounds += 1;
+        let msg = PaxosInstanceMessage::Prepare(self.proposer.prepare(&mut self.random));
         self.send_message(msg, message::MessageTarget::Broadcast, Some(timeout));
     }
 
     /// Returns `Some` if this is the first time the learner learns the value.
     pub fn receive_message(&mut self, message: &PaxosInstanceMessage<T>) -> Option<T> {  // FIXME should return Option<&T>
+        let _span = debug_span!("receive", instance = self.instance_id, kind = message.kind(),
+                                proposal = ?message.proposal_id(), peer = %message.sender(),
+                                requests = ?self.requests).entered();
+        trace!("handling {:?}", message);
         match *message {
             PaxosInstanceMessage::Prepare(ref prepare) => {
                 self.proposer.observe_proposal(&prepare.proposal_id);
                 if let Some(m) = self.acceptor.receive_prepare(prepare) {
+                    self.acceptor_changed = true;
                     let msg = PaxosInstanceMessage::Promise(m);
                     let target = message::MessageTarget::Node(prepare.proposer_id.clone());
                     self.send_message(msg, target, None);
@@ -110,13 +197,14 @@ impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
                     });
 
                     let msg = PaxosInstanceMessage::Propose(m);
-                    let timeout = Some(self.timeout);
+                    let timeout = Some(self.timeouts.propose);
                     self.send_message(msg, message::MessageTarget::Broadcast, timeout);
                 }
             },
             PaxosInstanceMessage::Propose(ref propose) => {
                 self.proposer.observe_proposal(&propose.proposal_id);
                 if let Some(m) = self.acceptor.receive_propose(propose) {
+                    self.acceptor_changed = true;
                     let msg = PaxosInstanceMessage::Accepted(m);
                     self.send_message(msg, message::MessageTarget::Broadcast, None);
                 }
@@ -131,16 +219,18 @@ impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
                         _ => true
                     });
 
-                    if let Some(v) = self.acceptor.value() {
-                        // if the acceptor accepted the proposal, directly set the value.
+                    if self.acceptor.highest_accepted_proposal_id() == accepted.proposal_id {
+                        // if the acceptor accepted the chosen proposal, directly set the value.
+                        let v = self.acceptor.value().expect("accepted proposal has a value");
                         self.learner.set_chosen_value(v.clone());
                         self.value = Some(v.clone());
                         self.acceptor.set_reached_consensus();
+                        debug!("chosen by a majority");
                         return Some(v.clone());
                     } else {
                         // otherwise, ask other nodes for the answer.
                         let msg = PaxosInstanceMessage::Learn(m);
-                        let timeout = Some(self.timeout);
+                        let timeout = Some(self.timeouts.learn);
                         self.send_message(msg, message::MessageTarget::Broadcast, timeout);
                     }
                 }
@@ -153,10 +243,7 @@ impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
             },
             PaxosInstanceMessage::Value(ref value) => {
                 // if got Value from any node, clear all the Learn timeout
-                self.waiting_reply.retain(|msg| match *msg {
-                    PaxosInstanceMessage::Learn(_) => false,
-                    _ => true
-                });
+                self.waiting_reply.retain(|msg| !matches!(*msg, PaxosInstanceMessage::Learn(_)));
 
                 self.value = Some(value.chosen_value.clone());
                 self.acceptor.set_reached_consensus();
@@ -185,17 +272,25 @@ impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
         None
     }
 
-    pub fn on_timeout(&mut self, message: PaxosInstanceMessage<T>, timeout: Duration) -> Result<()> {
+    /// Returns whether `message` was still waiting for a reply.
+    pub fn on_timeout(&mut self, message: PaxosInstanceMessage<T>, timeout: Duration) -> Result<bool> {
         if !self.waiting_reply.remove(&message) {
-            return Ok(());
+            return Ok(false);
         }
-        debug!("instance {} timeout {:?} {:?}", self.instance_id, timeout, message);
-        let new_timeout = self.backoff_timeout(timeout);
+        debug!(instance = self.instance_id, kind = message.kind(), proposal = ?message.proposal_id(), ?timeout,
+               "timed out waiting for replies");
         match message {
-            PaxosInstanceMessage::Prepare(..) |
+            PaxosInstanceMessage::Prepare(..) => {
+                self.timeouts.prepare = self.backoff_timeout(self.timeouts.prepare);
+                if self.value.is_none() {
+                    self.do_prepare(self.timeouts.prepare);
+                }
+            },
+            // the retry starts over with a Prepare, which waits as long as Prepares do
             PaxosInstanceMessage::Propose(..) => {
+                self.timeouts.propose = self.backoff_timeout(self.timeouts.propose);
                 if self.value.is_none() {
-                    self.do_prepare(new_timeout);
+                    self.do_prepare(self.timeouts.prepare);
                 }
             },
             PaxosInstanceMessage::Promise(..) |
@@ -206,7 +301,9 @@ impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
             },
             PaxosInstanceMessage::Learn(..) => {
                 // send the Learn message again
-                self.send_message(message, message::MessageTarget::Broadcast, Some(new_timeout));
+                self.timeouts.learn = self.backoff_timeout(self.timeouts.learn);
+                let timeout = Some(self.timeouts.learn);
+                self.send_message(message, message::MessageTarget::Broadcast, timeout);
             },
 //            PaxosInstanceMessage::Recovery(..) => {
 //                if self.value.is_none() {
@@ -215,6 +312,28 @@ impl<T: Clone + Hash + Eq + Debug> PaxosInstance<T> {
 //                }
 //            },
         }
-        Ok(())
+        Ok(true)
+    }
+}
+
+/// Hashes the protocol state of the instance, leaving out the random source, the round count, the
+/// request IDs, the messages that have not been collected yet and whether the acceptor changed.
+impl<T: Hash> Hash for PaxosInstance<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.node_id.hash(state);
+        self.instance_id.hash(state);
+        self.timeouts.hash(state);
+        self.proposer.hash(state);
+        self.acceptor.hash(state);
+        self.learner.hash(state);
+        // a `HashSet` has no order, so hash its elements separately and sort them
+        let mut waiting_reply: Vec<u64> = self.waiting_reply.iter().map(|msg| {
+            let mut hasher = DefaultHasher::new();
+            msg.hash(&mut hasher);
+            hasher.finish()
+        }).collect();
+        waiting_reply.sort();
+        waiting_reply.hash(state);
+        self.value.hash(state);
     }
 }
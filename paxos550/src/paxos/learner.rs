@@ -1,26 +1,27 @@
 use super::common::*;
-use errors::*;
-use std::collections::HashMap;
+use crate::errors::*;
+use std::collections::BTreeMap;
 
+#[derive(Clone, Hash)]
 pub struct Learner<T> {
     _instance_id: InstanceID,
     learner_id: NodeID,
     majority_size: usize,
-    proposal_accept_count: HashMap<ProposalID, usize>,
-    acceptor_highest_proposal_id: HashMap<NodeID, ProposalID>,
+    proposal_accept_count: BTreeMap<ProposalID, usize>,
+    acceptor_highest_proposal_id: BTreeMap<NodeID, ProposalID>,
     chosen_proposal_id: ProposalID,
     chosen_value: Option<T>,
 }
 
 impl<T: Clone> Learner<T> {
     pub fn new(instance_id: InstanceID, learner_id: NodeID, cluster_size: usize) -> Learner<T> {
-        let chosen_proposal_id = ProposalID::new(0, learner_id.clone());
+        let chosen_proposal_id = ProposalID::initial(learner_id.clone());
         Learner {
             _instance_id: instance_id,
             learner_id,
-            majority_size: (cluster_size + 1) / 2,
-            proposal_accept_count: HashMap::new(),
-            acceptor_highest_proposal_id: HashMap::new(),
+            majority_size: cluster_size / 2 + 1,
+            proposal_accept_count: BTreeMap::new(),
+            acceptor_highest_proposal_id: BTreeMap::new(),
             chosen_proposal_id,
             chosen_value: None
         }
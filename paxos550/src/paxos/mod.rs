@@ -6,6 +6,6 @@ mod instance;
 
 pub use self::common::*;
 pub use self::proposer::Proposer;
-pub use self::acceptor::Acceptor;
+pub use self::acceptor::{Acceptor, AcceptorState};
 pub use self::learner::Learner;
-pub use self::instance::PaxosInstance;
+pub use self::instance::{PaxosInstance, Timeouts};
@@ -1,26 +1,30 @@
 use super::common::*;
-use std::collections::HashSet;
+use rand::Rng;
+use std::collections::BTreeSet;
 
+#[derive(Clone, Hash)]
 pub struct Proposer<T> {
     _instance_id: InstanceID,
     proposer_id: NodeID,
     majority_size: usize,
     proposal_id: ProposalID,
     highest_proposal_id: ProposalID,
-    received_promises: HashSet<NodeID>,
+    highest_accepted_proposal_id: ProposalID,
+    received_promises: BTreeSet<NodeID>,
     value: Option<T>,
 }
 
 impl<T: Clone> Proposer<T> {
     pub fn new(instance_id: InstanceID, proposer_id: NodeID, cluster_size: usize) -> Proposer<T> {
-        let highest_proposal_id = ProposalID::new(0, proposer_id.clone());
+        let highest_proposal_id = ProposalID::initial(proposer_id.clone());
         Proposer {
             _instance_id: instance_id,
             proposer_id,
-            majority_size: (cluster_size + 1) / 2,
+            majority_size: cluster_size / 2 + 1,
             proposal_id: highest_proposal_id.clone(),
+            highest_accepted_proposal_id: highest_proposal_id.clone(),
             highest_proposal_id,
-            received_promises: HashSet::new(),
+            received_promises: BTreeSet::new(),
             value: None
         }
     }
@@ -31,10 +35,11 @@ impl<T: Clone> Proposer<T> {
         }
     }
 
-    pub fn prepare(&mut self) -> PrepareMessage {
+    pub fn prepare<R: Rng + ?Sized>(&mut self, rng: &mut R) -> PrepareMessage {
         self.proposal_id = ProposalID::new(self.highest_proposal_id.round() + 1,
-                                           self.proposer_id.clone());
+                                           self.proposer_id.clone(), rng);
         self.highest_proposal_id = self.proposal_id.clone();
+        self.highest_accepted_proposal_id = ProposalID::initial(self.proposer_id.clone());
         self.received_promises.clear();
         PrepareMessage {
             proposer_id: self.proposer_id.clone(),
@@ -46,11 +51,11 @@ impl<T: Clone> Proposer<T> {
         self.observe_proposal(&promise.proposal_id);
         if self.proposal_id == promise.proposal_id && !self.received_promises.contains(&promise.acceptor_id) {
             self.received_promises.insert(promise.acceptor_id.clone());
-            if promise.last_accepted_proposal_id > self.highest_proposal_id {
-                self.highest_proposal_id = promise.last_accepted_proposal_id.clone();
-                if promise.last_accepted_value.is_some() {
-                    self.value = promise.last_accepted_value.clone();
-                }
+            // adopt the value of the highest proposal accepted by any acceptor in the majority
+            if promise.last_accepted_value.is_some()
+                && promise.last_accepted_proposal_id > self.highest_accepted_proposal_id {
+                self.highest_accepted_proposal_id = promise.last_accepted_proposal_id.clone();
+                self.value = promise.last_accepted_value.clone();
             }
             if self.received_promises.len() == self.majority_size {
                 return Some(ProposeMessage {
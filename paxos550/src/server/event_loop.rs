@@ -0,0 +1,878 @@
+//! The event loop of a single node.
+//!
+//! `run` splits a node into tasks that talk over channels: one receives and decodes datagrams, one
+//! sends them, and one serves metrics. The `Server` itself only reacts to events and to its timers,
+//! which all wait on its `Clock`, and never awaits otherwise, so it handles one event at a time.
+
+use crate::paxos::*;
+use crate::locker::{Digest, LogEntry, Operation};
+use crate::errors::*;
+use crate::network::message::*;
+use crate::env::{self, Clock, Random, Sleep};
+use crate::server::{Batch, Command, FaultPolicy, PeerStatus, Request, Saved, ServerBuilder, StateMachine, Status, Storage};
+use crate::server::metrics::{self, Metrics};
+use crate::server::rtt::RttEstimator;
+use crate::logging::LogLevel;
+
+use rand::Rng;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{Instrument, Span};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::collections::VecDeque;
+use std::future;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::time::{Instant, UNIX_EPOCH};
+
+// IP fragmentation takes care of the datagrams larger than the MTU, such as big batches
+const MAX_UDP_SIZE: usize = 65535 - 20 - 8;
+
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+// peers not heard from for this long are not considered for leader
+const LEADER_TIMEOUT: Duration = Duration::from_secs(3);
+// below this, the time to encode and handle a batch matters more than the network
+const MIN_ADAPTIVE_TIMEOUT: Duration = Duration::from_millis(20);
+// a paused server drops what arrives beyond this, as a full socket buffer would
+const MAX_PAUSED_EVENTS: usize = 4096;
+// the chunks of a reply go out this many at a time, well within the receive buffer of a client
+const CHUNK_BURST: usize = 8;
+const CHUNK_INTERVAL: Duration = Duration::from_millis(10);
+// replies in chunks kept to resend the chunks a client missed
+const MAX_SENT_REPLIES: usize = 4;
+
+enum Event {
+    Message(MessagePayload<Batch>, SocketAddr),
+    Malformed,
+    Error(Error),
+    /// A `Command::Propose` that came while the server was paused.
+    Propose(Operation),
+}
+
+type Packet = (Vec<u8>, SocketAddr);
+
+/// The encoded chunks of a reply, kept for `MessagePayload::Resend`.
+struct SentReply {
+    addr: SocketAddr,
+    request: u64,
+    chunks: Vec<Vec<u8>>,
+}
+
+/// A client to reply to once its request is applied.
+struct WaitingClient {
+    addr: SocketAddr,
+    op: Operation,
+    asked_at: Instant,
+    span: Span,
+}
+
+/// What to do when a timer of the server fires.
+enum Timer {
+    /// A Paxos message got no answer within the duration.
+    Timeout(PaxosMessage<Batch>, Duration),
+    /// A datagram the faults hold back, or a chunk of a reply that was paced, is due.
+    Packet(Packet),
+}
+
+pub struct Server {
+    node_id: NodeID,
+    peers: HashMap<String, SocketAddr>,
+    /// The timeouts of the instances proposed from now on.
+    timeouts: Timeouts,
+    /// The configured timeouts, whose proportions the adaptive timeouts keep.
+    configured_timeouts: Timeouts,
+    adaptive_timeouts: bool,
+    /// Sends the operations of clients to the leader instead of proposing them.
+    redirect_clients: bool,
+    rtt: RttEstimator,
+    /// The sequence number of the last `Ping` and when it was sent. Late `Pong`s are ignored.
+    last_ping: (u64, Instant),
+
+    clock: Box<dyn Clock>,
+    random: Random,
+    messages_to_send: VecDeque<MessageInfo<Batch>>,
+    packets_to_send: VecDeque<Packet>,
+    /// Datagrams the faults held back that are now due, sent without faults.
+    due_packets: Vec<Packet>,
+    /// The pending timers by deadline, and by a sequence number among those with the same deadline.
+    timers: BTreeMap<(Instant, u64), Timer>,
+    next_timer: u64,
+    next_ping: Instant,
+    paxos: Vec<PaxosInstance<Batch>>,
+    batch_size: usize,
+    batch_delay: Duration,
+    /// Operations waiting to be proposed together, and when they have to go out.
+    pending_batch: Batch,
+    batch_deadline: Option<Instant>,
+    /// At most `window` instances proposed by this node are undecided at a time. The other
+    /// batches wait in `queued_batches`.
+    window: usize,
+    /// The batch this node proposed for each of its undecided instances.
+    in_flight: BTreeMap<InstanceID, Batch>,
+    /// Instances below `highest_decided` that this node proposes an empty batch for, to learn
+    /// their value. At most `window` at a time.
+    filling: BTreeSet<InstanceID>,
+    queued_batches: VecDeque<Batch>,
+    peak_in_flight: usize,
+    /// Clients to reply to once their operation is applied, by the ID of their request.
+    waiting_clients: HashMap<u64, Vec<WaitingClient>>,
+    state_machine: Box<dyn StateMachine>,
+    storage: Box<dyn Storage>,
+    /// When this node first heard of each instance it has not learned the value of yet.
+    undecided: BTreeMap<InstanceID, Instant>,
+    highest_decided: InstanceID,
+    /// When a datagram last came from each peer.
+    last_heard: HashMap<NodeID, Instant>,
+    next_log_to_apply: usize,
+    applied_operations: usize,
+    commands: UnboundedReceiver<Command>,
+    status: Arc<Mutex<Status>>,
+    metrics: Metrics,
+    metrics_listener: Option<std::net::TcpListener>,
+    log_level: Option<LogLevel>,
+    /// Whether `log_level` can be changed with `MessagePayload::LogLevel`.
+    log_level_changes: bool,
+    /// To label the metrics of the datagrams by peer.
+    peer_names: HashMap<SocketAddr, NodeID>,
+    /// Whether `faults` can be changed with `MessagePayload::Faults`.
+    fault_injection: bool,
+    /// Faults to inject into the traffic with peers.
+    faults: FaultPolicy,
+    /// The events that arrived while the server was paused, handled once it resumes. At most
+    /// `MAX_PAUSED_EVENTS`.
+    paused_events: VecDeque<Event>,
+    /// The last `MAX_SENT_REPLIES` replies sent in chunks, oldest first.
+    sent_replies: VecDeque<SentReply>,
+}
+
+impl Server {
+    pub fn new(builder: ServerBuilder, local_addr: SocketAddr, metrics_listener: Option<std::net::TcpListener>,
+               commands: UnboundedReceiver<Command>, status: Arc<Mutex<Status>>) -> Server {
+        let ServerBuilder {
+            node_id, mut peers, timeouts, adaptive_timeouts, redirect_clients, batch_size, batch_delay, window, clock,
+            mut random, state_machine, storage, log_level, log_level_changes, fault_injection, ..
+        } = builder;
+        peers.insert(node_id.clone(), local_addr);
+        let peer_names = peers.iter().map(|(name, addr)| (*addr, name.clone())).collect();
+        let empty_instance = PaxosInstance::with_random(
+            node_id.clone(), 0, peers.len(), Duration::default(), env::seeded_random(random.gen()));
+        Server {
+            node_id,
+            peers,
+            timeouts,
+            configured_timeouts: timeouts,
+            adaptive_timeouts,
+            redirect_clients,
+            rtt: RttEstimator::new(),
+            last_ping: (0, clock.now()),
+            next_ping: clock.now(),
+            clock,
+            random,
+            messages_to_send: VecDeque::new(),
+            packets_to_send: VecDeque::new(),
+            due_packets: Vec::new(),
+            timers: BTreeMap::new(),
+            next_timer: 0,
+            paxos: vec![empty_instance],
+            batch_size,
+            batch_delay,
+            pending_batch: Vec::new(),
+            batch_deadline: None,
+            window,
+            in_flight: BTreeMap::new(),
+            filling: BTreeSet::new(),
+            queued_batches: VecDeque::new(),
+            peak_in_flight: 0,
+            waiting_clients: HashMap::new(),
+            state_machine,
+            storage,
+            undecided: BTreeMap::new(),
+            highest_decided: 0,
+            last_heard: HashMap::new(),
+            next_log_to_apply: 1,
+            applied_operations: 0,
+            commands,
+            status,
+            metrics: Metrics::new(),
+            metrics_listener,
+            log_level,
+            log_level_changes,
+            peer_names,
+            fault_injection,
+            faults: FaultPolicy::default(),
+            paused_events: VecDeque::new(),
+            sent_replies: VecDeque::new(),
+        }
+    }
+
+    /// Takes back what the storage saved in an earlier run: the promises and accepted values of
+    /// the acceptors, and the decided log, which is applied again without being saved twice.
+    pub fn recover(&mut self) -> Result<()> {
+        let Saved { acceptors, log } = self.storage.load()?;
+        let last = match acceptors.keys().chain(log.keys()).max() {
+            Some(&last) => last,
+            None => return Ok(()),
+        };
+        for instance_id in self.paxos.len() ..= last {
+            let instance = self.new_instance(instance_id);
+            self.paxos.push(instance);
+        }
+        for (instance_id, state) in acceptors {
+            self.paxos[instance_id].restore_acceptor(state);
+        }
+        for (instance_id, batch) in log {
+            self.paxos[instance_id].restore_value(batch);
+            self.undecided.remove(&instance_id);
+            self.highest_decided = self.highest_decided.max(instance_id);
+        }
+        while self.next_log_to_apply <= last && self.paxos[self.next_log_to_apply].value().is_some() {
+            self.apply_batch(self.next_log_to_apply)?;
+            self.next_log_to_apply += 1;
+        }
+        info!(instances = last, applied = self.next_log_to_apply - 1, "recovered from storage");
+        Ok(())
+    }
+
+    /// Serves on `socket` until a `Command::Shutdown`, the handle going away, or an error.
+    pub async fn run(mut self, socket: std::net::UdpSocket) -> Result<()> {
+        let socket = Arc::new(UdpSocket::from_std(socket)?);
+        let (events, mut event_receiver) = mpsc::unbounded_channel();
+        let (packets, packet_receiver) = mpsc::unbounded_channel();
+        tokio::spawn(receive_packets(socket.clone(), events.clone()).in_current_span());
+        tokio::spawn(send_packets(socket, packet_receiver, events).in_current_span());
+        // without a listener the sender is dropped, which disables the branch below
+        let (scrapes, mut scrape_receiver) = mpsc::unbounded_channel::<oneshot::Sender<String>>();
+        if let Some(listener) = self.metrics_listener.take() {
+            tokio::spawn(metrics::serve(TcpListener::from_std(listener)?, scrapes).in_current_span());
+        }
+        loop {
+            let wake: Sleep = match self.next_deadline() {
+                Some(deadline) => self.clock.sleep_until(deadline),
+                None => Box::pin(future::pending()),
+            };
+            tokio::select! {
+                // requests from the `ServerHandle`. the server stops when the handle goes away.
+                command = self.commands.recv() => match command {
+                    Some(Command::Propose(op)) if self.faults.paused => self.hold(Event::Propose(op)),
+                    Some(Command::Propose(op)) => self.propose(0, op, None),
+                    Some(Command::Status(reply)) => {
+                        let _ = reply.send(self.status());
+                    },
+                    Some(Command::Shutdown) | None => {
+                        info!("shutting down");
+                        self.status();
+                        return Ok(());
+                    },
+                },
+                Some(event) = event_receiver.recv() => {
+                    // only a new fault policy gets through to a paused server
+                    let resumes = matches!(event, Event::Message(MessagePayload::Faults(_), _) | Event::Error(_));
+                    if self.faults.paused && !resumes {
+                        self.hold(event);
+                    } else {
+                        self.handle_event(event)?;
+                        while !self.faults.paused {
+                            match self.paused_events.pop_front() {
+                                Some(event) => self.handle_event(event)?,
+                                None => break,
+                            }
+                        }
+                    }
+                },
+                _ = wake => self.fire_timers()?,
+                // scrapes of a paused server wait, as they would for a stopped process
+                Some(reply) = scrape_receiver.recv(), if !self.faults.paused => {
+                    let _ = reply.send(self.render_metrics());
+                },
+            }
+
+            self.send_messages()?;
+            while let Some(packet) = self.packets_to_send.pop_front() {
+                let name = self.peer_name(packet.1);
+                let delays = if self.is_peer(&name) {
+                    self.faults.delays_to(&name, &mut self.random)
+                } else {
+                    vec![Duration::ZERO]
+                };
+                for delay in delays {
+                    *self.metrics.messages_sent.entry(name.clone()).or_insert(0) += 1;
+                    if delay.is_zero() {
+                        packets.send(packet.clone()).map_err(|_| Error::from("the sending task stopped"))?;
+                    } else {
+                        let deadline = self.clock.now() + delay;
+                        self.start_timer(deadline, Timer::Packet(packet.clone()));
+                    }
+                }
+            }
+            for packet in self.due_packets.drain(..) {
+                packets.send(packet).map_err(|_| Error::from("the sending task stopped"))?;
+            }
+        }
+    }
+
+    fn peer_name(&self, addr: SocketAddr) -> NodeID {
+        self.peer_names.get(&addr).cloned().unwrap_or_else(|| "client".to_string())
+    }
+
+    /// Whether `name` is another server, whose traffic faults apply to.
+    fn is_peer(&self, name: &str) -> bool {
+        name != self.node_id && self.peers.contains_key(name)
+    }
+
+    fn handle_event(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Message(_, addr) if self.is_peer(&self.peer_name(addr)) && self.faults.drops_from(&self.peer_name(addr)) => {
+                trace!(%addr, "dropped a message from a disconnected peer");
+            },
+            Event::Message(message, addr) => {
+                let name = self.peer_name(addr);
+                if self.peers.contains_key(&name) {
+                    self.last_heard.insert(name.clone(), self.clock.now());
+                }
+                *self.metrics.messages_received.entry(name).or_insert(0) += 1;
+                self.receive_message(message, addr)?
+            },
+            Event::Malformed => self.metrics.decode_failures += 1,
+            Event::Error(e) => return Err(e),
+            Event::Propose(op) => self.propose(0, op, None),
+        }
+        Ok(())
+    }
+
+    /// Keeps `event` of a paused server until it resumes, or drops it if too many are kept.
+    fn hold(&mut self, event: Event) {
+        if self.paused_events.len() < MAX_PAUSED_EVENTS {
+            self.paused_events.push_back(event);
+        } else {
+            self.metrics.paused_drops += 1;
+            trace!("dropped an event that came while the server was paused");
+        }
+    }
+
+    fn new_instance(&mut self, instance_id: InstanceID) -> PaxosInstance<Batch> {
+        self.undecided.insert(instance_id, self.clock.now());
+        let random = env::seeded_random(self.random.gen());
+        PaxosInstance::with_random(self.node_id.clone(), instance_id, self.peers.len(), self.timeouts, random)
+    }
+
+    fn setup_timeout_trigger(&mut self, now: Instant, message: MessageInfo<Batch>) {
+        if let Some(timeout) = message.timeout {
+            if let MessagePayload::PaxosMessage(msg) = message.payload {
+                self.start_timer(now + timeout, Timer::Timeout(msg, timeout));
+            }
+        }
+    }
+
+    fn start_timer(&mut self, deadline: Instant, timer: Timer) {
+        self.next_timer += 1;
+        self.timers.insert((deadline, self.next_timer), timer);
+    }
+
+    fn pings(&self) -> bool {
+        self.adaptive_timeouts || self.redirect_clients
+    }
+
+    /// When the next timer is due. A paused server waits for nothing.
+    fn next_deadline(&self) -> Option<Instant> {
+        if self.faults.paused {
+            return None;
+        }
+        let ping = if self.pings() { Some(self.next_ping) } else { None };
+        let timer = self.timers.keys().next().map(|&(deadline, _)| deadline);
+        let gap = self.next_gap().map(|(_, deadline)| deadline);
+        [self.batch_deadline, ping, timer, gap].into_iter().flatten().min()
+    }
+
+    /// Handles everything that is due by the clock.
+    fn fire_timers(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        if self.batch_deadline.is_some_and(|deadline| deadline <= now) {
+            self.propose_batch();
+        }
+        // the leader is the server peers hear from
+        if self.pings() && self.next_ping <= now {
+            self.next_ping = now + PING_INTERVAL;
+            self.ping()?;
+        }
+        while let Some(entry) = self.timers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            match entry.remove() {
+                Timer::Timeout(msg, timeout) => self.on_timeout(msg, timeout)?,
+                Timer::Packet(packet) => self.due_packets.push(packet),
+            }
+        }
+        while let Some((instance_id, deadline)) = self.next_gap() {
+            if deadline > now {
+                break;
+            }
+            self.fill_gap(instance_id);
+        }
+        Ok(())
+    }
+
+    /// The instance below `highest_decided` that this node has waited the longest for, and when a
+    /// learn timeout since it heard of it runs out. A node misses instances while it is down or
+    /// when their messages get lost, and cannot apply the log past them.
+    fn next_gap(&self) -> Option<(InstanceID, Instant)> {
+        if self.filling.len() >= self.window {
+            return None;
+        }
+        self.undecided.range(..self.highest_decided)
+            .filter(|&(id, _)| !self.in_flight.contains_key(id) && !self.filling.contains(id))
+            .map(|(&id, &since)| (id, since + self.timeouts.learn))
+            .min_by_key(|&(_, deadline)| deadline)
+    }
+
+    /// Proposes an empty batch for a gap. Paxos makes the proposer adopt the value a majority
+    /// accepted, so the instance gets the value that was chosen, if any, and nothing otherwise.
+    fn fill_gap(&mut self, instance_id: InstanceID) {
+        debug!(instance = instance_id, "proposing an empty batch to fill a gap in the log");
+        let instance = &mut self.paxos[instance_id];
+        instance.start_proposing(Batch::new());
+        instance.collect_messages_to_send(&mut self.messages_to_send);
+        self.filling.insert(instance_id);
+    }
+
+    /// Adds `op` to the next batch, as request `id`. `client` gets a reply once it is applied.
+    fn propose(&mut self, id: u64, op: Operation, client: Option<SocketAddr>) {
+        let time_ms = self.clock.system_time().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        let request = Request { id, op, time_ms };
+        if let Some(addr) = client {
+            let now = self.clock.now();
+            let waiting = self.waiting_clients.entry(request.id).or_default();
+            // the retry of a request that is still waiting is in a batch already
+            if waiting.iter().any(|client| client.addr == addr && client.op == request.op) {
+                debug!(id = request.id, client = %addr, "already proposed");
+                return;
+            }
+            let span = info_span!("request", id = request.id, client = %addr);
+            span.in_scope(|| debug!(op = ?request.op, "received"));
+            waiting.push(WaitingClient { addr, op: request.op.clone(), asked_at: now, span });
+        }
+        self.pending_batch.push(request);
+        if self.pending_batch.len() >= self.batch_size {
+            self.propose_batch();
+        } else if self.batch_deadline.is_none() {
+            self.batch_deadline = Some(self.clock.now() + self.batch_delay);
+        }
+    }
+
+    fn propose_batch(&mut self) {
+        self.batch_deadline = None;
+        if self.pending_batch.is_empty() {
+            return;
+        }
+        let batch = mem::take(&mut self.pending_batch);
+        self.queued_batches.push_back(batch);
+        self.start_queued_batches();
+    }
+
+    /// Starts an instance for each queued batch that fits in the window.
+    fn start_queued_batches(&mut self) {
+        while self.in_flight.len() < self.window {
+            let batch = match self.queued_batches.pop_front() {
+                Some(batch) => batch,
+                None => break,
+            };
+            let instance_id = self.paxos.len();
+            debug!(instance = instance_id, operations = batch.len(), "proposing");
+            let mut instance = self.new_instance(instance_id);
+            instance.set_requests(batch.iter().map(|request| request.id).collect());
+            instance.start_proposing(batch.clone());
+            instance.collect_messages_to_send(&mut self.messages_to_send);
+            self.paxos.push(instance);
+            self.in_flight.insert(instance_id, batch);
+            self.metrics.instances_started += 1;
+            self.peak_in_flight = self.peak_in_flight.max(self.in_flight.len());
+        }
+    }
+
+    /// Saves a decided batch and applies it.
+    fn apply(&mut self, instance_id: InstanceID) -> Result<()> {
+        let batch = self.paxos[instance_id].value().expect("only decided instances are applied");
+        self.storage.save(instance_id, batch)?;
+        self.apply_batch(instance_id)
+    }
+
+    /// Applies a decided batch and replies to the clients waiting for its operations.
+    fn apply_batch(&mut self, instance_id: InstanceID) -> Result<()> {
+        let batch = self.paxos[instance_id].value().expect("only decided instances are applied").clone();
+        info!(instance = instance_id, ?batch, "applying");
+        for request in batch {
+            let valid = self.state_machine.apply(&request);
+            let Request { id, op, time_ms } = request;
+            self.applied_operations += 1;
+            let waiting = self.waiting_clients.get_mut(&id)
+                .and_then(|clients| clients.iter().position(|client| client.op == op).map(|i| clients.remove(i)));
+            if let Some(client) = waiting {
+                let latency = self.clock.now() - client.asked_at;
+                client.span.in_scope(|| info!(instance = instance_id, valid, ?latency, "applied"));
+                if valid && matches!(op, Operation::Lock(..)) {
+                    self.metrics.lock_acquisition.observe(latency.as_secs_f64());
+                }
+                let data = serde_yaml::to_vec(&Applied { request: id, entry: LogEntry { op, valid, time_ms } })?;
+                self.packets_to_send.push_back((data, client.addr));
+            }
+        }
+        self.waiting_clients.retain(|_, c| !c.is_empty());
+        Ok(())
+    }
+
+    /// Builds the status as of now, and keeps it for the handle to show once the server stops.
+    fn status(&self) -> Status {
+        let now = self.clock.now();
+        let mut status = self.status.lock().unwrap();
+        status.peers = self.peers.iter().filter(|&(name, _)| *name != self.node_id).map(|(name, addr)| {
+            let last_heard = self.last_heard.get(name).map(|&heard| now - heard);
+            (name.clone(), PeerStatus { address: *addr, last_heard })
+        }).collect();
+        status.total_instances = self.paxos.len() - 1;
+        status.applied = self.next_log_to_apply - 1;
+        status.next_log_to_apply = self.next_log_to_apply;
+        status.highest_decided = self.highest_decided;
+        status.undecided = self.undecided.iter().map(|(&id, &since)| (id, now - since)).collect();
+        status.storage_bytes = self.storage.bytes();
+        status.applied_operations = self.applied_operations;
+        status.in_flight = self.in_flight.len();
+        status.peak_in_flight = self.peak_in_flight;
+        status.queued_batches = self.queued_batches.len();
+        status.prepare_timeout = self.timeouts.prepare;
+        status.propose_timeout = self.timeouts.propose;
+        status.learn_timeout = self.timeouts.learn;
+        status.round_trip_times = self.rtt.round_trip_times();
+        status.leader = if self.redirect_clients { Some(self.leader().clone()) } else { None };
+        status.clone()
+    }
+
+    /// The server with the lowest ID among this one and the peers heard from lately.
+    fn leader(&self) -> &NodeID {
+        let now = self.clock.now();
+        self.peers.keys()
+            .filter(|&name| *name == self.node_id || self.last_heard.get(name).is_some_and(|&heard| now - heard < LEADER_TIMEOUT))
+            .min()
+            .expect("a server is always a candidate")
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        let sequence = self.last_ping.0 + 1;
+        self.last_ping = (sequence, self.clock.now());
+        let data = serde_yaml::to_vec(&MessagePayload::<Batch>::Ping(self.node_id.clone(), sequence))?;
+        for (name, addr) in &self.peers {
+            if *name != self.node_id {
+                self.packets_to_send.push_back((data.clone(), *addr));
+            }
+        }
+        Ok(())
+    }
+
+    /// Every phase is at least a round trip to a majority. The shortest configured phase waits for
+    /// that, and the others keep their configured proportion to it.
+    fn adapt_timeouts(&mut self) {
+        if let Some(timeout) = self.rtt.quorum_timeout(self.peers.len()) {
+            let configured = self.configured_timeouts;
+            let shortest = configured.prepare.min(configured.propose).min(configured.learn);
+            let scale = timeout.as_secs_f64() / shortest.as_secs_f64();
+            let adapt = |phase: Duration| {
+                let scaled = Duration::try_from_secs_f64(phase.as_secs_f64() * scale).unwrap_or(Duration::MAX);
+                scaled.max(MIN_ADAPTIVE_TIMEOUT).min(configured.max_backoff)
+            };
+            self.timeouts = Timeouts {
+                prepare: adapt(configured.prepare),
+                propose: adapt(configured.propose),
+                learn: adapt(configured.learn),
+                max_backoff: configured.max_backoff,
+            };
+        }
+    }
+
+    fn render_metrics(&mut self) -> String {
+        self.metrics.applied_index = self.next_log_to_apply - 1;
+        self.metrics.highest_instance = self.paxos.len() - 1;
+        self.metrics.locks_held = self.state_machine.locks().len();
+        self.metrics.in_flight = self.in_flight.len();
+        self.metrics.peak_in_flight = self.peak_in_flight;
+        self.metrics.queued_batches = self.queued_batches.len();
+        self.metrics.render()
+    }
+
+    fn on_timeout(&mut self, msg: PaxosMessage<Batch>, timeout: Duration) -> Result<()> {
+        let kind = msg.message.kind();
+        let instance = &mut self.paxos[msg.instance_id];
+        if instance.on_timeout(msg.message, timeout)? {
+            *self.metrics.timeouts.entry(kind).or_insert(0) += 1;
+        }
+        instance.collect_messages_to_send(&mut self.messages_to_send);
+        Ok(())
+    }
+
+    /// Turns the messages produced by the Paxos instances into packets and timers.
+    fn send_messages(&mut self) -> Result<()> {
+        let now = self.clock.now();
+        while let Some(message) = self.messages_to_send.pop_front() {
+            let target_name = match message.target {
+                MessageTarget::Broadcast => {
+                    // break broadcast messages into peer-to-peer messages
+                    for name in self.peers.keys() {
+                        self.messages_to_send.push_front(MessageInfo {
+                            payload: message.payload.clone(),
+                            target: MessageTarget::Node(name.clone()),
+                            timeout: None  // clear timeout here
+                        })
+                    }
+
+                    // setup timeout trigger
+                    self.setup_timeout_trigger(now, message);
+
+                    // process the peer-to-peer messages
+                    continue;
+                },
+                MessageTarget::Node(ref x) => x.clone(),
+            };
+
+            // setup timeout trigger
+            self.setup_timeout_trigger(now, message.clone());
+
+            trace!(peer = %target_name, payload = ?message.payload, "sending");
+
+            // send messages to self
+            if *target_name == self.node_id {
+                self.receive_message(message.payload, "0.0.0.0:0".parse().unwrap())?;  // FIXME don't need addr here
+                continue;  // process the next message
+            }
+
+            // send messages
+            let data = serde_yaml::to_vec(&message.payload)?;
+            let addr = self.peers.get(&target_name)
+                .ok_or_else(|| Error::from("cannot find the peer"))?;
+            self.packets_to_send.push_back((data, *addr));
+        }
+        Ok(())
+    }
+
+    /// Sends the YAML text of a reply that may not fit in a datagram as `Chunk`s, and keeps them
+    /// in case some get lost.
+    fn send_chunks(&mut self, request: u64, text: &str, addr: SocketAddr) -> Result<()> {
+        if text.len() > MAX_REPLY_SIZE {
+            warn!(%addr, request, size = text.len(), "reply too large to send, the query has to be paged");
+            return Ok(());
+        }
+        let chunks = Chunk::split(request, text).iter()
+            .map(|chunk| serde_yaml::to_vec(chunk).map_err(Error::from))
+            .collect::<Result<Vec<_>>>()?;
+        self.pace_chunks(chunks.iter().cloned(), addr);
+        if self.sent_replies.len() == MAX_SENT_REPLIES {
+            self.sent_replies.pop_front();
+        }
+        self.sent_replies.push_back(SentReply { addr, request, chunks });
+        Ok(())
+    }
+
+    /// Sends `chunks` `CHUNK_BURST` at a time, so that a large reply does not overflow the
+    /// receive buffer of the client.
+    fn pace_chunks<I: IntoIterator<Item = Vec<u8>>>(&mut self, chunks: I, addr: SocketAddr) {
+        let now = self.clock.now();
+        for (index, chunk) in chunks.into_iter().enumerate() {
+            match index / CHUNK_BURST {
+                0 => self.packets_to_send.push_back((chunk, addr)),
+                burst => self.start_timer(now + CHUNK_INTERVAL * burst as u32, Timer::Packet((chunk, addr))),
+            }
+        }
+    }
+
+    fn receive_message(&mut self, message: MessagePayload<Batch>, addr: SocketAddr) -> Result<()> {
+        trace!(%addr, payload = ?message, "received");
+        match message {
+            MessagePayload::PaxosMessage(ref msg) => {
+                // create all the missing instances
+                let next_instance_id = self.paxos.len();
+                for instance_id in next_instance_id ..= msg.instance_id {
+                    let instance = self.new_instance(instance_id);
+                    self.paxos.push(instance);
+                }
+
+                // handle the message
+                let decided = {
+                    let instance = &mut self.paxos[msg.instance_id];
+                    if let Some(batch) = msg.message.value() {
+                        instance.set_requests(batch.iter().map(|request| request.id).collect());
+                    }
+                    let decided = instance.receive_message(&msg.message);
+                    // the promise or acceptance is kept before the acceptor's answer goes out
+                    if let Some(state) = instance.take_acceptor_state() {
+                        self.storage.save_acceptor(msg.instance_id, &state)?;
+                    }
+                    if let Some(ref v) = decided {
+                        info!(instance = msg.instance_id, value = ?v, "reached consensus");
+                    }
+                    instance.collect_messages_to_send(&mut self.messages_to_send);
+                    decided
+                };
+                let apply_log = decided.is_some();
+
+                // a decided instance leaves the window. if another proposer's value won it, our
+                // batch goes first in line for a later instance.
+                if let Some(value) = decided {
+                    self.metrics.instances_decided += 1;
+                    self.undecided.remove(&msg.instance_id);
+                    self.filling.remove(&msg.instance_id);
+                    self.highest_decided = self.highest_decided.max(msg.instance_id);
+                    if let Some(batch) = self.in_flight.remove(&msg.instance_id) {
+                        self.metrics.proposal_rounds.observe(self.paxos[msg.instance_id].rounds() as f64);
+                        if batch != value {
+                            info!(instance = msg.instance_id, ?batch, "another value was chosen, proposing again");
+                            self.queued_batches.push_front(batch);
+                        }
+                        self.start_queued_batches();
+                    }
+                }
+
+                // update the locker when the learner learns the value for the first time
+                if apply_log {
+                    let total_instances = self.paxos.len() - 1;
+                    while self.next_log_to_apply <= total_instances {
+                        if self.paxos[self.next_log_to_apply].value().is_some() {
+                            self.apply(self.next_log_to_apply)?;
+                            self.next_log_to_apply += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            },
+            MessagePayload::LockerMessage(id, op) => {
+                let leader = self.leader().clone();
+                if self.redirect_clients && leader != self.node_id {
+                    debug!(%addr, id, ?op, %leader, "redirecting");
+                    let redirect = MessagePayload::<Batch>::Redirect(leader.clone(), self.peers[&leader]);
+                    self.packets_to_send.push_back((serde_yaml::to_vec(&redirect)?, addr));
+                } else {
+                    self.propose(id, op, Some(addr));
+                }
+            },
+            MessagePayload::Redirect(..) => (),
+            MessagePayload::PrintLog(request, query) => {
+                let log = self.state_machine.log().iter().enumerate()
+                    .filter(|(_, entry)| query.matches(entry.op.key()));
+                let text = serde_yaml::to_string(&query.page(log))?;
+                self.send_chunks(request, &text, addr)?;
+            },
+            MessagePayload::PrintLocks(request, query) => {
+                let mut locks: Vec<_> = self.state_machine.locks().iter()
+                    .filter(|(key, _)| query.matches(key))
+                    .collect();
+                locks.sort();
+                let text = serde_yaml::to_string(&query.page(locks))?;
+                self.send_chunks(request, &text, addr)?;
+            },
+            MessagePayload::Resend(request, indexes) => {
+                // each chunk at most once, however often it is asked for
+                let indexes: BTreeSet<usize> = indexes.into_iter().collect();
+                let reply = self.sent_replies.iter().find(|reply| reply.addr == addr && reply.request == request);
+                let chunks: Vec<_> = match reply {
+                    Some(reply) => indexes.iter().filter_map(|&index| reply.chunks.get(index).cloned()).collect(),
+                    None => {
+                        debug!(%addr, request, "no reply to resend");
+                        Vec::new()
+                    },
+                };
+                self.pace_chunks(chunks, addr);
+            },
+            MessagePayload::PrintTotalInstances => {
+                let total_instances = self.paxos.len() - 1;
+                let data = serde_yaml::to_vec(&total_instances)?;
+                self.packets_to_send.push_back((data, addr));
+            },
+            MessagePayload::Digest(start, end) => {
+                let digest = Digest::new(self.state_machine.log(), self.state_machine.locks(), start, end)?;
+                self.packets_to_send.push_back((serde_yaml::to_vec(&digest)?, addr));
+            },
+            MessagePayload::Status => {
+                let data = serde_yaml::to_vec(&self.status())?;
+                self.packets_to_send.push_back((data, addr));
+            },
+            MessagePayload::LogLevel(filter) => {
+                let reply = match (&self.log_level, filter) {
+                    (None, _) => Err("the log level of this server cannot be changed".into()),
+                    (Some(_), Some(_)) if !self.log_level_changes => {
+                        Err("log level changes are disabled on this server".into())
+                    },
+                    (Some(level), Some(filter)) => level.set(&filter).and_then(|_| level.get()),
+                    (Some(level), None) => level.get(),
+                };
+                let reply: std::result::Result<String, String> = reply.map_err(|e| e.to_string());
+                if let Ok(ref level) = reply {
+                    info!(%addr, level = %level, "log level");
+                }
+                let data = serde_yaml::to_vec(&reply)?;
+                self.packets_to_send.push_back((data, addr));
+            },
+            MessagePayload::Faults(policy) => {
+                let reply = match policy {
+                    _ if !self.fault_injection => Err("fault injection is disabled on this server".to_string()),
+                    Some(policy) => policy.validate().map(|_| {
+                        info!(%addr, ?policy, "fault policy");
+                        self.faults = policy;
+                        self.faults.clone()
+                    }),
+                    None => Ok(self.faults.clone()),
+                };
+                self.packets_to_send.push_back((serde_yaml::to_vec(&reply)?, addr));
+            },
+            MessagePayload::Ping(_, sequence) => {
+                let data = serde_yaml::to_vec(&MessagePayload::<Batch>::Pong(self.node_id.clone(), sequence))?;
+                self.packets_to_send.push_back((data, addr));
+            },
+            MessagePayload::Pong(peer, sequence) => {
+                let (last_sequence, sent_at) = self.last_ping;
+                if sequence == last_sequence && self.peers.contains_key(&peer) {
+                    self.rtt.sample(&peer, self.clock.now() - sent_at);
+                    self.adapt_timeouts();
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+async fn receive_packets(socket: Arc<UdpSocket>, events: UnboundedSender<Event>) {
+    let mut buf = vec![0u8; MAX_UDP_SIZE];
+    loop {
+        let event = match socket.recv_from(&mut buf).await {
+            Ok((size, addr)) => match serde_yaml::from_slice(&buf[..size]) {
+                Ok(message) => Event::Message(message, addr),
+                Err(e) => {
+                    warn!(%addr, error = %e, "dropped a malformed message");
+                    Event::Malformed
+                },
+            },
+            Err(e) => Event::Error(e.into()),
+        };
+        if events.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+async fn send_packets(socket: Arc<UdpSocket>, mut packets: UnboundedReceiver<Packet>, events: UnboundedSender<Event>) {
+    while let Some((data, addr)) = packets.recv().await {
+        match socket.send_to(&data, addr).await {
+            Ok(size) if size == data.len() => (),
+            Ok(size) => warn!(%addr, size, expected = data.len(), "sent a partial datagram"),
+            // one datagram failing, e.g. too large or to an unreachable client, is like one lost.
+            // only a socket that lost its address cannot send anything any more.
+            Err(e) => match socket.local_addr() {
+                Ok(_) => warn!(%addr, size = data.len(), error = %e, "cannot send a datagram"),
+                Err(_) => {
+                    let _ = events.send(Event::Error(e.into()));
+                    return;
+                },
+            },
+        }
+    }
+}
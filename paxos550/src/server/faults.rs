@@ -0,0 +1,98 @@
+//! Faults a server injects into its own traffic, for chaos tests on a single host.
+//!
+//! A `FaultPolicy` is set while the server runs with `MessagePayload::Faults`, e.g. from the
+//! client's `FAULTS` command or `paxos550::cluster::LocalCluster`, if the server was built with
+//! `ServerBuilder::fault_injection`. As YAML:
+//!
+//! ```yaml
+//! paused: false          # as if the process was stopped
+//! default:               # the link to every peer not listed below
+//!   drop_rate: 0.1
+//! peers:
+//!   server2:
+//!     disconnected: true # as across a partition
+//!   server3:
+//!     duplicate_rate: 0.5
+//!     min_delay_ms: 10
+//!     max_delay_ms: 200
+//! ```
+
+use crate::paxos::NodeID;
+
+use rand::Rng;
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// The longest a datagram can be delayed, a minute.
+pub const MAX_DELAY_MS: u64 = 60_000;
+
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct FaultPolicy {
+    /// Holds every event but the next `MessagePayload::Faults` until the server is resumed, as a
+    /// stopped process would find its datagrams and timers waiting when it continues. Proposals
+    /// and metrics scrapes wait too. Like a full socket buffer, a paused server drops datagrams
+    /// and proposals once it holds a few thousand.
+    pub paused: bool,
+    /// Faults of the links to the peers that are not in `peers`.
+    pub default: LinkFaults,
+    pub peers: BTreeMap<NodeID, LinkFaults>,
+}
+
+/// Faults of the link to one peer. Only `disconnected` applies to the datagrams that come from
+/// the peer; the rest applies to the datagrams sent to it.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkFaults {
+    /// Drops every datagram both ways.
+    pub disconnected: bool,
+    /// Probability that a datagram is lost.
+    pub drop_rate: f64,
+    /// Probability that a datagram is sent twice.
+    pub duplicate_rate: f64,
+    /// Datagrams are held for a delay drawn uniformly from `[min_delay_ms, max_delay_ms]`, which
+    /// also reorders them.
+    pub min_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl FaultPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        for (peer, link) in self.peers.iter().map(|(peer, link)| (peer.as_str(), link))
+            .chain(Some(("default", &self.default))) {
+            if !(0.0..=1.0).contains(&link.drop_rate) || !(0.0..=1.0).contains(&link.duplicate_rate) {
+                return Err(format!("rates of {} must be between 0 and 1", peer));
+            }
+            if link.min_delay_ms > link.max_delay_ms {
+                return Err(format!("min_delay_ms of {} is above max_delay_ms", peer));
+            }
+            if link.max_delay_ms > MAX_DELAY_MS {
+                return Err(format!("max_delay_ms of {} is above {}", peer, MAX_DELAY_MS));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn link(&self, peer: &str) -> &LinkFaults {
+        self.peers.get(peer).unwrap_or(&self.default)
+    }
+
+    /// Whether the datagrams from `peer` are dropped.
+    pub fn drops_from(&self, peer: &str) -> bool {
+        self.link(peer).disconnected
+    }
+
+    /// How long to hold each copy of a datagram to `peer` before sending it. None are sent if
+    /// it is dropped. The policy must be valid.
+    pub fn delays_to<R: Rng + ?Sized>(&self, peer: &str, random: &mut R) -> Vec<Duration> {
+        let link = self.link(peer);
+        if link.disconnected || random.gen::<f64>() < link.drop_rate {
+            return Vec::new();
+        }
+        let copies = if random.gen::<f64>() < link.duplicate_rate { 2 } else { 1 };
+        (0..copies).map(|_| {
+            Duration::from_millis(random.gen_range(link.min_delay_ms, link.max_delay_ms + 1))
+        }).collect()
+    }
+}
@@ -0,0 +1,205 @@
+//! Counters and histograms of a server, served over HTTP in the Prometheus text format.
+
+use crate::paxos::NodeID;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+const ROUND_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 5.0, 8.0, 13.0];
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.002, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0];
+// enough for the request line and the headers of a scrape
+const MAX_REQUEST_SIZE: usize = 8192;
+
+pub struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram { buckets, counts: vec![0; buckets.len()], sum: 0.0, count: 0 }
+    }
+
+    pub fn observe(&mut self, value: f64) {
+        for (bound, count) in self.buckets.iter().zip(&mut self.counts) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        for (bound, count) in self.buckets.iter().zip(&self.counts) {
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum);
+        let _ = writeln!(out, "{}_count {}", name, self.count);
+    }
+}
+
+/// Everything the event loop counts. The gauges are filled in right before rendering.
+pub struct Metrics {
+    /// Instances this server proposed a batch in.
+    pub instances_started: u64,
+    /// Instances this server learned the value of.
+    pub instances_decided: u64,
+    /// Prepare rounds of each instance this server proposed in, counted when it is decided.
+    pub proposal_rounds: Histogram,
+    /// Retries by the kind of message that timed out.
+    pub timeouts: BTreeMap<&'static str, u64>,
+    /// Datagrams by peer, or `client` for any other address.
+    pub messages_sent: BTreeMap<NodeID, u64>,
+    pub messages_received: BTreeMap<NodeID, u64>,
+    pub decode_failures: u64,
+    /// Datagrams and proposals dropped because a paused server held too many.
+    pub paused_drops: u64,
+    /// From a client's `Lock` request to its successful application.
+    pub lock_acquisition: Histogram,
+    pub applied_index: usize,
+    pub highest_instance: usize,
+    pub locks_held: usize,
+    /// Instances of the window this server proposed in that are not decided yet.
+    pub in_flight: usize,
+    pub peak_in_flight: usize,
+    /// Batches waiting for room in the window.
+    pub queued_batches: usize,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            instances_started: 0,
+            instances_decided: 0,
+            proposal_rounds: Histogram::new(ROUND_BUCKETS),
+            timeouts: BTreeMap::new(),
+            messages_sent: BTreeMap::new(),
+            messages_received: BTreeMap::new(),
+            decode_failures: 0,
+            paused_drops: 0,
+            lock_acquisition: Histogram::new(LATENCY_BUCKETS),
+            applied_index: 0,
+            highest_instance: 0,
+            locks_held: 0,
+            in_flight: 0,
+            peak_in_flight: 0,
+            queued_batches: 0,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        counter(&mut out, "paxos550_instances_started_total", "Instances this server proposed a batch in.",
+                self.instances_started);
+        counter(&mut out, "paxos550_instances_decided_total", "Instances this server learned the value of.",
+                self.instances_decided);
+        self.proposal_rounds.render(&mut out, "paxos550_proposal_rounds",
+                                    "Prepare rounds an instance this server proposed in took to be decided.");
+        labeled(&mut out, "paxos550_timeouts_total", "Paxos messages retried after a timeout.", "counter",
+                "message", &self.timeouts);
+        labeled(&mut out, "paxos550_messages_sent_total", "Datagrams sent.", "counter", "peer",
+                &self.messages_sent);
+        labeled(&mut out, "paxos550_messages_received_total", "Datagrams received.", "counter", "peer",
+                &self.messages_received);
+        counter(&mut out, "paxos550_decode_failures_total", "Datagrams dropped because they could not be decoded.",
+                self.decode_failures);
+        counter(&mut out, "paxos550_paused_drops_total",
+                "Datagrams and proposals dropped because a paused server held too many.", self.paused_drops);
+        gauge(&mut out, "paxos550_applied_index", "Last instance applied to the state machine.",
+              self.applied_index);
+        gauge(&mut out, "paxos550_highest_instance", "Highest instance this server knows about.",
+              self.highest_instance);
+        gauge(&mut out, "paxos550_locks_held", "Keys locked in the state machine.", self.locks_held);
+        gauge(&mut out, "paxos550_in_flight", "Instances this server proposed in that are not decided yet.",
+              self.in_flight);
+        gauge(&mut out, "paxos550_peak_in_flight", "Most instances this server had in flight at once.",
+              self.peak_in_flight);
+        gauge(&mut out, "paxos550_queued_batches", "Batches waiting for room in the window.",
+              self.queued_batches);
+        self.lock_acquisition.render(&mut out, "paxos550_lock_acquisition_seconds",
+                                     "Time from a client's LOCK request to its successful application.");
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Metrics {
+        Metrics::new()
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    header(out, name, help, "counter");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    header(out, name, help, "gauge");
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn labeled<K: AsRef<str>>(out: &mut String, name: &str, help: &str, kind: &str, label: &str,
+                          values: &BTreeMap<K, u64>) {
+    header(out, name, help, kind);
+    for (key, value) in values {
+        let key = key.as_ref().replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+        let _ = writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, key, value);
+    }
+}
+
+/// Answers `GET /metrics` with the text the event loop renders for each scrape.
+pub async fn serve(listener: TcpListener, scrapes: UnboundedSender<oneshot::Sender<String>>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(answer(stream, scrapes.clone()));
+            },
+            Err(e) => warn!("cannot accept a metrics connection: {}", e),
+        }
+    }
+}
+
+async fn answer(mut stream: TcpStream, scrapes: UnboundedSender<oneshot::Sender<String>>) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(size) if request.len() + size > MAX_REQUEST_SIZE => return,
+            Ok(size) => request.extend_from_slice(&buf[..size]),
+        }
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let (reply, text) = oneshot::channel();
+            if scrapes.send(reply).is_err() {
+                return;
+            }
+            match text.await {
+                Ok(text) => ("200 OK", text),
+                Err(_) => return,
+            }
+        },
+        _ => ("404 Not Found", "only /metrics is served\n".to_string()),
+    };
+    let response = format!("HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+                            Connection: close\r\n\r\n{}", status, body.len(), body);
+    let _ = stream.write_all(response.as_bytes()).await;
+}
@@ -0,0 +1,509 @@
+//! A Paxos lock server that can be embedded in another program.
+//!
+//! `ServerBuilder` binds the socket and returns a `ServerHandle`. `ServerHandle::start` runs the
+//! server's event loop on its own thread, so any number of servers can run in one process.
+//!
+//! ```no_run
+//! use paxos550::server::ServerBuilder;
+//! use paxos550::locker::Operation;
+//!
+//! let mut server = ServerBuilder::new("node0".to_string(), "127.0.0.1:9000".parse().unwrap())
+//!     .peer("node1".to_string(), "127.0.0.1:9001".parse().unwrap())
+//!     .peer("node2".to_string(), "127.0.0.1:9002".parse().unwrap())
+//!     .build()
+//!     .unwrap();
+//! server.start().unwrap();
+//! server.propose(Operation::Lock("key".to_string(), "client".to_string())).unwrap();
+//! println!("{:?}", server.status());
+//! server.shutdown().unwrap();
+//! ```
+
+mod event_loop;
+mod faults;
+mod metrics;
+mod rtt;
+mod storage;
+
+pub use self::faults::*;
+pub use self::storage::*;
+
+use self::event_loop::Server;
+use crate::config::Config;
+use crate::env::{self, Clock, Random, SystemClock};
+use crate::errors::*;
+use crate::locker::{Locker, LogEntry, Operation, DEFAULT_LEASE};
+use crate::logging::LogLevel;
+use crate::paxos::{InstanceID, NodeID, Timeouts};
+
+use tokio::runtime;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tracing::Instrument;
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{SocketAddr, TcpListener, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc as std_mpsc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+pub const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(16);
+pub const DEFAULT_BATCH_SIZE: usize = 64;
+pub const DEFAULT_BATCH_DELAY: Duration = Duration::from_millis(5);
+pub const DEFAULT_WINDOW: usize = 8;
+
+/// The value of a Paxos instance: operations applied in order.
+pub type Batch = Vec<Request>;
+
+/// An operation in a batch, with the ID its client gave the request, so that the server the client
+/// asked can tell which reply is whose, and the state machine which requests are copies of one
+/// another. Operations proposed through `Command::Propose` have ID 0.
+#[derive(Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Debug)]
+pub struct Request {
+    pub id: u64,
+    pub op: Operation,
+    /// When the server that proposed it got it, in milliseconds since the UNIX epoch.
+    pub time_ms: u64,
+}
+
+/// The replicated state machine. Decided operations are applied to it in log order.
+pub trait StateMachine: Send {
+    /// Applies the operation of `request` and returns whether it was valid. A request a client
+    /// sent to several servers can be decided more than once, and should only take effect once.
+    fn apply(&mut self, request: &Request) -> bool;
+    /// Answers `MessagePayload::PrintLog`.
+    fn log(&self) -> &Vec<LogEntry>;
+    /// Answers `MessagePayload::PrintLocks`.
+    fn locks(&self) -> &HashMap<String, NodeID>;
+}
+
+impl StateMachine for Locker {
+    fn apply(&mut self, request: &Request) -> bool {
+        self.apply_request(request.id, &request.op, request.time_ms)
+    }
+
+    fn log(&self) -> &Vec<LogEntry> {
+        Locker::log(self)
+    }
+
+    fn locks(&self) -> &HashMap<String, NodeID> {
+        Locker::locks(self)
+    }
+}
+
+/// A snapshot of what a running server has done so far. Clients get it with
+/// `MessagePayload::Status`.
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+pub struct Status {
+    pub node_id: NodeID,
+    /// Version of the server's build.
+    pub version: String,
+    /// The other servers.
+    pub peers: BTreeMap<NodeID, PeerStatus>,
+    /// The server clients are redirected to, if they are.
+    pub leader: Option<NodeID>,
+    /// Number of Paxos instances the server knows about.
+    pub total_instances: usize,
+    /// Number of instances applied to the state machine.
+    pub applied: usize,
+    pub next_log_to_apply: InstanceID,
+    /// Highest instance this server learned the value of.
+    pub highest_decided: InstanceID,
+    /// How long ago this server heard of each instance it has not learned the value of yet.
+    pub undecided: BTreeMap<InstanceID, Duration>,
+    /// Number of operations in those instances.
+    pub applied_operations: usize,
+    /// Number of instances this server proposed that are not decided yet.
+    pub in_flight: usize,
+    /// Highest `in_flight` so far.
+    pub peak_in_flight: usize,
+    /// Number of batches waiting for room in the window.
+    pub queued_batches: usize,
+    /// The timeouts of the instances proposed now, which change with `round_trip_times` if the
+    /// timeouts are adaptive.
+    pub prepare_timeout: Duration,
+    pub propose_timeout: Duration,
+    pub learn_timeout: Duration,
+    /// Smoothed round-trip time to each peer, measured only if the timeouts are adaptive.
+    pub round_trip_times: HashMap<NodeID, Duration>,
+    /// Bytes the Paxos state and the decided log take up in storage, if the storage can tell.
+    pub storage_bytes: Option<u64>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct PeerStatus {
+    pub address: SocketAddr,
+    /// How long ago a datagram last came from the peer, if ever.
+    pub last_heard: Option<Duration>,
+}
+
+pub enum Command {
+    Propose(Operation),
+    /// Asks for the current `Status`, which the server only builds when asked.
+    Status(std_mpsc::Sender<Status>),
+    Shutdown,
+}
+
+pub struct ServerBuilder {
+    node_id: NodeID,
+    listen: SocketAddr,
+    socket: Option<UdpSocket>,
+    metrics: Option<SocketAddr>,
+    peers: HashMap<NodeID, SocketAddr>,
+    timeouts: Timeouts,
+    adaptive_timeouts: bool,
+    redirect_clients: bool,
+    batch_size: usize,
+    batch_delay: Duration,
+    window: usize,
+    clock: Box<dyn Clock>,
+    random: Random,
+    lease: Duration,
+    state_machine: Box<dyn StateMachine>,
+    storage: Box<dyn Storage>,
+    log_level: Option<LogLevel>,
+    log_level_changes: bool,
+    fault_injection: bool,
+}
+
+impl ServerBuilder {
+    /// A server for a `Locker` with in-memory storage, the system clock and a random seed.
+    pub fn new(node_id: NodeID, listen: SocketAddr) -> ServerBuilder {
+        ServerBuilder {
+            node_id,
+            listen,
+            socket: None,
+            metrics: None,
+            peers: HashMap::new(),
+            timeouts: Timeouts::uniform(DEFAULT_TIMEOUT, DEFAULT_MAX_BACKOFF),
+            adaptive_timeouts: false,
+            redirect_clients: false,
+            batch_size: DEFAULT_BATCH_SIZE,
+            batch_delay: DEFAULT_BATCH_DELAY,
+            window: DEFAULT_WINDOW,
+            clock: Box::new(SystemClock),
+            random: env::system_random(),
+            lease: DEFAULT_LEASE,
+            state_machine: Box::new(Locker::new()),
+            storage: Box::new(MemoryStorage::new()),
+            log_level: None,
+            log_level_changes: false,
+            fault_injection: false,
+        }
+    }
+
+    /// A server for node `id` of `config`, with the settings of the file.
+    pub fn from_config(config: &Config, id: &str) -> Result<ServerBuilder> {
+        let node = config.node(id)?;
+        let mut builder = ServerBuilder::new(node.id.clone(), node.listen.unwrap_or(node.address))
+            .peers(config.peers(id))
+            .metrics(node.metrics)
+            .timeout(config.server.timeout())
+            .max_backoff(config.server.max_backoff())
+            .adaptive_timeouts(config.server.adaptive_timeouts)
+            .redirect_clients(config.server.redirect_clients)
+            .batch_size(config.server.batch_size)
+            .batch_delay(config.server.batch_delay())
+            .window(config.server.window)
+            .lease(config.server.lease())
+            .log_level_changes(config.server.log_level_changes)
+            .fault_injection(config.server.fault_injection);
+        if let Some(timeout) = config.server.prepare_timeout() {
+            builder = builder.prepare_timeout(timeout);
+        }
+        if let Some(timeout) = config.server.propose_timeout() {
+            builder = builder.propose_timeout(timeout);
+        }
+        if let Some(timeout) = config.server.learn_timeout() {
+            builder = builder.learn_timeout(timeout);
+        }
+        if let Some(ref path) = node.storage {
+            builder = builder.storage(Box::new(FileStorage::open(path)?));
+        }
+        Ok(builder)
+    }
+
+    pub fn listen(mut self, listen: SocketAddr) -> ServerBuilder {
+        self.listen = listen;
+        self
+    }
+
+    /// Serves on a socket that is bound already, instead of binding `listen`. A launcher that picks
+    /// the ports of its servers can hold them this way until the servers run.
+    pub fn socket(mut self, socket: UdpSocket) -> ServerBuilder {
+        self.socket = Some(socket);
+        self
+    }
+
+    /// Serves Prometheus metrics over HTTP at `/metrics` on this address.
+    pub fn metrics(mut self, metrics: Option<SocketAddr>) -> ServerBuilder {
+        self.metrics = metrics;
+        self
+    }
+
+    pub fn peer(mut self, node_id: NodeID, addr: SocketAddr) -> ServerBuilder {
+        self.peers.insert(node_id, addr);
+        self
+    }
+
+    pub fn peers(mut self, peers: HashMap<NodeID, SocketAddr>) -> ServerBuilder {
+        self.peers.extend(peers);
+        self
+    }
+
+    /// The timeout of every phase of a Paxos instance, before back-off.
+    pub fn timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.timeouts = Timeouts::uniform(timeout, self.timeouts.max_backoff);
+        self
+    }
+
+    /// How long a proposer waits for a majority of `Promise`s, before back-off.
+    pub fn prepare_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.timeouts.prepare = timeout;
+        self
+    }
+
+    /// How long a proposer waits for a majority of `Accepted`s, before back-off.
+    pub fn propose_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.timeouts.propose = timeout;
+        self
+    }
+
+    /// How long a learner waits for a `Value`, before back-off.
+    pub fn learn_timeout(mut self, timeout: Duration) -> ServerBuilder {
+        self.timeouts.learn = timeout;
+        self
+    }
+
+    /// The longest a retry waits, however many times it backed off.
+    pub fn max_backoff(mut self, max_backoff: Duration) -> ServerBuilder {
+        self.timeouts.max_backoff = max_backoff;
+        self
+    }
+
+    /// Pings the peers and derives the timeouts from the round-trip time to a majority of them,
+    /// instead of using the configured timeouts after the first measurements.
+    pub fn adaptive_timeouts(mut self, adaptive_timeouts: bool) -> ServerBuilder {
+        self.adaptive_timeouts = adaptive_timeouts;
+        self
+    }
+
+    /// Redirects the operations of clients to the leader, the server with the lowest ID among
+    /// those that answer pings, so that it proposes most operations without competing with the
+    /// other servers.
+    pub fn redirect_clients(mut self, redirect_clients: bool) -> ServerBuilder {
+        self.redirect_clients = redirect_clients;
+        self
+    }
+
+    /// Proposes the pending operations as soon as there are `batch_size` of them.
+    pub fn batch_size(mut self, batch_size: usize) -> ServerBuilder {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// How long an operation waits for others to join its batch.
+    pub fn batch_delay(mut self, batch_delay: Duration) -> ServerBuilder {
+        self.batch_delay = batch_delay;
+        self
+    }
+
+    /// Maximum number of undecided instances this server proposes at a time. Further batches wait
+    /// until one of them is decided.
+    pub fn window(mut self, window: usize) -> ServerBuilder {
+        self.window = window;
+        self
+    }
+
+    pub fn clock(mut self, clock: Box<dyn Clock>) -> ServerBuilder {
+        self.clock = clock;
+        self
+    }
+
+    pub fn random(mut self, random: Random) -> ServerBuilder {
+        self.random = random;
+        self
+    }
+
+    /// A `Locker` whose locks expire `lease` after they were taken or last renewed. Every server
+    /// of a cluster must have the same lease.
+    pub fn lease(mut self, lease: Duration) -> ServerBuilder {
+        self.lease = lease;
+        self.state_machine = Box::new(Locker::with_lease(lease));
+        self
+    }
+
+    pub fn state_machine(mut self, state_machine: Box<dyn StateMachine>) -> ServerBuilder {
+        self.state_machine = state_machine;
+        self
+    }
+
+    pub fn storage(mut self, storage: Box<dyn Storage>) -> ServerBuilder {
+        self.storage = storage;
+        self
+    }
+
+    /// Lets clients ask for the log level with `MessagePayload::LogLevel`.
+    pub fn log_level(mut self, log_level: LogLevel) -> ServerBuilder {
+        self.log_level = Some(log_level);
+        self
+    }
+
+    /// Lets anyone who can reach the server change its log level with `MessagePayload::LogLevel`,
+    /// e.g. to `trace`, which can flood its output. Off unless asked for.
+    pub fn log_level_changes(mut self, log_level_changes: bool) -> ServerBuilder {
+        self.log_level_changes = log_level_changes;
+        self
+    }
+
+    /// Lets anyone who can reach the server make it drop, delay or pause its traffic with
+    /// `MessagePayload::Faults`. Off unless a chaos test asks for it.
+    pub fn fault_injection(mut self, fault_injection: bool) -> ServerBuilder {
+        self.fault_injection = fault_injection;
+        self
+    }
+
+    /// Checks the settings, which may come from a file, flags or code, before anything is bound.
+    fn validate(&self) -> Result<()> {
+        let timeouts = &self.timeouts;
+        let durations = [timeouts.prepare, timeouts.propose, timeouts.learn, timeouts.max_backoff, self.lease];
+        if durations.contains(&Duration::ZERO) {
+            bail!(ErrorKind::InvalidConfig("timeouts and lease must be positive".to_string()));
+        }
+        if self.batch_size == 0 || self.window == 0 {
+            bail!(ErrorKind::InvalidConfig("batch_size and window must be positive".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Binds the listening sockets and takes back what the storage saved in an earlier run. The
+    /// server does not run until `ServerHandle::start`.
+    pub fn build(mut self) -> Result<ServerHandle> {
+        self.validate()?;
+        let socket = match self.socket.take() {
+            Some(socket) => socket,
+            None => UdpSocket::bind(self.listen)?,
+        };
+        socket.set_nonblocking(true)?;
+        let local_addr = socket.local_addr()?;
+        let metrics_listener = match self.metrics {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .chain_err(|| format!("cannot serve metrics on {}", addr))?;
+                listener.set_nonblocking(true)?;
+                Some(listener)
+            },
+            None => None,
+        };
+        let metrics_addr = match metrics_listener {
+            Some(ref listener) => Some(listener.local_addr()?),
+            None => None,
+        };
+        let node_id = self.node_id.clone();
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let status = Arc::new(Mutex::new(Status {
+            node_id: node_id.clone(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            ..Status::default()
+        }));
+        let mut server = Server::new(self, local_addr, metrics_listener, receiver, status.clone());
+        server.recover()?;
+        Ok(ServerHandle {
+            node_id,
+            local_addr,
+            metrics_addr,
+            server: Some((server, socket)),
+            commands,
+            status,
+            thread: None,
+        })
+    }
+}
+
+/// Controls a server built by `ServerBuilder`. Dropping the handle shuts the server down.
+pub struct ServerHandle {
+    node_id: NodeID,
+    local_addr: SocketAddr,
+    metrics_addr: Option<SocketAddr>,
+    server: Option<(Server, UdpSocket)>,
+    commands: UnboundedSender<Command>,
+    /// The last status the server built, for when it is not running.
+    status: Arc<Mutex<Status>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl ServerHandle {
+    pub fn node_id(&self) -> &NodeID {
+        &self.node_id
+    }
+
+    /// The address the server listens on, useful when it was built with port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// The address metrics are served on, if any.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// Runs the server on a new thread.
+    pub fn start(&mut self) -> Result<()> {
+        let (server, socket) = self.server.take().ok_or_else(|| Error::from("server already started"))?;
+        let span = info_span!("server", node = %self.node_id);
+        let thread = thread::Builder::new().name(self.node_id.clone()).spawn(move || {
+            // dropping the runtime at the end stops the server's tasks
+            let runtime = runtime::Builder::new_current_thread().enable_all().build()?;
+            runtime.block_on(server.run(socket).instrument(span))
+        })?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    /// Proposes `op` in the next batch, as if a client sent it to this server.
+    pub fn propose(&self, op: Operation) -> Result<()> {
+        self.commands.send(Command::Propose(op))
+            .map_err(|_| Error::from("server is not running"))
+    }
+
+    /// Asks the running server for its status. A server that is not running has the status it
+    /// had when it stopped.
+    pub fn status(&self) -> Status {
+        if self.thread.is_some() {
+            let (reply, status) = std_mpsc::channel();
+            if self.commands.send(Command::Status(reply)).is_ok() {
+                if let Ok(status) = status.recv() {
+                    return status;
+                }
+            }
+        }
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Stops the server and waits for its thread to exit.
+    pub fn shutdown(&mut self) -> Result<()> {
+        // the server may have stopped on an error already
+        let _ = self.commands.send(Command::Shutdown);
+        self.join()
+    }
+
+    /// Waits until the server stops, which only happens on an error or a `shutdown`.
+    pub fn wait(mut self) -> Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join().map_err(|_| Error::from("server thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown() {
+            error!(node = %self.node_id, error = %e, "server stopped with an error");
+        }
+    }
+}
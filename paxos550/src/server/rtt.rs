@@ -0,0 +1,59 @@
+//! Round-trip time estimates for adaptive timeouts, in the manner of TCP's retransmission timer
+//! (RFC 6298).
+
+use crate::paxos::NodeID;
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+struct Estimate {
+    smoothed: Duration,
+    variation: Duration,
+}
+
+impl Estimate {
+    fn retransmission_timeout(&self) -> Duration {
+        self.smoothed + self.variation * 4
+    }
+}
+
+#[derive(Default)]
+pub struct RttEstimator {
+    peers: HashMap<NodeID, Estimate>,
+}
+
+impl RttEstimator {
+    pub fn new() -> RttEstimator {
+        RttEstimator { peers: HashMap::new() }
+    }
+
+    pub fn sample(&mut self, peer: &NodeID, rtt: Duration) {
+        match self.peers.get_mut(peer) {
+            Some(estimate) => {
+                let error = estimate.smoothed.abs_diff(rtt);
+                estimate.variation = (estimate.variation * 3 + error) / 4;
+                estimate.smoothed = (estimate.smoothed * 7 + rtt) / 8;
+            },
+            None => {
+                self.peers.insert(peer.clone(), Estimate { smoothed: rtt, variation: rtt / 2 });
+            },
+        }
+    }
+
+    /// The smoothed round-trip time to each peer measured so far.
+    pub fn round_trip_times(&self) -> HashMap<NodeID, Duration> {
+        self.peers.iter().map(|(peer, estimate)| (peer.clone(), estimate.smoothed)).collect()
+    }
+
+    /// How long to wait for the replies of a majority of `cluster_size` nodes, this one included.
+    /// `None` until enough peers have been measured.
+    pub fn quorum_timeout(&self, cluster_size: usize) -> Option<Duration> {
+        let peers_needed = cluster_size / 2;
+        if peers_needed == 0 || self.peers.len() < peers_needed {
+            return None;
+        }
+        let mut timeouts: Vec<_> = self.peers.values().map(Estimate::retransmission_timeout).collect();
+        timeouts.sort();
+        Some(timeouts[peers_needed - 1])
+    }
+}
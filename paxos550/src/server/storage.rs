@@ -0,0 +1,144 @@
+use crate::errors::*;
+use crate::server::{Batch, Request};
+use crate::paxos::{AcceptorState, InstanceID};
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::mem;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// What a server saved before it stopped, to start again where it left off.
+#[derive(Clone, Default, Debug)]
+pub struct Saved {
+    /// The latest state of the acceptor of each instance.
+    pub acceptors: BTreeMap<InstanceID, AcceptorState<Batch>>,
+    /// The decided instances that were applied, from the first one on.
+    pub log: BTreeMap<InstanceID, Batch>,
+}
+
+/// Where a server keeps what it promised, accepted and decided, so that a restarted server keeps
+/// the promises of the earlier one and applies the same log again.
+pub trait Storage: Send {
+    /// What was saved before the server was built. The server reads it once, before it runs.
+    fn load(&mut self) -> Result<Saved>;
+
+    /// Called whenever the acceptor of an instance promises or accepts, before it answers.
+    fn save_acceptor(&mut self, instance_id: InstanceID, state: &AcceptorState<Batch>) -> Result<()>;
+
+    /// Called for every decided instance, in order, before it is applied to the state machine.
+    fn save(&mut self, instance_id: InstanceID, batch: &[Request]) -> Result<()>;
+
+    /// Bytes the saved state takes up, if the storage can tell.
+    fn bytes(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Keeps the log in memory. Clones share the same log, so a server built with a clone of the
+/// storage of one that stopped starts where it left off.
+#[derive(Clone, Default, Debug)]
+pub struct MemoryStorage {
+    saved: Arc<Mutex<Saved>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage::default()
+    }
+
+    pub fn log(&self) -> Vec<(InstanceID, Batch)> {
+        self.saved.lock().unwrap().log.iter().map(|(&id, batch)| (id, batch.clone())).collect()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(&mut self) -> Result<Saved> {
+        Ok(self.saved.lock().unwrap().clone())
+    }
+
+    fn save_acceptor(&mut self, instance_id: InstanceID, state: &AcceptorState<Batch>) -> Result<()> {
+        self.saved.lock().unwrap().acceptors.insert(instance_id, state.clone());
+        Ok(())
+    }
+
+    fn save(&mut self, instance_id: InstanceID, batch: &[Request]) -> Result<()> {
+        self.saved.lock().unwrap().log.insert(instance_id, batch.to_vec());
+        Ok(())
+    }
+}
+
+/// A line of a storage file.
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Acceptor(InstanceID, AcceptorState<Batch>),
+    Decided(InstanceID, Batch),
+}
+
+/// Appends every change to a file as a line of JSON, and syncs it to disk before the server acts
+/// on it. The file grows with every promise, and is read back when it is opened again.
+pub struct FileStorage {
+    file: File,
+    /// What the file held when it was opened, until the server loads it.
+    saved: Saved,
+    bytes: u64,
+}
+
+impl FileStorage {
+    /// Creates the file, or reads what an earlier run saved in it. A last line that a crash cut
+    /// short is dropped, since the server did not act on it.
+    pub fn open(path: &Path) -> Result<FileStorage> {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(path)
+            .chain_err(|| format!("cannot open storage file {}", path.display()))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text).chain_err(|| format!("cannot read storage file {}", path.display()))?;
+        let mut saved = Saved::default();
+        let mut bytes = 0;
+        for line in text.split_inclusive('\n') {
+            match serde_json::from_str(line) {
+                Ok(Record::Acceptor(instance_id, state)) => {
+                    saved.acceptors.insert(instance_id, state);
+                },
+                Ok(Record::Decided(instance_id, batch)) => {
+                    saved.log.insert(instance_id, batch);
+                },
+                Err(_) if !line.ends_with('\n') => {
+                    warn!(path = %path.display(), "dropped the unfinished last line of the storage file");
+                    break;
+                },
+                Err(e) => bail!("{} is not a storage file: {}", path.display(), e),
+            }
+            bytes += line.len() as u64;
+        }
+        file.set_len(bytes)?;
+        Ok(FileStorage { file, saved, bytes })
+    }
+
+    fn append(&mut self, record: &Record) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        self.bytes += line.len() as u64;
+        Ok(())
+    }
+}
+
+impl Storage for FileStorage {
+    fn load(&mut self) -> Result<Saved> {
+        Ok(mem::take(&mut self.saved))
+    }
+
+    fn save_acceptor(&mut self, instance_id: InstanceID, state: &AcceptorState<Batch>) -> Result<()> {
+        self.append(&Record::Acceptor(instance_id, state.clone()))
+    }
+
+    fn save(&mut self, instance_id: InstanceID, batch: &[Request]) -> Result<()> {
+        self.append(&Record::Decided(instance_id, batch.to_vec()))
+    }
+
+    fn bytes(&self) -> Option<u64> {
+        Some(self.bytes)
+    }
+}
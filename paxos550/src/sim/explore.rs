@@ -0,0 +1,327 @@
+//! Bounded exhaustive exploration of small clusters.
+//!
+//! Starting from a cluster where nobody has proposed yet, `explore` enumerates every order in
+//! which proposals start, messages are delivered and pending timeouts fire, using only
+//! `PaxosInstance::receive_message` and `PaxosInstance::on_timeout`. As in the TLA+ specification
+//! of Paxos, a message stays in the network once it is sent and can be delivered any number of
+//! times, in any order, or never, which covers lost, duplicated and reordered messages. States are
+//! deduplicated by hash and explored breadth first, so the first violation found comes with a
+//! shortest trace.
+//!
+//! After every step the explorer checks that nodes agree on the value of each instance, that the
+//! value was proposed for that instance, and that no component panicked or returned an error.
+
+use crate::paxos::*;
+use crate::network::message::{MessageInfo, MessagePayload, MessageTarget};
+
+use rand::rngs::mock::StepRng;
+use std::collections::{HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct ExploreConfig<T> {
+    pub cluster_size: usize,
+    /// Values to propose, as `(node, instance, value)`.
+    pub proposals: Vec<(usize, InstanceID, T)>,
+    /// Maximum number of timeouts fired in one execution.
+    pub max_timeouts: usize,
+    /// Maximum number of steps in one execution.
+    pub max_depth: usize,
+    /// Stop after visiting this many distinct states.
+    pub max_states: usize,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    /// Number of distinct states visited.
+    pub states: usize,
+    /// `false` if `max_depth` or `max_states` cut the exploration short.
+    pub complete: bool,
+    pub violation: Option<Violation>,
+}
+
+#[derive(Debug)]
+pub struct Violation {
+    pub error: String,
+    /// The steps that lead from the initial state to the violation.
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.error)?;
+        for (i, step) in self.trace.iter().enumerate() {
+            writeln!(f, "{:3}. {}", i + 1, step)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct State<T> {
+    nodes: Vec<Vec<PaxosInstance<T>>>,
+    sent: Vec<(usize, usize, PaxosMessage<T>)>,
+    timers: Vec<(usize, PaxosMessage<T>, Duration)>,
+    started: Vec<bool>,
+    timeouts: usize,
+}
+
+#[derive(Clone, Copy)]
+enum Event {
+    Propose(usize),
+    Deliver(usize),
+    Timeout(usize),
+}
+
+struct Step {
+    parent: Option<usize>,
+    description: String,
+    depth: usize,
+}
+
+fn hash_one<H: Hash>(value: &H) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn node_id(node: usize) -> NodeID {
+    format!("node{}", node)
+}
+
+impl<T: Clone + Hash + Eq + Debug> State<T> {
+    fn new(config: &ExploreConfig<T>) -> State<T> {
+        let instances = config.proposals.iter().map(|p| p.1 + 1).max().unwrap_or(0);
+        let nodes = (0..config.cluster_size).map(|node| {
+            (0..instances).map(|instance_id| {
+                // a constant random source keeps the state fully described by its hash
+                PaxosInstance::with_random(node_id(node), instance_id, config.cluster_size,
+                                           Duration::from_secs(1), Box::new(StepRng::new(0, 0)))
+            }).collect()
+        }).collect();
+        State {
+            nodes,
+            sent: Vec::new(),
+            timers: Vec::new(),
+            started: vec![false; config.proposals.len()],
+            timeouts: 0,
+        }
+    }
+
+    fn fingerprint(&self) -> u64 {
+        // the order of messages and timers does not matter
+        let mut sent: Vec<u64> = self.sent.iter().map(hash_one).collect();
+        sent.sort();
+        let mut timers: Vec<u64> = self.timers.iter().map(hash_one).collect();
+        timers.sort();
+        let mut hasher = DefaultHasher::new();
+        self.nodes.hash(&mut hasher);
+        sent.hash(&mut hasher);
+        timers.hash(&mut hasher);
+        self.started.hash(&mut hasher);
+        self.timeouts.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn events(&self, config: &ExploreConfig<T>) -> Vec<Event> {
+        let mut events = Vec::new();
+        for (i, &started) in self.started.iter().enumerate() {
+            if !started {
+                events.push(Event::Propose(i));
+            }
+        }
+        for i in 0..self.sent.len() {
+            events.push(Event::Deliver(i));
+        }
+        if self.timeouts < config.max_timeouts {
+            for i in 0..self.timers.len() {
+                events.push(Event::Timeout(i));
+            }
+        }
+        events
+    }
+
+    /// Whether delivering a message again would leave the state unchanged. Checked on the
+    /// receiving instance alone, which is much cheaper than cloning the whole state.
+    fn is_redundant(&self, event: Event) -> bool {
+        let (to, message) = match event {
+            Event::Deliver(i) => (self.sent[i].1, &self.sent[i].2),
+            _ => return false,
+        };
+        let before = &self.nodes[to][message.instance_id];
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut after = before.clone();
+            after.receive_message(&message.message);
+            let mut messages = VecDeque::new();
+            after.collect_messages_to_send(&mut messages);
+            messages.is_empty() && hash_one(&after) == hash_one(before)
+        })).unwrap_or(false)
+    }
+
+    fn describe(&self, event: Event, config: &ExploreConfig<T>) -> String {
+        match event {
+            Event::Propose(i) => {
+                let (node, instance_id, ref value) = config.proposals[i];
+                format!("node{} proposes {:?} in instance {}", node, value, instance_id)
+            },
+            Event::Deliver(i) => {
+                let (from, to, ref message) = self.sent[i];
+                format!("deliver node{} -> node{}: {:?}", from, to, message)
+            },
+            Event::Timeout(i) => {
+                let (node, ref message, _) = self.timers[i];
+                format!("timeout at node{}: {:?}", node, message)
+            },
+        }
+    }
+
+    fn apply(&mut self, event: Event, config: &ExploreConfig<T>) -> Result<(), String> {
+        match event {
+            Event::Propose(i) => {
+                self.started[i] = true;
+                let (node, instance_id, ref value) = config.proposals[i];
+                self.nodes[node][instance_id].start_proposing(value.clone());
+                self.flush(node, instance_id)
+            },
+            Event::Deliver(i) => {
+                let (_, to, message) = self.sent[i].clone();
+                self.deliver(to, message)
+            },
+            Event::Timeout(i) => {
+                self.timeouts += 1;
+                let (node, message, timeout) = self.timers.remove(i);
+                self.nodes[node][message.instance_id].on_timeout(message.message, timeout)
+                    .map_err(|e| format!("node{} timeout error: {}", node, e))?;
+                self.flush(node, message.instance_id)
+            },
+        }
+    }
+
+    fn deliver(&mut self, to: usize, message: PaxosMessage<T>) -> Result<(), String> {
+        self.nodes[to][message.instance_id].receive_message(&message.message);
+        self.flush(to, message.instance_id)
+    }
+
+    /// Collects the messages sent by an instance. Messages to the sender itself are delivered
+    /// right away, as the server does.
+    fn flush(&mut self, node: usize, instance_id: InstanceID) -> Result<(), String> {
+        let mut messages = VecDeque::new();
+        self.nodes[node][instance_id].collect_messages_to_send(&mut messages);
+        let mut local = Vec::new();
+        for MessageInfo { payload, target, timeout } in messages {
+            let message = match payload {
+                MessagePayload::PaxosMessage(m) => m,
+                other => return Err(format!("node{} sent {:?}", node, other)),
+            };
+            if let Some(timeout) = timeout {
+                self.timers.push((node, message.clone(), timeout));
+            }
+            let targets: Vec<usize> = match target {
+                MessageTarget::Broadcast => (0..self.nodes.len()).collect(),
+                MessageTarget::Node(ref id) => vec![id["node".len()..].parse().unwrap()],
+            };
+            for to in targets {
+                let envelope = (node, to, message.clone());
+                if to == node {
+                    local.push(message.clone());
+                } else if !self.sent.contains(&envelope) {
+                    self.sent.push(envelope);
+                }
+            }
+        }
+        for message in local {
+            self.deliver(node, message)?;
+        }
+        Ok(())
+    }
+
+    fn check(&mut self, config: &ExploreConfig<T>) -> Result<(), String> {
+        let instances = self.nodes.first().map_or(0, |n| n.len());
+        for instance_id in 0..instances {
+            let mut chosen: Option<(usize, T)> = None;
+            for node in 0..self.nodes.len() {
+                let value = match self.nodes[node][instance_id].value() {
+                    Some(v) => v.clone(),
+                    None => continue,
+                };
+                if !config.proposals.iter().any(|p| p.1 == instance_id && p.2 == value) {
+                    return Err(format!("node{} learned {:?} for instance {}, which was never proposed",
+                                       node, value, instance_id));
+                }
+                if let Some((other, ref v)) = chosen {
+                    if *v != value {
+                        return Err(format!("node{} learned {:?} but node{} learned {:?} for instance {}",
+                                           node, value, other, v, instance_id));
+                    }
+                }
+                chosen = Some((node, value));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Explores every execution allowed by `config` and returns the first violation found, if any.
+pub fn explore<T: Clone + Hash + Eq + Debug>(config: &ExploreConfig<T>) -> Report {
+    let mut visited = HashSet::new();
+    let mut steps: Vec<Step> = Vec::new();
+    let mut queue = VecDeque::new();
+    let mut complete = true;
+
+    let initial = State::new(config);
+    visited.insert(initial.fingerprint());
+    steps.push(Step { parent: None, description: String::new(), depth: 0 });
+    queue.push_back((initial, 0));
+
+    while let Some((state, index)) = queue.pop_front() {
+        let depth = steps[index].depth;
+        let events = state.events(config);
+        if depth >= config.max_depth {
+            complete &= events.iter().all(|&e| state.is_redundant(e));
+            continue;
+        }
+        for event in events {
+            if state.is_redundant(event) {
+                continue;
+            }
+            let mut next = state.clone();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                next.apply(event, config)?;
+                next.check(config)
+            })).unwrap_or_else(|e| {
+                let message = e.downcast_ref::<&str>().map(|s| s.to_string())
+                    .or_else(|| e.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "unknown panic".to_string());
+                Err(format!("panic: {}", message))
+            });
+            if let Err(error) = result {
+                let mut trace = vec![state.describe(event, config)];
+                let mut i = index;
+                while let Some(parent) = steps[i].parent {
+                    trace.push(steps[i].description.clone());
+                    i = parent;
+                }
+                trace.reverse();
+                return Report {
+                    states: visited.len(),
+                    complete: false,
+                    violation: Some(Violation { error, trace }),
+                };
+            }
+            if !visited.insert(next.fingerprint()) {
+                continue;
+            }
+            if visited.len() >= config.max_states {
+                return Report { states: visited.len(), complete: false, violation: None };
+            }
+            steps.push(Step { parent: Some(index), description: state.describe(event, config), depth: depth + 1 });
+            queue.push_back((next, steps.len() - 1));
+        }
+    }
+    Report { states: visited.len(), complete, violation: None }
+}
@@ -0,0 +1,177 @@
+//! Linearizability checking of client histories.
+//!
+//! A `History` records when each client operation was invoked and when (if ever) it returned.
+//! `check` searches for a sequential order of the operations that respects real time and that a
+//! sequential `Model` would produce, in the style of Wing & Gong with memoization of visited
+//! states. Operations that never returned may take effect at any point after their invocation,
+//! or not at all. The history is split by `Model::key` first, since operations on different keys
+//! never affect each other.
+
+use crate::errors::*;
+use crate::locker::Operation;
+use crate::paxos::NodeID;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::hash::Hash;
+
+pub trait Model {
+    type Key: Clone + Eq + Hash + Debug;
+    type State: Clone + Eq + Hash;
+    type Input: Clone + Debug;
+    type Output: Clone + Eq + Debug;
+
+    fn init(&self) -> Self::State;
+    /// Applies `input` to `state`, returning the new state and the output.
+    fn step(&self, state: &Self::State, input: &Self::Input) -> (Self::State, Self::Output);
+    /// Operations with different keys are checked independently.
+    fn key(&self, input: &Self::Input) -> Self::Key;
+}
+
+/// Sequential specification of the lock service: one owner per key, only the owner can unlock,
+/// renew or lock again. Leases never run out: the histories it checks must not span one.
+pub struct LockModel;
+
+impl Model for LockModel {
+    type Key = String;
+    type State = Option<NodeID>;
+    type Input = Operation;
+    type Output = bool;
+
+    fn init(&self) -> Option<NodeID> {
+        None
+    }
+
+    fn step(&self, state: &Option<NodeID>, input: &Operation) -> (Option<NodeID>, bool) {
+        match (input, state) {
+            (Operation::Lock(_, node), &None) => (Some(node.clone()), true),
+            (Operation::Lock(_, node), Some(owner)) if owner == node => (state.clone(), true),
+            (Operation::Unlock(_, node), Some(owner)) if owner == node => (None, true),
+            (Operation::Renew(_, node), Some(owner)) if owner == node => (state.clone(), true),
+            _ => (state.clone(), false),
+        }
+    }
+
+    fn key(&self, input: &Operation) -> String {
+        input.key().to_string()
+    }
+}
+
+pub type OperationID = usize;
+
+#[derive(Clone, Debug)]
+pub struct HistoryEntry<I, O> {
+    pub client: String,
+    pub input: I,
+    pub invoked_at: u64,
+    /// Completion time and output, or `None` if the operation never returned.
+    pub returned: Option<(u64, O)>,
+}
+
+/// Invocations and responses in the order they were observed.
+pub struct History<I, O> {
+    entries: Vec<HistoryEntry<I, O>>,
+    time: u64,
+}
+
+impl<I: Clone + Debug, O: Clone + Debug> History<I, O> {
+    pub fn new() -> History<I, O> {
+        History {
+            entries: Vec::new(),
+            time: 0,
+        }
+    }
+
+    pub fn invoke(&mut self, client: &str, input: I) -> OperationID {
+        self.time += 1;
+        self.entries.push(HistoryEntry {
+            client: client.to_string(),
+            input,
+            invoked_at: self.time,
+            returned: None,
+        });
+        self.entries.len() - 1
+    }
+
+    pub fn complete(&mut self, op: OperationID, output: O) {
+        self.time += 1;
+        let entry = &mut self.entries[op];
+        assert!(entry.returned.is_none(), "operation completed twice: {:?}", entry.input);
+        entry.returned = Some((self.time, output));
+    }
+
+    pub fn entries(&self) -> &[HistoryEntry<I, O>] {
+        &self.entries
+    }
+}
+
+impl<I: Clone + Debug, O: Clone + Debug> Default for History<I, O> {
+    fn default() -> History<I, O> {
+        History::new()
+    }
+}
+
+/// Checks that `history` is linearizable with respect to `model`.
+pub fn check<M: Model>(model: &M, history: &History<M::Input, M::Output>) -> Result<()> {
+    let mut partitions = HashMap::new();
+    for entry in history.entries() {
+        partitions.entry(model.key(&entry.input)).or_insert_with(Vec::new).push(entry);
+    }
+    for (key, entries) in partitions {
+        let mut search = Search {
+            model,
+            entries: &entries,
+            done: vec![false; entries.len()],
+            visited: HashSet::new(),
+        };
+        let state = model.init();
+        if !search.run(&state) {
+            let ops: Vec<_> = entries.iter()
+                .map(|e| format!("{} {:?} -> {:?}", e.client, e.input, e.returned.as_ref().map(|r| &r.1)))
+                .collect();
+            bail!(ErrorKind::NotLinearizable(format!("key {:?}: {:#?}", key, ops)));
+        }
+    }
+    Ok(())
+}
+
+struct Search<'a, M: Model + 'a> {
+    model: &'a M,
+    entries: &'a [&'a HistoryEntry<M::Input, M::Output>],
+    done: Vec<bool>,
+    visited: HashSet<(Vec<bool>, M::State)>,
+}
+
+impl<'a, M: Model> Search<'a, M> {
+    fn run(&mut self, state: &M::State) -> bool {
+        // every operation that returned before this point must be linearized before it
+        let deadline = self.entries.iter().zip(&self.done)
+            .filter(|&(_, &done)| !done)
+            .filter_map(|(e, _)| e.returned.as_ref().map(|r| r.0))
+            .min();
+        let deadline = match deadline {
+            Some(t) => t,
+            None => return true,  // only pending operations are left, and they may never happen
+        };
+        if !self.visited.insert((self.done.clone(), state.clone())) {
+            return false;
+        }
+        for i in 0..self.entries.len() {
+            if self.done[i] || self.entries[i].invoked_at > deadline {
+                continue;
+            }
+            let (next, output) = self.model.step(state, &self.entries[i].input);
+            if let Some((_, ref expected)) = self.entries[i].returned {
+                if output != *expected {
+                    continue;
+                }
+            }
+            self.done[i] = true;
+            if self.run(&next) {
+                return true;
+            }
+            self.done[i] = false;
+        }
+        false
+    }
+}
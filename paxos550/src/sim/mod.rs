@@ -0,0 +1,362 @@
+//! Deterministic in-memory network simulator.
+//!
+//! Runs a cluster of `PaxosInstance`s over a virtual network driven by a seeded RNG and a
+//! virtual clock. Messages collected through `PaxosInstance::collect_messages_to_send` are
+//! scheduled for delivery with a random delay and can be dropped, duplicated or blocked by a
+//! partition. Timeouts fire on the virtual clock. Every time a node learns a value the simulator
+//! checks that no other node has learned a different value for the same instance.
+//!
+//! Each instance gets its own random source derived from the simulator's seed, so a run is fully
+//! determined by its seed and the calls made on the `Simulator`.
+
+pub mod explore;
+pub mod linearizability;
+
+use crate::paxos::*;
+use crate::errors::*;
+use crate::network::message::{MessageInfo, MessagePayload, MessageTarget};
+use crate::env::{self, ManualClock};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::time::Duration;
+
+#[derive(Clone, Debug)]
+pub struct SimConfig {
+    /// Probability that a message between two different nodes is lost.
+    pub drop_rate: f64,
+    /// Probability that a message between two different nodes is delivered twice.
+    pub duplicate_rate: f64,
+    /// Delivery delays are drawn uniformly from `[min_delay, max_delay]`, which also reorders
+    /// messages that are in flight at the same time.
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    /// Initial timeout handed to every `PaxosInstance`.
+    pub timeout: Duration,
+}
+
+impl Default for SimConfig {
+    fn default() -> SimConfig {
+        SimConfig {
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+            timeout: Duration::from_millis(100),
+        }
+    }
+}
+
+enum Event<T> {
+    Deliver { from: usize, to: usize, message: PaxosMessage<T> },
+    Timeout { node: usize, message: PaxosMessage<T>, timeout: Duration },
+}
+
+struct Scheduled<T> {
+    time: Duration,
+    seq: u64,
+    event: Event<T>,
+}
+
+impl<T> PartialEq for Scheduled<T> {
+    fn eq(&self, other: &Scheduled<T>) -> bool {
+        self.time == other.time && self.seq == other.seq
+    }
+}
+
+impl<T> Eq for Scheduled<T> {}
+
+impl<T> PartialOrd for Scheduled<T> {
+    fn partial_cmp(&self, other: &Scheduled<T>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Scheduled<T> {
+    fn cmp(&self, other: &Scheduled<T>) -> Ordering {
+        // reversed so that `BinaryHeap` pops the earliest event first
+        (other.time, other.seq).cmp(&(self.time, self.seq))
+    }
+}
+
+struct SimNode<T> {
+    id: NodeID,
+    instances: Vec<PaxosInstance<T>>,
+    learned: HashMap<InstanceID, T>,
+    paused: bool,
+    group: usize,
+}
+
+pub struct Simulator<T> {
+    config: SimConfig,
+    rng: StdRng,
+    clock: ManualClock,
+    seq: u64,
+    queue: BinaryHeap<Scheduled<T>>,
+    nodes: Vec<SimNode<T>>,
+    node_index: HashMap<NodeID, usize>,
+    chosen: HashMap<InstanceID, T>,
+    trace: Vec<String>,
+}
+
+impl<T: Clone + Hash + Eq + Debug> Simulator<T> {
+    pub fn new(cluster_size: usize, config: SimConfig, seed: u64) -> Simulator<T> {
+        let nodes: Vec<_> = (0..cluster_size).map(|i| SimNode {
+            id: format!("node{}", i),
+            instances: Vec::new(),
+            learned: HashMap::new(),
+            paused: false,
+            group: 0,
+        }).collect();
+        let node_index = nodes.iter().enumerate().map(|(i, n)| (n.id.clone(), i)).collect();
+        Simulator {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            clock: ManualClock::new(),
+            seq: 0,
+            queue: BinaryHeap::new(),
+            nodes,
+            node_index,
+            chosen: HashMap::new(),
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn node_id(&self, node: usize) -> &NodeID {
+        &self.nodes[node].id
+    }
+
+    /// Current virtual time since the simulation started.
+    pub fn now(&self) -> Duration {
+        self.clock.elapsed()
+    }
+
+    /// The virtual clock, for components that run alongside the simulated cluster.
+    pub fn clock(&self) -> &ManualClock {
+        &self.clock
+    }
+
+    /// Every delivery, drop and timeout handled so far, in order.
+    pub fn trace(&self) -> &[String] {
+        &self.trace
+    }
+
+    /// Starts proposing `value` on `node` in a new instance, the same way the server handles a
+    /// client request. Returns the instance ID.
+    pub fn propose(&mut self, node: usize, value: T) -> InstanceID {
+        let instance_id = self.nodes[node].instances.len();
+        self.propose_at(node, instance_id, value);
+        instance_id
+    }
+
+    /// Starts proposing `value` on `node` in the given instance.
+    pub fn propose_at(&mut self, node: usize, instance_id: InstanceID, value: T) {
+        self.trace.push(format!("{:?} {} propose {} {:?}",
+                                self.now(), self.nodes[node].id, instance_id, value));
+        self.ensure_instance(node, instance_id);
+        self.nodes[node].instances[instance_id].start_proposing(value);
+        self.flush(node, instance_id);
+    }
+
+    /// Splits the cluster so that messages only flow between nodes in the same group. Nodes that
+    /// are not listed form a group of their own.
+    pub fn partition(&mut self, groups: &[&[usize]]) {
+        let isolated = groups.len() + 1;
+        for (i, node) in self.nodes.iter_mut().enumerate() {
+            node.group = groups.iter().position(|g| g.contains(&i)).unwrap_or(isolated + i);
+        }
+        self.trace.push(format!("{:?} partition {:?}", self.now(), groups));
+    }
+
+    pub fn heal(&mut self) {
+        for node in &mut self.nodes {
+            node.group = 0;
+        }
+        self.trace.push(format!("{:?} heal", self.now()));
+    }
+
+    /// Stops a node: it neither receives messages nor fires timeouts until resumed. Its state is
+    /// kept, since the servers have no recovery protocol.
+    pub fn pause(&mut self, node: usize) {
+        self.nodes[node].paused = true;
+        self.trace.push(format!("{:?} pause {}", self.now(), self.nodes[node].id));
+    }
+
+    pub fn resume(&mut self, node: usize) {
+        self.nodes[node].paused = false;
+        self.trace.push(format!("{:?} resume {}", self.now(), self.nodes[node].id));
+    }
+
+    /// The value `node` has learned for `instance_id`, if any.
+    pub fn learned(&self, node: usize, instance_id: InstanceID) -> Option<&T> {
+        self.nodes[node].learned.get(&instance_id)
+    }
+
+    /// The value chosen for `instance_id`, as learned by at least one node.
+    pub fn chosen(&self, instance_id: InstanceID) -> Option<&T> {
+        self.chosen.get(&instance_id)
+    }
+
+    /// Number of instances `node` knows about.
+    pub fn total_instances(&self, node: usize) -> usize {
+        self.nodes[node].instances.len()
+    }
+
+    /// Processes the next event. Returns `false` if there is nothing left to do.
+    pub fn step(&mut self) -> Result<bool> {
+        let scheduled = match self.queue.pop() {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+        self.clock.set_elapsed(scheduled.time);
+        match scheduled.event {
+            Event::Deliver { from, to, message } => {
+                if self.nodes[to].paused || self.nodes[from].group != self.nodes[to].group {
+                    self.trace.push(format!("{:?} {} -> {} blocked {:?}",
+                                            self.now(), self.nodes[from].id, self.nodes[to].id, message));
+                    return Ok(true);
+                }
+                self.trace.push(format!("{:?} {} -> {} {:?}",
+                                        self.now(), self.nodes[from].id, self.nodes[to].id, message));
+                self.ensure_instance(to, message.instance_id);
+                let learned = self.nodes[to].instances[message.instance_id].receive_message(&message.message);
+                self.flush(to, message.instance_id);
+                if let Some(v) = learned {
+                    self.learn(to, message.instance_id, v)?;
+                }
+            },
+            Event::Timeout { node, message, timeout } => {
+                if self.nodes[node].paused {
+                    // the timer fires once the node is resumed
+                    let time = self.now() + timeout;
+                    self.schedule(time, Event::Timeout { node, message, timeout });
+                    return Ok(true);
+                }
+                self.trace.push(format!("{:?} {} timeout {:?}", self.now(), self.nodes[node].id, message));
+                self.nodes[node].instances[message.instance_id].on_timeout(message.message, timeout)?;
+                self.flush(node, message.instance_id);
+            },
+        }
+        Ok(true)
+    }
+
+    /// Runs the simulation for `duration` of virtual time.
+    pub fn run_for(&mut self, duration: Duration) -> Result<()> {
+        let deadline = self.now() + duration;
+        while self.queue.peek().is_some_and(|s| s.time <= deadline) {
+            self.step()?;
+        }
+        self.clock.set_elapsed(deadline);
+        Ok(())
+    }
+
+    /// Runs the simulation until `done` returns `true` or until `limit` of virtual time has
+    /// passed. Returns whether `done` was satisfied.
+    pub fn run_until<F>(&mut self, limit: Duration, mut done: F) -> Result<bool>
+        where F: FnMut(&Simulator<T>) -> bool
+    {
+        let deadline = self.now() + limit;
+        loop {
+            if done(self) {
+                return Ok(true);
+            }
+            if self.queue.peek().is_none_or(|s| s.time > deadline) {
+                self.clock.set_elapsed(deadline);
+                return Ok(false);
+            }
+            self.step()?;
+        }
+    }
+
+    /// Runs the simulation until every node has learned a value for each of the given instances,
+    /// or until `limit` of virtual time has passed. Returns whether the instances were decided.
+    pub fn run_until_decided(&mut self, instances: &[InstanceID], limit: Duration) -> Result<bool> {
+        self.run_until(limit, |sim| {
+            instances.iter().all(|&i| (0..sim.cluster_size()).all(|n| sim.learned(n, i).is_some()))
+        })
+    }
+
+    fn ensure_instance(&mut self, node: usize, instance_id: InstanceID) {
+        let cluster_size = self.nodes.len();
+        let timeout = self.config.timeout;
+        for id in self.nodes[node].instances.len() ..= instance_id {
+            let random = env::seeded_random(self.rng.gen());
+            let node = &mut self.nodes[node];
+            node.instances.push(PaxosInstance::with_random(node.id.clone(), id, cluster_size, timeout, random));
+        }
+    }
+
+    fn learn(&mut self, node: usize, instance_id: InstanceID, value: T) -> Result<()> {
+        self.trace.push(format!("{:?} {} learned {} {:?}", self.now(), self.nodes[node].id, instance_id, value));
+        if let Some(chosen) = self.chosen.get(&instance_id) {
+            if *chosen != value {
+                let detail = format!("{} learned {:?} but {:?} was chosen",
+                                     self.nodes[node].id, value, chosen);
+                bail!(ErrorKind::SafetyViolation(instance_id, detail));
+            }
+        }
+        self.chosen.entry(instance_id).or_insert_with(|| value.clone());
+        self.nodes[node].learned.insert(instance_id, value);
+        Ok(())
+    }
+
+    fn flush(&mut self, node: usize, instance_id: InstanceID) {
+        let mut messages = VecDeque::new();
+        self.nodes[node].instances[instance_id].collect_messages_to_send(&mut messages);
+        for info in messages {
+            self.dispatch(node, info);
+        }
+    }
+
+    fn dispatch(&mut self, from: usize, info: MessageInfo<T>) {
+        let message = match info.payload {
+            MessagePayload::PaxosMessage(m) => m,
+            _ => return,
+        };
+        if let Some(timeout) = info.timeout {
+            let time = self.now() + timeout;
+            self.schedule(time, Event::Timeout { node: from, message: message.clone(), timeout });
+        }
+        let targets: Vec<usize> = match info.target {
+            MessageTarget::Broadcast => (0..self.nodes.len()).collect(),
+            MessageTarget::Node(ref id) => vec![self.node_index[id]],
+        };
+        for to in targets {
+            if to == from {
+                // local delivery never goes through the network
+                let time = self.now();
+                self.schedule(time, Event::Deliver { from, to, message: message.clone() });
+                continue;
+            }
+            if self.rng.gen_bool(self.config.drop_rate) {
+                self.trace.push(format!("{:?} {} -> {} dropped {:?}",
+                                        self.now(), self.nodes[from].id, self.nodes[to].id, message));
+                continue;
+            }
+            let copies = if self.rng.gen_bool(self.config.duplicate_rate) { 2 } else { 1 };
+            for _ in 0..copies {
+                let time = self.now() + self.random_delay();
+                self.schedule(time, Event::Deliver { from, to, message: message.clone() });
+            }
+        }
+    }
+
+    fn random_delay(&mut self) -> Duration {
+        let min = self.config.min_delay.as_micros() as u64;
+        let max = self.config.max_delay.as_micros() as u64;
+        Duration::from_micros(self.rng.gen_range(min, max + 1))
+    }
+
+    fn schedule(&mut self, time: Duration, event: Event<T>) {
+        self.seq += 1;
+        self.queue.push(Scheduled { time, seq: self.seq, event });
+    }
+}
@@ -0,0 +1,475 @@
+extern crate paxos550;
+extern crate serde_yaml;
+extern crate tokio;
+
+use paxos550::client::{LockClient, LockClientBuilder};
+use paxos550::env::ManualClock;
+use paxos550::errors::{ErrorKind, Result};
+use paxos550::locker::{LogEntry, Operation};
+use paxos550::message::{Applied, MessagePayload, Query};
+use paxos550::server::*;
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::{SocketAddr, UdpSocket};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Starts `count` servers that know each other. `configure` can change the builder of each.
+fn cluster<F: Fn(ServerBuilder) -> ServerBuilder>(count: usize, configure: F) -> Vec<ServerHandle> {
+    let sockets: Vec<_> = (0..count).map(|_| UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+    let addrs: Vec<SocketAddr> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+    drop(sockets);
+    (0..count).map(|i| {
+        let builder = ServerBuilder::new(format!("node{}", i), addrs[i]).timeout(Duration::from_millis(100));
+        let mut builder = configure(builder);
+        for (j, &addr) in addrs.iter().enumerate() {
+            if j != i {
+                builder = builder.peer(format!("node{}", j), addr);
+            }
+        }
+        let mut server = builder.build().unwrap();
+        server.start().unwrap();
+        server
+    }).collect()
+}
+
+fn client(id: &str, servers: &[ServerHandle]) -> LockClientBuilder {
+    servers.iter().fold(LockClientBuilder::new(id.to_string()), |builder, server| {
+        builder.server(server.node_id().clone(), server.local_addr())
+    })
+}
+
+fn wait_until<F: FnMut() -> bool>(mut condition: F) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// An address nothing answers on.
+fn silent_addr() -> (UdpSocket, SocketAddr) {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    (socket, addr)
+}
+
+fn error<T>(result: Result<T>) -> ErrorKind {
+    match result {
+        Ok(_) => panic!("no error"),
+        Err(e) => e.0,
+    }
+}
+
+#[test]
+fn clients_take_turns_on_a_lock() {
+    let servers = cluster(3, |b| b);
+    // no renewals in the log
+    let mut a = client("a", &servers).renew_interval(Duration::from_secs(60)).build().unwrap();
+    let mut b = client("b", &servers).lock_timeout(Duration::from_millis(300)).build().unwrap();
+
+    let guard = a.lock("k").unwrap();
+    assert_eq!(guard.key(), "k");
+    assert!(b.try_lock("k").unwrap().is_none());
+    assert!(matches!(error(b.lock("k")), ErrorKind::LockHeld(key) if key == "k"));
+    assert!(matches!(error(b.renew("k")), ErrorKind::LockNotHeld(_)));
+    assert!(matches!(error(b.unlock("k")), ErrorKind::LockNotHeld(_)));
+    a.renew("k").unwrap();
+
+    let locks = b.list_locks(Query::default()).unwrap();
+    assert_eq!(locks.items, vec![("k".to_string(), "a".to_string())]);
+    guard.unlock().unwrap();
+    let _guard = b.try_lock("k").unwrap().expect("not locked");
+
+    // b retried its lock in between
+    let log = a.log(Query { key: Some("k".to_string()), ..Query::default() }).unwrap();
+    let ops: Vec<_> = log.items.iter().filter(|(_, entry)| entry.valid).map(|(_, entry)| entry.op.clone()).collect();
+    assert_eq!(ops, vec![
+        Operation::Lock("k".to_string(), "a".to_string()),
+        Operation::Renew("k".to_string(), "a".to_string()),
+        Operation::Unlock("k".to_string(), "a".to_string()),
+        Operation::Lock("k".to_string(), "b".to_string()),
+    ]);
+    let page = a.log(Query { key: Some("k".to_string()), offset: 1, limit: Some(1) }).unwrap();
+    assert_eq!(page.items, vec![log.items[1].clone()]);
+    assert_eq!((&page.items[0].1.op, page.items[0].1.valid), (&Operation::Lock("k".to_string(), "b".to_string()), false));
+}
+
+#[test]
+fn locks_expire_unless_renewed() {
+    let clock = ManualClock::new();
+    let mut server = ServerBuilder::new("node0".to_string(), "127.0.0.1:0".parse().unwrap())
+        .lease(Duration::from_secs(10))
+        .clock(Box::new(clock.clone()))
+        .batch_size(1)
+        .build().unwrap();
+    server.start().unwrap();
+    let servers = [server];
+    let mut a = client("a", &servers).renew_interval(Duration::from_secs(600)).build().unwrap();
+    let mut b = client("b", &servers).build().unwrap();
+
+    let _guard = a.lock("k").unwrap();
+    clock.advance(Duration::from_secs(6));
+    a.renew("k").unwrap();
+    clock.advance(Duration::from_secs(6));
+    assert!(b.try_lock("k").unwrap().is_none());
+    clock.advance(Duration::from_secs(6));
+    assert!(b.try_lock("k").unwrap().is_some());
+    assert!(matches!(error(a.renew("k")), ErrorKind::LockNotHeld(_)));
+}
+
+fn lock_both(client: &mut LockClient, first: &str, second: &str) -> Result<()> {
+    let _first = client.lock(first)?;
+    let _second = client.try_lock(second)?.ok_or("second lock taken")?;
+    Ok(())
+}
+
+#[test]
+fn guards_unlock_when_dropped() {
+    let servers = cluster(3, |b| b);
+    let mut a = client("a", &servers).build().unwrap();
+    let mut b = client("b", &servers).build().unwrap();
+
+    let taken = b.lock("y").unwrap();
+    assert!(lock_both(&mut a, "x", "y").is_err());
+    taken.unlock().unwrap();
+    lock_both(&mut a, "x", "y").unwrap();
+    assert!(b.list_locks(Query::default()).unwrap().items.is_empty());
+}
+
+#[tokio::test]
+async fn blocking_clients_do_not_panic_in_async_code() {
+    let servers = cluster(3, |b| b);
+    let mut a = client("a", &servers).build().unwrap();
+    let mut b = client("b", &servers).build().unwrap();
+    // unlocks in the background
+    drop(a.lock("k").unwrap());
+    wait_until(|| b.try_lock("k").unwrap().is_some());
+}
+
+#[test]
+fn guards_renew_their_lock_until_it_is_lost() {
+    let servers = cluster(3, |b| b);
+    let mut a = client("a", &servers).renew_interval(Duration::from_millis(50)).build().unwrap();
+    let guard = a.lock("k").unwrap();
+    let renewal = Operation::Renew("k".to_string(), "a".to_string());
+    wait_until(|| {
+        let log = a.log(Query::default()).unwrap();
+        log.items.iter().filter(|(_, entry)| entry.op == renewal && entry.valid).count() >= 2
+    });
+    assert!(!guard.is_lost());
+
+    // released behind the guard's back
+    a.unlock("k").unwrap();
+    wait_until(|| guard.is_lost());
+    assert!(matches!(error(guard.unlock()), ErrorKind::LockNotHeld(_)));
+}
+
+#[test]
+fn requests_fail_over_to_the_next_server() {
+    let servers = cluster(3, |b| b);
+    let (_silent, addr) = silent_addr();
+    // whichever server it starts with, the client gets past the silent one
+    let mut client = client("a", &servers)
+        .server("silent".to_string(), addr)
+        .timeout(Duration::from_millis(200))
+        .build()
+        .unwrap();
+    for _ in 0..4 {
+        client.lock("k").unwrap().unlock().unwrap();
+    }
+
+    let mut alone = LockClientBuilder::new("b".to_string())
+        .server("silent".to_string(), addr)
+        .timeout(Duration::from_millis(50))
+        .attempts(2)
+        .build()
+        .unwrap();
+    assert!(matches!(error(alone.lock("k")), ErrorKind::Unreachable(_)));
+}
+
+#[test]
+fn clients_only_take_the_reply_to_their_request() {
+    let (server, addr) = silent_addr();
+    let mut client = LockClientBuilder::new("a".to_string())
+        .server("node0".to_string(), addr)
+        .timeout(Duration::from_millis(500))
+        .attempts(1)
+        .build().unwrap();
+    let replier = thread::spawn(move || {
+        let mut buf = vec![0u8; 65536];
+        let (size, client) = server.recv_from(&mut buf).unwrap();
+        let (request, op) = match serde_yaml::from_slice(&buf[..size]).unwrap() {
+            MessagePayload::<Operation>::LockerMessage(request, op) => (request, op),
+            message => panic!("unexpected request {:?}", message),
+        };
+        let reply = |request, valid| {
+            let entry = LogEntry { op: op.clone(), valid, time_ms: 0 };
+            serde_yaml::to_vec(&Applied { request, entry }).unwrap()
+        };
+        // a reply from another address, and one to another request, are not the answer
+        UdpSocket::bind("127.0.0.1:0").unwrap().send_to(&reply(request, true), client).unwrap();
+        server.send_to(&reply(request.wrapping_add(1), true), client).unwrap();
+        server.send_to(&reply(request, false), client).unwrap();
+    });
+    assert!(matches!(error(client.renew("k")), ErrorKind::LockNotHeld(_)));
+    replier.join().unwrap();
+}
+
+#[test]
+fn clients_follow_redirects_to_the_leader() {
+    let mut servers = cluster(3, |b| b.redirect_clients(true));
+    wait_until(|| servers.iter().all(|s| s.status().leader == Some("node0".to_string())));
+
+    // only knows a server that is not the leader
+    let mut client = LockClientBuilder::new("a".to_string())
+        .server("node2".to_string(), servers[2].local_addr())
+        .timeout(Duration::from_millis(200))
+        .build()
+        .unwrap();
+    client.lock("k").unwrap().leak();
+    assert_eq!(client.server(), "node0");
+
+    servers[0].shutdown().unwrap();
+    wait_until(|| servers[1..].iter().all(|s| s.status().leader == Some("node1".to_string())));
+    client.unlock("k").unwrap();
+    assert_eq!(client.server(), "node1");
+}
+
+#[tokio::test]
+async fn async_clients_lock_from_tasks() {
+    let servers = cluster(3, |b| b);
+    let mut a = client("a", &servers).build_async().await.unwrap();
+    let mut b = client("b", &servers).renew_interval(Duration::from_millis(50)).build_async().await.unwrap();
+    let guard = a.lock("k").await.unwrap();
+    let waiter = tokio::spawn(async move {
+        let guard = b.lock("k").await.unwrap();
+        (b, guard)
+    });
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    // unlocks in the background
+    drop(guard);
+    let (mut b, mut guard) = waiter.await.unwrap();
+
+    b.unlock("k").await.unwrap();
+    tokio::time::timeout(Duration::from_secs(10), guard.lost()).await.unwrap();
+    assert!(guard.is_lost());
+}
+
+/// Runs the client binary and returns its output and exit code.
+fn run_client(servers: &[ServerHandle], id: &str, args: &[&str]) -> (String, i32) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_client"));
+    command.args(["--id", id]);
+    for server in servers {
+        command.arg("--server").arg(format!("{}={}", server.node_id(), server.local_addr()));
+    }
+    let output = command.args(args).output().unwrap();
+    (String::from_utf8(output.stdout).unwrap(), output.status.code().unwrap())
+}
+
+#[test]
+fn commands_print_json_and_exit_with_their_result() {
+    let servers = cluster(3, |b| b);
+    assert_eq!(run_client(&servers, "a", &["lock", "k"]),
+               (r#"{"command":"lock","key":"k","result":"acquired"}"#.to_string() + "\n", 0));
+    assert_eq!(run_client(&servers, "b", &["lock", "k"]),
+               (r#"{"command":"lock","key":"k","owner":"a","result":"held"}"#.to_string() + "\n", 1));
+    assert_eq!(run_client(&servers, "b", &["unlock", "k"]).1, 1);
+
+    assert_eq!(run_client(&servers, "a", &["lock", "k", "--no-such-flag"]), (String::new(), 3));
+
+    let (_silent, addr) = silent_addr();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_client"));
+    command.args(["--id", "a", "--server", &format!("silent={}", addr), "--timeout", "50", "locks"]);
+    let output = command.output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stdout).contains(r#""result":"unreachable""#));
+}
+
+#[test]
+fn denied_locks_exit_with_1_when_the_owner_cannot_be_asked() {
+    let (server, addr) = silent_addr();
+    // denies the lock, and then does not answer who holds it
+    let replier = thread::spawn(move || {
+        let mut buf = vec![0u8; 65536];
+        let (size, client) = server.recv_from(&mut buf).unwrap();
+        let (request, op) = match serde_yaml::from_slice(&buf[..size]).unwrap() {
+            MessagePayload::<Operation>::LockerMessage(request, op) => (request, op),
+            message => panic!("unexpected request {:?}", message),
+        };
+        let entry = LogEntry { op, valid: false, time_ms: 0 };
+        server.send_to(&serde_yaml::to_vec(&Applied { request, entry }).unwrap(), client).unwrap();
+        server
+    });
+    let output = Command::new(env!("CARGO_BIN_EXE_client"))
+        .args(["--id", "b", "--server", &format!("node0={}", addr), "--timeout", "50", "lock", "k"])
+        .output()
+        .unwrap();
+    assert_eq!((String::from_utf8(output.stdout).unwrap(), output.status.code().unwrap()),
+               (r#"{"command":"lock","key":"k","owner":null,"result":"held"}"#.to_string() + "\n", 1));
+    replier.join().unwrap();
+}
+
+#[test]
+fn shell_commands_fail_over_and_time_out() {
+    let servers = cluster(3, |b| b);
+    let (_silent, addr) = silent_addr();
+    let mut command = Command::new(env!("CARGO_BIN_EXE_client"));
+    command.args(["--id", "a", "--timeout", "200", "--server", &format!("silent={}", addr)]);
+    for server in &servers {
+        command.arg("--server").arg(format!("{}={}", server.node_id(), server.local_addr()));
+    }
+    let mut shell = command.stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().unwrap();
+    shell.stdin.take().unwrap().write_all(b"LOCK k\nLOCKS\nTOTAL silent\nUNLOCK k\nLOCKS node1\n").unwrap();
+    let output = String::from_utf8(shell.wait_with_output().unwrap().stdout).unwrap();
+    assert!(output.contains("locked k on node"), "{}", output);
+    assert!(output.contains("k\t=>\ta"), "{}", output);
+    assert!(output.contains("no reply after 1 attempts"), "{}", output);
+    assert!(output.contains("unlocked k"), "{}", output);
+    assert!(output.contains("Locks from node1:"), "{}", output);
+}
+
+#[test]
+fn bench_reports_what_its_clients_did() {
+    let servers = cluster(3, |b| b);
+    let mut command = Command::new(env!("CARGO_BIN_EXE_bench"));
+    for server in &servers {
+        command.arg("--server").arg(format!("{}={}", server.node_id(), server.local_addr()));
+    }
+    let output = command.args(["--clients", "3", "--keys", "2", "--workload", "zipf", "--duration", "1"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let report = String::from_utf8(output.stdout).unwrap();
+    let acquired: usize = report.lines()
+        .find_map(|line| line.strip_prefix("locks"))
+        .and_then(|line| line.split_whitespace().next())
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(acquired > 0, "{}", report);
+    assert!(report.contains("lock latency    p50"), "{}", report);
+    assert!(!report.contains("instances       0 "), "{}", report);
+
+    for duration in ["-1", "NaN", "inf", "0"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_bench"))
+            .args(["--server", &format!("node0={}", servers[0].local_addr()), &format!("--duration={}", duration)])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--duration must be a positive number of seconds"),
+                "{}", String::from_utf8_lossy(&output.stderr));
+    }
+}
+
+/// A faulty replica, on which anyone can unlock any lock.
+#[derive(Default)]
+struct LaxLocker {
+    locks: HashMap<String, String>,
+    log: Vec<LogEntry>,
+}
+
+impl StateMachine for LaxLocker {
+    fn apply(&mut self, request: &Request) -> bool {
+        let Request { op, time_ms, .. } = request;
+        let valid = match op {
+            Operation::Lock(key, owner) if !self.locks.contains_key(key) => {
+                self.locks.insert(key.clone(), owner.clone());
+                true
+            },
+            Operation::Lock(..) => false,
+            Operation::Unlock(key, _) => self.locks.remove(key).is_some(),
+            Operation::Renew(key, owner) => self.locks.get(key) == Some(owner),
+        };
+        self.log.push(LogEntry { op: op.clone(), valid, time_ms: *time_ms });
+        valid
+    }
+
+    fn log(&self) -> &Vec<LogEntry> {
+        &self.log
+    }
+
+    fn locks(&self) -> &HashMap<String, String> {
+        &self.locks
+    }
+}
+
+fn run_logdiff(servers: &[ServerHandle], args: &[&str]) -> (String, i32) {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_logdiff"));
+    for server in servers {
+        command.arg("--server").arg(format!("{}={}", server.node_id(), server.local_addr()));
+    }
+    let output = command.args(args).output().unwrap();
+    (String::from_utf8(output.stdout).unwrap(), output.status.code().unwrap())
+}
+
+#[test]
+fn logdiff_finds_where_a_replica_diverges() {
+    let first = Cell::new(true);
+    let servers = cluster(3, |builder| match first.replace(false) {
+        true => builder.state_machine(Box::<LaxLocker>::default()),
+        false => builder,
+    });
+    let mut digests: Vec<_> = servers.iter()
+        .map(|server| client("d", std::slice::from_ref(server)).build().unwrap())
+        .collect();
+    let mut entries = |count: usize| {
+        wait_until(|| digests.iter_mut().all(|client| client.digest(0, 0).unwrap().entries == count));
+    };
+    let mut a = client("a", &servers[1..2]).build().unwrap();
+    a.lock("k").unwrap().leak();
+    entries(1);
+    let (report, code) = run_logdiff(&servers, &[]);
+    assert_eq!(code, 0, "{}", report);
+    assert!(report.contains("The logs agree on their first 1 entries."), "{}", report);
+
+    // only node0 lets b take the lock of a
+    let mut b = client("b", &servers[1..2]).build().unwrap();
+    assert!(matches!(error(b.unlock("k")), ErrorKind::LockNotHeld(_)));
+    // the logs agree again after the entry they diverge on
+    a.lock("j").unwrap().leak();
+    entries(3);
+    let (report, code) = run_logdiff(&servers, &["--replay"]);
+    assert_eq!(code, 1, "{}", report);
+    assert!(report.contains("The logs diverge at entry 1:\n    \
+                             node0: Unlock(\"k\", \"b\"), valid\n    \
+                             node1, node2: Unlock(\"k\", \"b\"), invalid\n"), "{}", report);
+    assert!(report.contains("node0 logged entry 1 as Unlock(\"k\", \"b\"), valid, but it applies as"), "{}", report);
+    assert!(report.contains("The log of node1 replays to its locks."), "{}", report);
+}
+
+#[test]
+fn logdiff_replays_logs_with_the_lease_of_the_servers() {
+    let servers = cluster(3, |builder| builder.lease(Duration::from_millis(200)));
+    let mut a = client("a", &servers[..1]).build().unwrap();
+    a.try_acquire("k").unwrap();
+    // the lock of a has run out when b takes it
+    thread::sleep(Duration::from_millis(400));
+    let mut b = client("b", &servers[..1]).build().unwrap();
+    b.try_acquire("k").unwrap();
+    let mut digests: Vec<_> = servers.iter()
+        .map(|server| client("d", std::slice::from_ref(server)).build().unwrap())
+        .collect();
+    wait_until(|| digests.iter_mut().all(|client| client.digest(0, 0).unwrap().entries == 2));
+
+    // with the default lease of 10 seconds, a still holds k
+    let (report, code) = run_logdiff(&servers, &["--replay"]);
+    assert_eq!(code, 1, "{}", report);
+    assert!(report.contains("node0 logged entry 1 as Lock(\"k\", \"b\"), valid, but it applies as"), "{}", report);
+    let (report, code) = run_logdiff(&servers, &["--replay", "--lease", "200"]);
+    assert_eq!(code, 0, "{}", report);
+    assert!(report.contains("The log of node0 replays to its locks."), "{}", report);
+
+    let nodes: String = servers.iter()
+        .map(|server| format!("  - id: {}\n    address: {}\n", server.node_id(), server.local_addr()))
+        .collect();
+    let path = std::env::temp_dir().join(format!("paxos550-logdiff-test-{}.yaml", std::process::id()));
+    std::fs::write(&path, format!("nodes:\n{}server:\n  lease_ms: 200\n", nodes)).unwrap();
+    let (report, code) = run_logdiff(&[], &["--replay", "--config", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(code, 0, "{}", report);
+    assert_eq!(run_logdiff(&servers, &["--replay", "--lease", "0"]).1, 3);
+}
@@ -0,0 +1,119 @@
+extern crate paxos550;
+extern crate serde_yaml;
+
+use paxos550::client::{LockClient, LockClientBuilder};
+use paxos550::cluster::{Launch, LocalCluster, LocalClusterBuilder};
+use paxos550::config::{Config, ServerConfig};
+use paxos550::errors::ErrorKind;
+use paxos550::server::{FaultPolicy, LinkFaults};
+
+use std::path::PathBuf;
+use std::time::Duration;
+
+fn fast() -> ServerConfig {
+    ServerConfig { timeout_ms: 100, max_backoff_ms: 500, ..ServerConfig::default() }
+}
+
+/// A client that only knows `servers` of the cluster.
+fn client(cluster: &LocalCluster, id: &str, servers: &[&str]) -> LockClient {
+    servers.iter().fold(LockClientBuilder::new(id.to_string()), |builder, server| {
+        builder.server(server.to_string(), cluster.address(server).unwrap())
+    }).timeout(Duration::from_millis(300)).attempts(2).build().unwrap()
+}
+
+#[test]
+fn partitions_leave_the_minority_without_progress() {
+    let mut cluster = LocalClusterBuilder::new(3).server_config(fast()).start().unwrap();
+    let config = Config::load(cluster.config_path()).unwrap();
+    assert_eq!(config.members(), cluster.config().members());
+    assert_eq!(cluster.ids(), vec!["server1", "server2", "server3"]);
+
+    cluster.partition(&[vec!["server1".to_string()]]).unwrap();
+    assert_eq!(cluster.disconnected("server1"), ["server2", "server3"]);
+    assert_eq!(cluster.disconnected("server2"), ["server1"]);
+    let mut alone = client(&cluster, "a", &["server1"]);
+    let mut majority = client(&cluster, "b", &["server2", "server3"]);
+    assert!(matches!(alone.lock("x").err().map(|e| e.0), Some(ErrorKind::Unreachable(_))));
+    majority.lock("y").unwrap().unlock().unwrap();
+
+    cluster.heal().unwrap();
+    assert!(cluster.disconnected("server1").is_empty());
+    cluster.partition(&[vec!["server1".to_string(), "server2".to_string()]]).unwrap();
+    assert_eq!(cluster.disconnected("server3"), ["server1", "server2"]);
+    assert!(cluster.partition(&[vec!["server1".to_string()], vec!["server1".to_string()]]).is_err());
+    assert!(cluster.partition(&[vec!["server9".to_string()]]).is_err());
+}
+
+#[test]
+fn paused_servers_answer_once_resumed() {
+    let mut cluster = LocalClusterBuilder::new(3).server_config(fast()).start().unwrap();
+    cluster.pause("server1").unwrap();
+    cluster.pause("server2").unwrap();
+    assert!(cluster.faults("server1").paused);
+    let mut client = client(&cluster, "a", &["server3"]);
+    assert!(matches!(client.lock("x").err().map(|e| e.0), Some(ErrorKind::Unreachable(_))));
+
+    cluster.resume("server2").unwrap();
+    client.lock("y").unwrap().unlock().unwrap();
+
+    // faults other than a partition stay when it heals
+    let lossy: FaultPolicy = serde_yaml::from_str("default: {drop_rate: 0.2, max_delay_ms: 20}").unwrap();
+    cluster.set_faults("server3", lossy.clone()).unwrap();
+    cluster.partition(&[vec!["server3".to_string()]]).unwrap();
+    assert_eq!(cluster.disconnected("server3"), ["server1", "server2"]);
+    cluster.heal().unwrap();
+    assert_eq!(cluster.faults("server3"), lossy);
+    assert!(cluster.set_faults("server3", FaultPolicy { default: LinkFaults { drop_rate: -1.0, ..LinkFaults::default() },
+                                                       ..FaultPolicy::default() }).is_err());
+
+    // a server restarted in this process answers on its port again, with its faults
+    cluster.kill("server3").unwrap();
+    cluster.restart("server3").unwrap();
+    assert!(cluster.is_running("server3"));
+    assert_eq!(cluster.faults("server3"), lossy);
+    client.lock("z").unwrap().unlock().unwrap();
+}
+
+#[test]
+fn servers_run_as_processes_that_are_killed_and_restarted() {
+    let dir = std::env::temp_dir().join(format!("paxos550-cluster-test-{}", std::process::id()));
+    let mut cluster = LocalClusterBuilder::new(3)
+        .server_config(fast())
+        .launch(Launch::Processes(PathBuf::from(env!("CARGO_BIN_EXE_server"))))
+        .dir(dir.clone())
+        .start()
+        .unwrap();
+    let mut a = client(&cluster, "a", &["server1", "server2", "server3"]);
+    a.lock("k").unwrap().leak();
+
+    // a majority is left, which still knows who holds k
+    cluster.kill("server1").unwrap();
+    assert!(!cluster.is_running("server1"));
+    assert!(cluster.kill("server1").is_err());
+    let mut b = client(&cluster, "b", &["server3"]);
+    assert!(b.try_lock("k").unwrap().is_none());
+    b.lock("m").unwrap().unlock().unwrap();
+
+    // server1 comes back with what it saved, and learns what was decided while it was down
+    assert!(cluster.restart("server2").is_err());
+    cluster.restart("server1").unwrap();
+    assert!(cluster.is_running("server1"));
+    let mut c = LockClientBuilder::new("c".to_string())
+        .server("server1".to_string(), cluster.address("server1").unwrap())
+        .timeout(Duration::from_secs(5))
+        .build()
+        .unwrap();
+    assert!(c.try_lock("k").unwrap().is_none());
+    assert!(c.try_lock("m").unwrap().is_some());
+
+    // server1 and server3 are a majority
+    cluster.kill("server2").unwrap();
+    b.lock("j").unwrap().unlock().unwrap();
+    cluster.kill("server1").unwrap();
+    assert!(matches!(b.lock("j").err().map(|e| e.0), Some(ErrorKind::Unreachable(_))));
+
+    cluster.shutdown().unwrap();
+    // the logs of the servers stay in a directory that was given
+    assert!(dir.join("server2.log").exists());
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,132 @@
+extern crate paxos550;
+
+use paxos550::config::*;
+use paxos550::errors::*;
+use paxos550::locker::Operation;
+use paxos550::server::*;
+
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn invalid(text: &str) -> String {
+    match Config::parse(text) {
+        Ok(config) => panic!("accepted {:?}", config),
+        Err(e) => e.to_string(),
+    }
+}
+
+#[test]
+fn example_config_is_valid() {
+    let config = Config::parse(include_str!("../script/cluster.yaml")).unwrap();
+    assert_eq!(config.nodes.len(), 5);
+    assert_eq!(config.peers("server1").len(), 4);
+    assert_eq!(config.members()["server3"], "127.0.0.1:9003".parse().unwrap());
+}
+
+#[test]
+fn omitted_settings_take_defaults() {
+    let config = Config::parse("nodes:\n  - id: a\n    address: 127.0.0.1:9001\n").unwrap();
+    assert_eq!(config.server.timeout(), DEFAULT_TIMEOUT);
+    assert_eq!(config.server.batch_size, DEFAULT_BATCH_SIZE);
+    assert_eq!(config.server.window, DEFAULT_WINDOW);
+    assert!(config.nodes[0].listen.is_none());
+    assert!(config.nodes[0].storage.is_none());
+}
+
+#[test]
+fn invalid_configs_are_rejected() {
+    assert!(invalid("nodes: []\n").contains("no nodes"));
+    assert!(invalid("nodes:\n  - id: a\n    address: 127.0.0.1:9001\n  - id: a\n    address: 127.0.0.1:9002\n")
+        .contains("node a is listed twice"));
+    assert!(invalid("nodes:\n  - id: a\n    address: 127.0.0.1:9001\n  - id: b\n    address: 127.0.0.1:9001\n")
+        .contains("is used by two nodes"));
+    assert!(invalid("nodes:\n  - id: a\n    address: 127.0.0.1:9001\nserver:\n  window: 0\n")
+        .contains("must be positive"));
+    invalid("nodes:\n  - id: a\n    address: not-an-address\n");
+    invalid("nodes:\n  - id: a\n    address: 127.0.0.1:9001\n    port: 9001\n");
+}
+
+#[test]
+fn unknown_node_is_reported() {
+    let config = Config::parse(include_str!("../script/cluster.yaml")).unwrap();
+    match ServerBuilder::from_config(&config, "server9") {
+        Err(Error(ErrorKind::InvalidConfig(detail), _)) => assert!(detail.contains("server9")),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("built a server that is not in the config"),
+    }
+}
+
+#[test]
+fn builders_reject_invalid_settings() {
+    let addr = "127.0.0.1:0".parse().unwrap();
+    let builders = [
+        ServerBuilder::new("a".to_string(), addr).timeout(Duration::ZERO),
+        ServerBuilder::new("a".to_string(), addr).learn_timeout(Duration::ZERO),
+        ServerBuilder::new("a".to_string(), addr).batch_size(0),
+        ServerBuilder::new("a".to_string(), addr).window(0),
+    ];
+    for builder in builders {
+        match builder.build() {
+            Err(Error(ErrorKind::InvalidConfig(detail), _)) => assert!(detail.contains("must be positive")),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("built a server with invalid settings"),
+        }
+    }
+}
+
+#[test]
+fn members_are_parsed() {
+    assert_eq!(parse_member("node1=127.0.0.1:9001").unwrap(), ("node1".to_string(), "127.0.0.1:9001".parse().unwrap()));
+    assert!(parse_member("node1").is_err());
+    assert!(parse_member("=127.0.0.1:9001").is_err());
+    assert!(parse_member("node1=localhost").is_err());
+}
+
+#[test]
+fn flags_override_the_servers_of_the_config() {
+    let path = std::path::Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/script/cluster.yaml"));
+    let members = servers(Some(path), ["server1=127.0.0.1:9101", "extra=127.0.0.1:9200"]).unwrap();
+    assert_eq!(members.len(), 6);
+    assert_eq!(members["server1"], "127.0.0.1:9101".parse().unwrap());
+    assert_eq!(members["server2"], "127.0.0.1:9002".parse().unwrap());
+    assert!(servers(None, []).is_err());
+
+    assert_eq!(parse_flag::<u64>("10", "--timeout").unwrap(), 10);
+    assert_eq!(parse_flag::<u64>("ten", "--timeout").unwrap_err().to_string(), "invalid value 'ten' for --timeout");
+}
+
+#[test]
+fn server_from_config_keeps_its_log_in_storage_file() {
+    let path = std::env::temp_dir().join(format!("paxos550-config-test-{}.log", std::process::id()));
+    fs::write(&path, "the log of an earlier run\n").unwrap();
+    let text = format!("nodes:\n  - id: a\n    address: 127.0.0.1:0\n    storage: {}\nserver:\n  batch_delay_ms: 0\n",
+                       path.display());
+    let config = Config::parse(&text).unwrap();
+    // a file the server did not write is not overwritten
+    let error = ServerBuilder::from_config(&config, "a").err().expect("opened a file that is not storage");
+    assert!(error.to_string().contains("is not a storage file"), "{}", error);
+    fs::remove_file(&path).unwrap();
+
+    let mut server = ServerBuilder::from_config(&config, "a").unwrap().build().unwrap();
+    server.start().unwrap();
+    server.propose(Operation::Lock("key".to_string(), "client".to_string())).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while server.status().applied == 0 {
+        assert!(Instant::now() < deadline, "operation was not applied");
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.shutdown().unwrap();
+    let log = fs::read_to_string(&path).unwrap();
+    assert!(log.contains("key"), "{}", log);
+
+    // a line cut short by a crash is dropped, and the rest is read back
+    fs::write(&path, format!("{}{{\"Decided\":[2,", log)).unwrap();
+    let mut server = ServerBuilder::from_config(&config, "a").unwrap().build().unwrap();
+    server.start().unwrap();
+    let status = server.status();
+    assert_eq!((status.applied, status.highest_decided), (1, 1));
+    server.shutdown().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), log);
+    fs::remove_file(&path).unwrap();
+}
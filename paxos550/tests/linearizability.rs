@@ -0,0 +1,330 @@
+extern crate paxos550;
+extern crate rand;
+extern crate tokio;
+
+use paxos550::client::{AsyncLockClient, LockClientBuilder};
+use paxos550::errors::*;
+use paxos550::locker::{Locker, Operation};
+use paxos550::server::{FaultPolicy, LinkFaults, ServerBuilder, ServerHandle};
+use paxos550::sim::*;
+use paxos550::sim::linearizability::*;
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const CLUSTER_SIZE: usize = 5;
+const CLIENTS: usize = 8;
+const KEYS: usize = 3;
+const TICK: Duration = Duration::from_millis(10);
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+struct Replica {
+    locker: Locker,
+    next_instance: usize,
+    applied: Vec<(Operation, bool)>,
+}
+
+struct Request {
+    id: OperationID,
+    node: usize,
+    op: Operation,
+    applied_before: usize,
+    deadline: Duration,
+}
+
+struct Client {
+    name: String,
+    request: Option<Request>,
+}
+
+/// Drives `CLIENTS` clients against a simulated cluster while partitioning and killing nodes.
+/// A client that does not hear back is replaced by a new one, and its operation stays pending.
+fn run(seed: u64) -> Result<(History<Operation, bool>, usize)> {
+    let config = SimConfig {
+        drop_rate: 0.05,
+        duplicate_rate: 0.05,
+        min_delay: Duration::from_millis(1),
+        max_delay: Duration::from_millis(50),
+        timeout: Duration::from_millis(100),
+    };
+    let mut sim = Simulator::new(CLUSTER_SIZE, config, seed);
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut history = History::new();
+    let mut replicas: Vec<_> = (0..CLUSTER_SIZE).map(|_| Replica {
+        locker: Locker::new(),
+        next_instance: 0,
+        applied: Vec::new(),
+    }).collect();
+    let mut next_client = 0;
+    let mut new_client = || {
+        next_client += 1;
+        Client { name: format!("client{}", next_client), request: None }
+    };
+    let mut clients: Vec<_> = (0..CLIENTS).map(|_| new_client()).collect();
+    let mut killed = Vec::new();
+    let mut completed = 0;
+
+    for tick in 0..4000 {
+        sim.run_for(TICK)?;
+        let workload = tick < 2000;
+
+        // apply decided instances in order, like the server does
+        for (node, replica) in replicas.iter_mut().enumerate() {
+            while let Some(op) = sim.learned(node, replica.next_instance).cloned() {
+                // no lease runs out: the operations have no time
+                let valid = replica.locker.append_log(&op, 0);
+                replica.applied.push((op, valid));
+                replica.next_instance += 1;
+            }
+        }
+
+        // deliver responses, and give up on clients that waited too long
+        for client in &mut clients {
+            let done = match client.request {
+                None => continue,
+                Some(ref r) => {
+                    let applied = &replicas[r.node].applied[r.applied_before..];
+                    applied.iter().find(|&(op, _)| *op == r.op).map(|&(_, valid)| (r.id, valid))
+                },
+            };
+            if let Some((id, valid)) = done {
+                history.complete(id, valid);
+                client.request = None;
+                completed += 1;
+            } else if client.request.as_ref().unwrap().deadline < sim.now() {
+                *client = new_client();
+            }
+        }
+
+        if !workload {
+            continue;
+        }
+
+        // faults
+        if rng.gen_bool(0.01) {
+            let mut nodes: Vec<usize> = (0..CLUSTER_SIZE).collect();
+            rng.shuffle(&mut nodes);
+            let split = rng.gen_range(1, CLUSTER_SIZE);
+            sim.partition(&[&nodes[..split], &nodes[split..]]);
+        }
+        if rng.gen_bool(0.02) {
+            sim.heal();
+        }
+        if killed.len() < (CLUSTER_SIZE - 1) / 2 && rng.gen_bool(0.002) {
+            let node = rng.gen_range(0, CLUSTER_SIZE);
+            if !killed.contains(&node) {
+                sim.pause(node);
+                killed.push(node);
+            }
+        }
+
+        // new requests
+        for client in &mut clients {
+            if client.request.is_some() || !rng.gen_bool(0.1) {
+                continue;
+            }
+            let key = format!("key{}", rng.gen_range(0, KEYS));
+            let op = if rng.gen() {
+                Operation::Lock(key, client.name.clone())
+            } else {
+                Operation::Unlock(key, client.name.clone())
+            };
+            let node = rng.gen_range(0, CLUSTER_SIZE);
+            let id = history.invoke(&client.name, op.clone());
+            if !killed.contains(&node) {
+                sim.propose(node, op.clone());
+            }
+            client.request = Some(Request {
+                id,
+                node,
+                op,
+                applied_before: replicas[node].applied.len(),
+                deadline: sim.now() + CLIENT_TIMEOUT,
+            });
+        }
+    }
+
+    // replicas must have applied the same prefix of the log with the same results
+    for replica in &replicas {
+        for other in &replicas {
+            let len = replica.applied.len().min(other.applied.len());
+            assert_eq!(replica.applied[..len], other.applied[..len]);
+        }
+    }
+    Ok((history, completed))
+}
+
+#[test]
+fn lock_service_is_linearizable_under_faults() {
+    for seed in 0..20 {
+        let (history, completed) = run(seed).expect("safety violation");
+        assert!(completed > 0, "seed {} completed no operations", seed);
+        if let Err(e) = check(&LockModel, &history) {
+            panic!("seed {}: {}", seed, e);
+        }
+    }
+}
+
+/// Starts `count` in-process servers that know each other and inject faults into their traffic
+/// once asked to.
+fn servers(count: usize) -> Vec<ServerHandle> {
+    let sockets: Vec<_> = (0..count).map(|_| UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+    let addrs: Vec<SocketAddr> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+    sockets.into_iter().enumerate().map(|(i, socket)| {
+        let mut builder = ServerBuilder::new(format!("node{}", i), addrs[i])
+            .socket(socket)
+            .timeout(Duration::from_millis(50))
+            .batch_size(2)
+            .window(2)
+            // no lock is held long enough to expire
+            .lease(Duration::from_secs(600))
+            .fault_injection(true);
+        for (j, &addr) in addrs.iter().enumerate() {
+            if j != i {
+                builder = builder.peer(format!("node{}", j), addr);
+            }
+        }
+        let mut server = builder.build().unwrap();
+        server.start().unwrap();
+        server
+    }).collect()
+}
+
+/// A client that starts with a random one of `servers`, and asks each of them once at most.
+async fn client(name: &str, servers: &[(String, SocketAddr)]) -> AsyncLockClient {
+    LockClientBuilder::new(name.to_string())
+        .servers(servers.iter().cloned().collect())
+        .timeout(CLIENT_TIMEOUT / 5)
+        .attempts(servers.len())
+        .build_async()
+        .await
+        .unwrap()
+}
+
+/// Sends random operations until `until`, failing over from server to server, recording in `history` when each was
+/// invoked and when it returned. A client that does not hear back is replaced by a new one, and
+/// its operation stays pending.
+async fn run_client(servers: Arc<Vec<(String, SocketAddr)>>, history: Arc<Mutex<History<Operation, bool>>>,
+                    first: usize, seed: u64, until: Instant) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut name = format!("client{}", first);
+    let mut generation = 0;
+    while Instant::now() < until {
+        let key = format!("key{}", rng.gen_range(0, KEYS));
+        let op = match rng.gen_range(0, 3) {
+            0 => Operation::Lock(key.clone(), name.clone()),
+            1 => Operation::Unlock(key.clone(), name.clone()),
+            _ => Operation::Renew(key.clone(), name.clone()),
+        };
+        let mut client = client(&name, &servers).await;
+        let id = history.lock().unwrap().invoke(&name, op.clone());
+        let result = match op {
+            Operation::Lock(..) => client.try_acquire(&key).await,
+            Operation::Unlock(..) => client.unlock(&key).await.map(|()| true),
+            Operation::Renew(..) => client.renew(&key).await.map(|()| true),
+        };
+        match result {
+            Ok(valid) => history.lock().unwrap().complete(id, valid),
+            Err(Error(ErrorKind::LockNotHeld(_), _)) => history.lock().unwrap().complete(id, false),
+            Err(Error(ErrorKind::Unreachable(_), _)) => {
+                generation += 1;
+                name = format!("client{}.{}", first, generation);
+            },
+            Err(e) => panic!("{} failed: {}", name, e),
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn servers_are_linearizable_under_faults() {
+    let handles = servers(3);
+    let servers: Arc<Vec<_>> = Arc::new(handles.iter().map(|s| (s.node_id().clone(), s.local_addr())).collect());
+    let lossy = LinkFaults { drop_rate: 0.05, duplicate_rate: 0.05, max_delay_ms: 10, ..LinkFaults::default() };
+    for server in servers.iter() {
+        let policy = FaultPolicy { default: lossy.clone(), ..FaultPolicy::default() };
+        client("faults", std::slice::from_ref(server)).await.faults(Some(policy)).await.unwrap();
+    }
+    let history = Arc::new(Mutex::new(History::new()));
+    let start = Instant::now();
+    let clients: Vec<_> = (0..CLIENTS).map(|i| {
+        tokio::spawn(run_client(servers.clone(), history.clone(), i, i as u64, start + Duration::from_secs(2)))
+    }).collect();
+
+    // cut one server off from the others for a while
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let cut_off = FaultPolicy {
+        default: LinkFaults { disconnected: true, ..LinkFaults::default() },
+        ..FaultPolicy::default()
+    };
+    client("faults", &servers[2..]).await.faults(Some(cut_off)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let policy = FaultPolicy { default: lossy, ..FaultPolicy::default() };
+    client("faults", &servers[2..]).await.faults(Some(policy)).await.unwrap();
+
+    for client in clients {
+        client.await.unwrap();
+    }
+    let history = history.lock().unwrap();
+    let completed = history.entries().iter().filter(|entry| entry.returned.is_some()).count();
+    assert!(completed > 0, "no operation completed");
+    if let Err(e) = check(&LockModel, &history) {
+        panic!("{}", e);
+    }
+}
+
+#[test]
+fn checker_accepts_concurrent_lock_requests() {
+    let mut history = History::new();
+    let a = history.invoke("a", Operation::Lock("k".into(), "a".into()));
+    let b = history.invoke("b", Operation::Lock("k".into(), "b".into()));
+    history.complete(b, true);
+    history.complete(a, false);
+    let c = history.invoke("a", Operation::Unlock("k".into(), "a".into()));
+    history.complete(c, false);
+    history.invoke("b", Operation::Unlock("k".into(), "b".into()));
+    let d = history.invoke("c", Operation::Lock("k".into(), "c".into()));
+    history.complete(d, true);
+    assert!(check(&LockModel, &history).is_ok());
+}
+
+#[test]
+fn checker_lets_owners_lock_again() {
+    let mut history = History::new();
+    let a = history.invoke("a", Operation::Lock("k".into(), "a".into()));
+    history.complete(a, true);
+    let again = history.invoke("a", Operation::Lock("k".into(), "a".into()));
+    history.complete(again, true);
+    let b = history.invoke("b", Operation::Lock("k".into(), "b".into()));
+    history.complete(b, false);
+    assert!(check(&LockModel, &history).is_ok());
+}
+
+#[test]
+fn checker_rejects_two_owners() {
+    let mut history = History::new();
+    let a = history.invoke("a", Operation::Lock("k".into(), "a".into()));
+    history.complete(a, true);
+    let b = history.invoke("b", Operation::Lock("k".into(), "b".into()));
+    history.complete(b, true);
+    let other = history.invoke("c", Operation::Lock("other".into(), "c".into()));
+    history.complete(other, true);
+    match check(&LockModel, &history) {
+        Err(Error(ErrorKind::NotLinearizable(..), _)) => (),
+        r => panic!("unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn checker_rejects_stale_read_of_unlock() {
+    let mut history = History::new();
+    let a = history.invoke("a", Operation::Lock("k".into(), "a".into()));
+    history.complete(a, true);
+    let b = history.invoke("a", Operation::Unlock("k".into(), "a".into()));
+    history.complete(b, true);
+    let c = history.invoke("a", Operation::Unlock("k".into(), "a".into()));
+    history.complete(c, true);
+    assert!(check(&LockModel, &history).is_err());
+}
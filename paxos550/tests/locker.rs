@@ -0,0 +1,85 @@
+extern crate paxos550;
+
+use paxos550::locker::{Locker, Operation};
+
+use std::time::Duration;
+
+// an arbitrary start, far from 0
+const T: u64 = 1_700_000_000_000;
+
+fn lock(key: &str, node: &str) -> Operation {
+    Operation::Lock(key.to_string(), node.to_string())
+}
+
+fn locker() -> Locker {
+    Locker::with_lease(Duration::from_secs(10))
+}
+
+#[test]
+fn locks_expire_after_their_lease() {
+    let mut locker = locker();
+    assert!(locker.append_log(&lock("k", "a"), T));
+    assert!(!locker.append_log(&lock("k", "b"), T + 9_999));
+    assert_eq!(locker.locks()["k"], "a");
+    assert!(locker.append_log(&lock("k", "b"), T + 10_000));
+    assert_eq!(locker.locks()["k"], "b");
+}
+
+#[test]
+fn owners_extend_their_lease_by_renewing_or_locking_again() {
+    let mut locker = locker();
+    assert!(locker.append_log(&lock("k", "a"), T));
+    assert!(locker.append_log(&Operation::Renew("k".to_string(), "a".to_string()), T + 6_000));
+    assert!(locker.append_log(&lock("k", "a"), T + 12_000));
+    assert!(!locker.append_log(&lock("k", "b"), T + 21_999));
+    assert!(locker.append_log(&lock("k", "b"), T + 22_000));
+}
+
+#[test]
+fn expired_locks_can_be_neither_renewed_nor_unlocked() {
+    let mut locker = locker();
+    assert!(locker.append_log(&lock("k", "a"), T));
+    assert!(!locker.append_log(&Operation::Renew("k".to_string(), "a".to_string()), T + 10_000));
+    assert!(!locker.append_log(&Operation::Unlock("k".to_string(), "a".to_string()), T + 10_001));
+    assert!(locker.locks().is_empty());
+    // the owner takes it again like anyone else
+    assert!(locker.append_log(&lock("k", "a"), T + 10_002));
+}
+
+#[test]
+fn time_never_goes_back() {
+    let mut locker = locker();
+    assert!(locker.append_log(&lock("k", "a"), T));
+    assert!(locker.append_log(&lock("other", "b"), T + 10_000));
+    // proposed by a server whose clock is behind, but applied after the lease of `k` ended
+    assert!(locker.append_log(&lock("k", "c"), T + 5_000));
+    assert_eq!(locker.log().iter().map(|entry| entry.time_ms).collect::<Vec<_>>(), vec![T, T + 10_000, T + 5_000]);
+}
+
+#[test]
+fn copies_of_a_request_take_effect_once() {
+    let mut locker = locker();
+    assert!(locker.apply_request(7, &lock("k", "a"), T));
+    assert!(locker.apply_request(8, &Operation::Unlock("k".to_string(), "a".to_string()), T + 1));
+    // a copy of the lock, decided after the unlock, gets the result of the first
+    assert!(locker.apply_request(7, &lock("k", "a"), T + 2));
+    assert!(locker.locks().is_empty());
+    assert_eq!(locker.log().len(), 2);
+    // the same ID of another client is another request, and ID 0 is never a copy
+    assert!(locker.apply_request(7, &lock("k", "b"), T + 3));
+    assert!(locker.apply_request(0, &lock("other", "c"), T + 4));
+    assert!(locker.apply_request(0, &lock("other", "c"), T + 5));
+    assert_eq!(locker.log().len(), 5);
+}
+
+#[test]
+fn requests_older_than_a_lease_are_not_applied() {
+    let mut locker = locker();
+    assert!(locker.apply_request(1, &lock("k", "a"), T));
+    assert!(locker.apply_request(2, &lock("other", "b"), T + 25_000));
+    // forgotten, but too old to be applied again
+    assert!(!locker.apply_request(1, &lock("k", "a"), T + 5_000));
+    assert!(!locker.apply_request(3, &lock("k", "c"), T + 14_999));
+    assert!(locker.apply_request(4, &lock("k", "c"), T + 15_000));
+    assert_eq!(locker.log().len(), 3);
+}
@@ -0,0 +1,127 @@
+extern crate paxos550;
+extern crate serde_yaml;
+extern crate tracing;
+
+use paxos550::locker::Operation;
+use paxos550::logging::{self, LogFormat};
+use paxos550::message::{MessagePayload, MessageTarget};
+use paxos550::paxos::PaxosInstance;
+use paxos550::server::{ServerBuilder, ServerHandle};
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Collects what the subscriber writes.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Output {
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap().lines().map(String::from).collect()
+    }
+}
+
+/// Runs one instance of a single node cluster to the end.
+fn decide_one_instance() {
+    let mut instance = PaxosInstance::new("node0".to_string(), 7, 1, Duration::from_secs(1));
+    instance.set_requests(vec![41, 42]);
+    instance.start_proposing(1u32);
+    let mut messages = VecDeque::new();
+    instance.collect_messages_to_send(&mut messages);
+    while let Some(info) = messages.pop_front() {
+        assert!(info.target == MessageTarget::Broadcast || info.target == MessageTarget::Node("node0".to_string()));
+        if let MessagePayload::PaxosMessage(msg) = info.payload {
+            instance.receive_message(&msg.message);
+            instance.collect_messages_to_send(&mut messages);
+        }
+    }
+    assert_eq!(instance.value(), Some(&1));
+}
+
+#[test]
+fn json_logs_carry_the_fields_of_the_paxos_message() {
+    let output = Output::default();
+    let writer = output.clone();
+    let (subscriber, _) = logging::subscriber(LogFormat::Json, "debug", move || writer.clone()).unwrap();
+    tracing::subscriber::with_default(subscriber, decide_one_instance);
+
+    let lines = output.lines();
+    let chosen = lines.iter().find(|line| line.contains("chosen by a majority")).expect("no decision logged");
+    for field in &[r#""instance":7"#, r#""kind":"accepted""#, r#""peer":"node0""#, r#""proposal":"Some(ProposalID(1"#,
+                   r#""requests":"[41, 42]""#] {
+        assert!(chosen.contains(field), "no {} in {}", field, chosen);
+    }
+}
+
+#[test]
+fn log_level_changes_at_runtime() {
+    let output = Output::default();
+    let writer = output.clone();
+    let (subscriber, level) = logging::subscriber(LogFormat::Text, "warn", move || writer.clone()).unwrap();
+    tracing::subscriber::with_default(subscriber, || {
+        decide_one_instance();
+        assert!(output.lines().is_empty());
+        level.set("debug").unwrap();
+        decide_one_instance();
+        assert_eq!(level.get().unwrap(), "debug");
+        assert!(level.set("paxos550=loud").is_err());
+    });
+    assert!(output.lines().iter().any(|line| line.contains("chosen by a majority")));
+}
+
+/// Sends `MessagePayload::LogLevel(filter)` to `server` and returns its reply.
+fn ask_log_level(server: &ServerHandle, filter: Option<&str>) -> Result<String, String> {
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let request = MessagePayload::<Vec<Operation>>::LogLevel(filter.map(String::from));
+    client.send_to(&serde_yaml::to_vec(&request).unwrap(), server.local_addr()).unwrap();
+    let mut buf = [0u8; 1024];
+    let (size, _) = client.recv_from(&mut buf).unwrap();
+    serde_yaml::from_slice(&buf[..size]).unwrap()
+}
+
+#[test]
+fn clients_set_the_log_level_of_a_server() {
+    let (_subscriber, level) = logging::subscriber(LogFormat::Text, "info", io::sink).unwrap();
+    let mut server = ServerBuilder::new("node0".to_string(), "127.0.0.1:0".parse().unwrap())
+        .log_level(level.clone())
+        .log_level_changes(true)
+        .build()
+        .unwrap();
+    server.start().unwrap();
+
+    assert_eq!(ask_log_level(&server, None), Ok("info".to_string()));
+    assert_eq!(ask_log_level(&server, Some("debug")), Ok("debug".to_string()));
+    assert!(ask_log_level(&server, Some("paxos550=loud")).is_err());
+    assert_eq!(level.get().unwrap(), "debug");
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn log_level_changes_are_refused_unless_enabled() {
+    let (_subscriber, level) = logging::subscriber(LogFormat::Text, "info", io::sink).unwrap();
+    let mut server = ServerBuilder::new("node0".to_string(), "127.0.0.1:0".parse().unwrap())
+        .log_level(level.clone())
+        .build()
+        .unwrap();
+    server.start().unwrap();
+
+    assert_eq!(ask_log_level(&server, None), Ok("info".to_string()));
+    assert_eq!(ask_log_level(&server, Some("trace")), Err("log level changes are disabled on this server".to_string()));
+    assert_eq!(level.get().unwrap(), "info");
+    server.shutdown().unwrap();
+}
@@ -0,0 +1,49 @@
+extern crate paxos550;
+
+use paxos550::sim::explore::*;
+
+fn check(config: &ExploreConfig<&'static str>) -> Report {
+    let report = explore(config);
+    if let Some(ref violation) = report.violation {
+        panic!("after {} states:\n{}", report.states, violation);
+    }
+    report
+}
+
+#[test]
+fn single_proposer_is_explored_exhaustively() {
+    let report = check(&ExploreConfig {
+        cluster_size: 3,
+        proposals: vec![(0, 0, "a")],
+        max_timeouts: 0,
+        max_depth: 30,
+        max_states: 100_000,
+    });
+    assert!(report.complete);
+}
+
+#[test]
+fn two_proposers_one_instance() {
+    // deep enough for a proposer that ignores the accepted values in its promises to get a
+    // different value chosen
+    check(&ExploreConfig {
+        cluster_size: 3,
+        proposals: vec![(0, 0, "a"), (1, 0, "b")],
+        max_timeouts: 0,
+        max_depth: 10,
+        max_states: 1_000_000,
+    });
+}
+
+/// Takes minutes; run with `cargo test --release -- --ignored`.
+#[test]
+#[ignore]
+fn two_proposers_two_instances_with_retries() {
+    check(&ExploreConfig {
+        cluster_size: 3,
+        proposals: vec![(0, 0, "a"), (1, 0, "b"), (2, 1, "c"), (0, 1, "d")],
+        max_timeouts: 2,
+        max_depth: 40,
+        max_states: 5_000_000,
+    });
+}
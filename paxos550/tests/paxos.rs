@@ -0,0 +1,177 @@
+extern crate paxos550;
+
+use paxos550::message::MessagePayload;
+use paxos550::paxos::*;
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+/// Instance 1 on each node of a cluster, with the messages between them delivered by hand.
+struct Nodes {
+    instances: Vec<PaxosInstance<String>>,
+}
+
+impl Nodes {
+    fn new(size: usize) -> Nodes {
+        Nodes {
+            instances: (0..size)
+                .map(|i| PaxosInstance::new(format!("node{}", i), 1, size, Duration::from_secs(1)))
+                .collect(),
+        }
+    }
+
+    fn propose(&mut self, node: usize, value: &str) {
+        self.instances[node].start_proposing(value.to_string());
+    }
+
+    /// The messages `node` sent since the last call, whatever their target.
+    fn sent(&mut self, node: usize) -> Vec<PaxosInstanceMessage<String>> {
+        let mut messages = VecDeque::new();
+        self.instances[node].collect_messages_to_send(&mut messages);
+        messages.into_iter().filter_map(|info| match info.payload {
+            MessagePayload::PaxosMessage(m) => Some(m.message),
+            _ => None,
+        }).collect()
+    }
+
+    /// The only message `node` sent since the last call.
+    fn sent_one(&mut self, node: usize) -> PaxosInstanceMessage<String> {
+        let mut messages = self.sent(node);
+        assert_eq!(messages.len(), 1, "{:?}", messages);
+        messages.remove(0)
+    }
+
+    /// Delivers `message` to each node of `to`, and returns what each learned.
+    fn deliver(&mut self, message: &PaxosInstanceMessage<String>, to: &[usize]) -> Vec<Option<String>> {
+        to.iter().map(|&node| self.instances[node].receive_message(message)).collect()
+    }
+}
+
+#[test]
+fn four_nodes_need_three_to_agree() {
+    let mut nodes = Nodes::new(4);
+    nodes.propose(0, "a");
+    let prepare = nodes.sent_one(0);
+    nodes.deliver(&prepare, &[0, 1]);
+    for node in 0..2 {
+        let promise = nodes.sent_one(node);
+        nodes.deliver(&promise, &[0]);
+    }
+    // two promises of four are not a majority
+    assert!(nodes.sent(0).is_empty());
+    nodes.deliver(&prepare, &[2]);
+    let promise = nodes.sent_one(2);
+    nodes.deliver(&promise, &[0]);
+    let propose = nodes.sent_one(0);
+    assert!(matches!(propose, PaxosInstanceMessage::Propose(_)));
+
+    // neither are two acceptances, so node3 does not ask for the value yet
+    nodes.deliver(&propose, &[0, 1, 2]);
+    let accepted: Vec<_> = (0..3).map(|node| nodes.sent_one(node)).collect();
+    assert_eq!(nodes.deliver(&accepted[0], &[3]), [None]);
+    assert_eq!(nodes.deliver(&accepted[1], &[3]), [None]);
+    assert!(nodes.sent(3).is_empty());
+    assert_eq!(nodes.deliver(&accepted[2], &[3]), [None]);
+    assert!(matches!(nodes.sent_one(3), PaxosInstanceMessage::Learn(_)));
+}
+
+#[test]
+fn proposers_adopt_a_value_accepted_by_an_earlier_proposal() {
+    let mut nodes = Nodes::new(3);
+    nodes.propose(0, "a");
+    let prepare = nodes.sent_one(0);
+    // node2 promises too, so its next proposal has a higher round
+    nodes.deliver(&prepare, &[0, 1, 2]);
+    for node in 0..2 {
+        let promise = nodes.sent_one(node);
+        nodes.deliver(&promise, &[0]);
+    }
+    nodes.sent(2);
+    // only node1 accepts "a", and its Accepted is lost
+    let propose = nodes.sent_one(0);
+    nodes.deliver(&propose, &[1]);
+    nodes.sent(1);
+
+    nodes.propose(2, "b");
+    let prepare = nodes.sent_one(2);
+    nodes.deliver(&prepare, &[1, 2]);
+    for node in 1..3 {
+        let promise = nodes.sent_one(node);
+        nodes.deliver(&promise, &[2]);
+    }
+    // node1 promised with "a", which node2 proposes instead of its own value
+    match nodes.sent_one(2) {
+        PaxosInstanceMessage::Propose(ref propose) => assert_eq!(propose.value, "a"),
+        message => panic!("{:?}", message),
+    }
+}
+
+#[test]
+fn nodes_learn_the_chosen_value_rather_than_the_one_they_accepted() {
+    let mut nodes = Nodes::new(3);
+    nodes.propose(0, "a");
+    let prepare = nodes.sent_one(0);
+    nodes.deliver(&prepare, &[0, 1, 2]);
+    for node in 0..2 {
+        let promise = nodes.sent_one(node);
+        nodes.deliver(&promise, &[0]);
+    }
+    nodes.sent(2);
+    // only node1 accepts "a"
+    let propose = nodes.sent_one(0);
+    nodes.deliver(&propose, &[1]);
+    nodes.sent(1);
+
+    // node0 and node2 choose "b", without node1
+    nodes.propose(2, "b");
+    let prepare = nodes.sent_one(2);
+    nodes.deliver(&prepare, &[0, 2]);
+    for &node in &[0, 2] {
+        let promise = nodes.sent_one(node);
+        nodes.deliver(&promise, &[2]);
+    }
+    let propose = nodes.sent_one(2);
+    nodes.deliver(&propose, &[0, 2]);
+    let accepted = [nodes.sent_one(0), nodes.sent_one(2)];
+    assert_eq!(nodes.deliver(&accepted[0], &[2]), [None]);
+    assert_eq!(nodes.deliver(&accepted[1], &[2]), [Some("b".to_string())]);
+
+    // node1 accepted "a", so it has to ask for the value
+    assert_eq!(nodes.deliver(&accepted[0], &[1]), [None]);
+    assert_eq!(nodes.deliver(&accepted[1], &[1]), [None]);
+    let learn = nodes.sent_one(1);
+    assert!(matches!(learn, PaxosInstanceMessage::Learn(_)));
+    nodes.deliver(&learn, &[2]);
+    let value = nodes.sent_one(2);
+    assert_eq!(nodes.deliver(&value, &[1]), [Some("b".to_string())]);
+}
+
+#[test]
+fn nodes_that_learned_without_accepting_ignore_late_proposals() {
+    let mut nodes = Nodes::new(3);
+    nodes.propose(0, "a");
+    let prepare = nodes.sent_one(0);
+    nodes.deliver(&prepare, &[0, 1, 2]);
+    for &node in &[0, 2] {
+        let promise = nodes.sent_one(node);
+        nodes.deliver(&promise, &[0]);
+    }
+    nodes.sent(1);
+    let propose = nodes.sent_one(0);
+    nodes.deliver(&propose, &[0, 2]);
+    let accepted = [nodes.sent_one(0), nodes.sent_one(2)];
+
+    // node1 learns "a" from node0, without accepting it
+    nodes.deliver(&accepted[0], &[1]);
+    nodes.deliver(&accepted[1], &[1]);
+    let learn = nodes.sent_one(1);
+    nodes.deliver(&accepted[0], &[0]);
+    assert_eq!(nodes.deliver(&accepted[1], &[0]), [Some("a".to_string())]);
+    nodes.deliver(&learn, &[0]);
+    let value = nodes.sent_one(0);
+    assert_eq!(nodes.deliver(&value, &[1]), [Some("a".to_string())]);
+
+    // the Propose that node1 missed comes late
+    assert_eq!(nodes.deliver(&propose, &[1]), [None]);
+    assert!(nodes.sent(1).is_empty());
+}
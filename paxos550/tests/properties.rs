@@ -0,0 +1,388 @@
+extern crate proptest;
+extern crate rand;
+extern crate paxos550;
+
+use paxos550::env;
+use paxos550::message::MessagePayload;
+use paxos550::paxos::*;
+
+use proptest::prelude::*;
+use rand::rngs::mock::StepRng;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+fn proposal_id(round: u64, tiebreak: u64, node: usize) -> ProposalID {
+    ProposalID::new(round, format!("node{}", node), &mut StepRng::new(tiebreak, 0))
+}
+
+fn arb_proposal_id() -> impl Strategy<Value = ProposalID> {
+    (1..5u64, 0..3u64, 0..5usize).prop_map(|(round, tiebreak, node)| proposal_id(round, tiebreak, node))
+}
+
+#[derive(Clone, Debug)]
+enum AcceptorInput {
+    Prepare(PrepareMessage),
+    Propose(ProposeMessage<u32>),
+}
+
+fn arb_acceptor_input() -> impl Strategy<Value = AcceptorInput> {
+    prop_oneof![
+        arb_proposal_id().prop_map(|id| AcceptorInput::Prepare(PrepareMessage {
+            proposer_id: id.proposer_id(),
+            proposal_id: id,
+        })),
+        (arb_proposal_id(), 0..3u32).prop_map(|(id, value)| AcceptorInput::Propose(ProposeMessage {
+            proposer_id: id.proposer_id(),
+            proposal_id: id,
+            value,
+        })),
+    ]
+}
+
+/// Promises from acceptors 0, 1, ..., each with the proposal it accepted last, if any. A proposal
+/// ID always comes with the same value.
+fn arb_promises(count: usize) -> impl Strategy<Value = Vec<(usize, Option<(ProposalID, u32)>)>> {
+    let accepted = prop::option::of(arb_proposal_id().prop_map(|id| {
+        let value = (id.round() as u32 + u32::from(id.proposer_id().as_bytes()[4])) % 3;
+        (id, value)
+    }));
+    prop::collection::vec(accepted, count).prop_map(|promises| promises.into_iter().enumerate().collect())
+}
+
+proptest! {
+    #[test]
+    fn acceptor_never_accepts_below_its_promise(inputs in prop::collection::vec(arb_acceptor_input(), 1..40)) {
+        let mut acceptor = Acceptor::<u32>::new(1, "node0".to_string());
+        let mut promised: Option<ProposalID> = None;
+        let mut accepted: Option<(ProposalID, u32)> = None;
+        for input in &inputs {
+            match *input {
+                AcceptorInput::Prepare(ref prepare) => {
+                    if let Some(promise) = acceptor.receive_prepare(prepare) {
+                        prop_assert!(promised.as_ref().is_none_or(|p| prepare.proposal_id >= *p));
+                        prop_assert_eq!(&promise.proposal_id, &prepare.proposal_id);
+                        // the promise reports the last accepted proposal
+                        prop_assert_eq!(promise.last_accepted_value, accepted.as_ref().map(|a| a.1));
+                        promised = Some(prepare.proposal_id.clone());
+                    } else {
+                        prop_assert!(promised.as_ref().is_some_and(|p| prepare.proposal_id < *p));
+                    }
+                },
+                AcceptorInput::Propose(ref propose) => {
+                    if acceptor.receive_propose(propose).is_some() {
+                        prop_assert!(promised.as_ref().is_none_or(|p| propose.proposal_id >= *p));
+                        promised = Some(propose.proposal_id.clone());
+                        accepted = Some((propose.proposal_id.clone(), propose.value));
+                    } else {
+                        prop_assert!(promised.as_ref().is_some_and(|p| propose.proposal_id < *p));
+                    }
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn restored_acceptor_answers_as_if_it_never_stopped(inputs in prop::collection::vec(arb_acceptor_input(), 1..40),
+                                                       restart in 0..40usize) {
+        let mut running = Acceptor::<u32>::new(1, "node0".to_string());
+        let mut restarted = Acceptor::<u32>::new(1, "node0".to_string());
+        for (i, input) in inputs.iter().enumerate() {
+            if i == restart {
+                let state = restarted.state();
+                restarted = Acceptor::new(1, "node0".to_string());
+                restarted.restore(state);
+            }
+            match *input {
+                AcceptorInput::Prepare(ref prepare) => {
+                    prop_assert_eq!(running.receive_prepare(prepare), restarted.receive_prepare(prepare));
+                },
+                AcceptorInput::Propose(ref propose) => {
+                    prop_assert_eq!(running.receive_propose(propose), restarted.receive_propose(propose));
+                },
+            }
+        }
+        prop_assert_eq!(running.state(), restarted.state());
+    }
+
+    #[test]
+    fn proposer_adopts_highest_accepted_value(cluster_size in 1..6usize,
+                                              own_value in 0..3u32,
+                                              promises in arb_promises(5)) {
+        let majority = cluster_size / 2 + 1;
+        let mut proposer = Proposer::new(1, "node0".to_string(), cluster_size);
+        proposer.set_value(own_value);
+        // acceptors can only have accepted proposals below the one they promise
+        proposer.observe_proposal(&proposal_id(5, 0, 4));
+        let prepare = proposer.prepare(&mut StepRng::new(0, 0));
+        let mut propose = None;
+        for &(acceptor, ref accepted) in promises.iter().take(majority) {
+            prop_assert!(propose.is_none());
+            propose = proposer.receive_promise(&PromiseMessage {
+                acceptor_id: format!("node{}", acceptor),
+                proposal_id: prepare.proposal_id.clone(),
+                last_accepted_proposal_id: accepted.as_ref()
+                    .map_or(ProposalID::initial(format!("node{}", acceptor)), |a| a.0.clone()),
+                last_accepted_value: accepted.as_ref().map(|a| a.1),
+            });
+        }
+        let propose = propose.expect("a majority of promises must lead to a proposal");
+        let expected = promises.iter().take(majority)
+            .filter_map(|(_, accepted)| accepted.clone())
+            .max_by(|a, b| a.0.cmp(&b.0))
+            .map_or(own_value, |a| a.1);
+        prop_assert_eq!(propose.proposal_id, prepare.proposal_id);
+        prop_assert_eq!(propose.value, expected);
+    }
+
+    #[test]
+    fn learner_needs_a_majority_of_one_proposal(cluster_size in 1..6usize,
+                                                accepted in prop::collection::vec((0..5usize, arb_proposal_id()), 0..30)) {
+        let majority = cluster_size / 2 + 1;
+        let mut learner = Learner::<u32>::new(1, "node0".to_string(), cluster_size);
+        let mut latest: HashMap<usize, ProposalID> = HashMap::new();
+        let mut votes: HashMap<ProposalID, HashSet<usize>> = HashMap::new();
+        for &(acceptor, ref proposal_id) in &accepted {
+            if acceptor >= cluster_size {
+                continue;
+            }
+            let learned = learner.receive_accepted(&AcceptedMessage {
+                acceptor_id: format!("node{}", acceptor),
+                proposal_id: proposal_id.clone(),
+            });
+            if latest.get(&acceptor).is_none_or(|p| p < proposal_id) {
+                latest.insert(acceptor, proposal_id.clone());
+                votes.entry(proposal_id.clone()).or_default().insert(acceptor);
+            }
+            if learned.is_some() {
+                prop_assert_eq!(votes[proposal_id].len(), majority);
+                // already got the majority, later messages are ignored
+                learner.set_chosen_value(0);
+            }
+        }
+    }
+
+    #[test]
+    fn retries_back_off_up_to_the_limit(seed in any::<u64>(), timeout_ms in 0..500u64, max_backoff_ms in 1..2000u64,
+                                        retries in 1..20usize) {
+        let timeouts = Timeouts {
+            prepare: Duration::from_millis(timeout_ms),
+            propose: Duration::from_millis(timeout_ms * 2),
+            learn: Duration::from_millis(timeout_ms * 3),
+            max_backoff: Duration::from_millis(max_backoff_ms),
+        };
+        let mut instance = PaxosInstance::with_random("node0".to_string(), 1, 3, timeouts, env::seeded_random(seed));
+        instance.start_proposing(0u32);
+        let mut messages = VecDeque::new();
+        instance.collect_messages_to_send(&mut messages);
+        let mut timeout = messages[0].timeout.unwrap();
+        prop_assert_eq!(timeout, timeouts.prepare);
+        for _ in 0..retries {
+            let message = match messages.pop_front().unwrap().payload {
+                MessagePayload::PaxosMessage(msg) => msg.message,
+                payload => panic!("unexpected message {:?}", payload),
+            };
+            instance.on_timeout(message, timeout).unwrap();
+            instance.collect_messages_to_send(&mut messages);
+            let retry = messages[0].timeout.unwrap();
+            prop_assert!(retry >= timeout && retry <= timeout * 2);
+            prop_assert!(retry <= timeouts.max_backoff.max(timeouts.prepare));
+            timeout = retry;
+        }
+    }
+
+    #[test]
+    fn phases_back_off_separately(seed in any::<u64>()) {
+        let timeouts = Timeouts {
+            prepare: Duration::from_millis(100),
+            propose: Duration::from_millis(1000),
+            learn: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(60),
+        };
+        let mut instance = PaxosInstance::with_random("node0".to_string(), 1, 3, timeouts, env::seeded_random(seed));
+        let mut messages = VecDeque::new();
+        let mut next = |instance: &mut PaxosInstance<u32>| {
+            instance.collect_messages_to_send(&mut messages);
+            let message = messages.pop_front().unwrap();
+            match message.payload {
+                MessagePayload::PaxosMessage(msg) => (msg.message, message.timeout),
+                payload => panic!("unexpected message {:?}", payload),
+            }
+        };
+        instance.start_proposing(0u32);
+        let (prepare, timeout) = next(&mut instance);
+        instance.on_timeout(prepare, timeout.unwrap()).unwrap();
+        let (prepare, prepare_timeout) = next(&mut instance);
+
+        // a majority promises, so the proposer proposes with the timeout of Propose
+        instance.receive_message(&prepare);
+        let promise = match next(&mut instance).0 {
+            PaxosInstanceMessage::Promise(promise) => promise,
+            message => panic!("unexpected message {:?}", message),
+        };
+        let other = PromiseMessage { acceptor_id: "node1".to_string(), ..promise.clone() };
+        instance.receive_message(&PaxosInstanceMessage::Promise(promise));
+        instance.receive_message(&PaxosInstanceMessage::Promise(other));
+        let (propose, timeout) = next(&mut instance);
+        prop_assert!(matches!(propose, PaxosInstanceMessage::Propose(_)));
+        prop_assert_eq!(timeout, Some(timeouts.propose));
+
+        // the Prepare of the retry waits as long as the last Prepare did
+        instance.on_timeout(propose, timeouts.propose).unwrap();
+        let (prepare, timeout) = next(&mut instance);
+        prop_assert!(matches!(prepare, PaxosInstanceMessage::Prepare(_)));
+        prop_assert_eq!(timeout, prepare_timeout);
+    }
+}
+
+/// A network of `cluster_size` nodes, each running a Proposer, an Acceptor and a Learner, that
+/// delivers, duplicates and drops messages in the order chosen by the test.
+struct Cluster {
+    majority: usize,
+    proposers: Vec<Proposer<u32>>,
+    acceptors: Vec<Acceptor<u32>>,
+    learners: Vec<Learner<u32>>,
+    in_flight: Vec<(usize, PaxosInstanceMessage<u32>)>,
+    promised: Vec<Option<ProposalID>>,
+    proposals: HashMap<ProposalID, u32>,
+    votes: HashMap<ProposalID, HashSet<usize>>,
+    chosen: Option<u32>,
+    learned: Vec<Option<u32>>,
+}
+
+#[derive(Clone, Debug)]
+enum Action {
+    Prepare(usize),
+    Deliver(usize),
+    Duplicate(usize),
+    Drop(usize),
+}
+
+fn arb_action() -> impl Strategy<Value = Action> {
+    prop_oneof![
+        1 => (0..5usize).prop_map(Action::Prepare),
+        8 => any::<usize>().prop_map(Action::Deliver),
+        1 => any::<usize>().prop_map(Action::Duplicate),
+        1 => any::<usize>().prop_map(Action::Drop),
+    ]
+}
+
+impl Cluster {
+    fn new(cluster_size: usize) -> Cluster {
+        let id = |i| format!("node{}", i);
+        Cluster {
+            majority: cluster_size / 2 + 1,
+            proposers: (0..cluster_size).map(|i| {
+                let mut p = Proposer::new(1, id(i), cluster_size);
+                p.set_value(i as u32);
+                p
+            }).collect(),
+            acceptors: (0..cluster_size).map(|i| Acceptor::new(1, id(i))).collect(),
+            learners: (0..cluster_size).map(|i| Learner::new(1, id(i), cluster_size)).collect(),
+            in_flight: Vec::new(),
+            promised: vec![None; cluster_size],
+            proposals: HashMap::new(),
+            votes: HashMap::new(),
+            chosen: None,
+            learned: vec![None; cluster_size],
+        }
+    }
+
+    fn node(id: &NodeID) -> usize {
+        id["node".len()..].parse().unwrap()
+    }
+
+    fn broadcast(&mut self, message: PaxosInstanceMessage<u32>) {
+        for to in 0..self.proposers.len() {
+            self.in_flight.push((to, message.clone()));
+        }
+    }
+
+    fn apply(&mut self, action: &Action, rng: &mut StepRng) -> Result<(), TestCaseError> {
+        match *action {
+            Action::Prepare(node) => {
+                let node = node % self.proposers.len();
+                let prepare = self.proposers[node].prepare(rng);
+                self.broadcast(PaxosInstanceMessage::Prepare(prepare));
+            },
+            Action::Drop(i) if !self.in_flight.is_empty() => {
+                let i = i % self.in_flight.len();
+                self.in_flight.remove(i);
+            },
+            Action::Deliver(i) | Action::Duplicate(i) if !self.in_flight.is_empty() => {
+                let i = i % self.in_flight.len();
+                let (to, message) = if let Action::Duplicate(_) = *action {
+                    self.in_flight[i].clone()
+                } else {
+                    self.in_flight.remove(i)
+                };
+                self.deliver(to, message)?;
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn deliver(&mut self, to: usize, message: PaxosInstanceMessage<u32>) -> Result<(), TestCaseError> {
+        match message {
+            PaxosInstanceMessage::Prepare(ref prepare) => {
+                self.proposers[to].observe_proposal(&prepare.proposal_id);
+                if let Some(promise) = self.acceptors[to].receive_prepare(prepare) {
+                    prop_assert!(self.promised[to].as_ref().is_none_or(|p| prepare.proposal_id >= *p));
+                    self.promised[to] = Some(prepare.proposal_id.clone());
+                    let from = Cluster::node(&prepare.proposer_id);
+                    self.in_flight.push((from, PaxosInstanceMessage::Promise(promise)));
+                }
+            },
+            PaxosInstanceMessage::Promise(ref promise) => {
+                if let Some(propose) = self.proposers[to].receive_promise(promise) {
+                    self.proposals.insert(propose.proposal_id.clone(), propose.value);
+                    self.broadcast(PaxosInstanceMessage::Propose(propose));
+                }
+            },
+            PaxosInstanceMessage::Propose(ref propose) => {
+                self.proposers[to].observe_proposal(&propose.proposal_id);
+                if let Some(accepted) = self.acceptors[to].receive_propose(propose) {
+                    // an acceptor never accepts below its promise
+                    prop_assert!(self.promised[to].as_ref().is_none_or(|p| propose.proposal_id >= *p));
+                    self.promised[to] = Some(propose.proposal_id.clone());
+
+                    // a chosen value is never changed
+                    let votes = self.votes.entry(propose.proposal_id.clone()).or_default();
+                    votes.insert(to);
+                    if votes.len() >= self.majority {
+                        if let Some(chosen) = self.chosen {
+                            prop_assert_eq!(chosen, propose.value);
+                        }
+                        self.chosen = Some(propose.value);
+                    }
+                    self.broadcast(PaxosInstanceMessage::Accepted(accepted));
+                }
+            },
+            PaxosInstanceMessage::Accepted(ref accepted) if self.learners[to].receive_accepted(accepted).is_some() => {
+                let value = self.proposals[&accepted.proposal_id];
+                self.learners[to].set_chosen_value(value);
+                self.learned[to] = Some(value);
+                // learners agree with each other and with the chosen value
+                prop_assert_eq!(self.chosen, Some(value));
+            },
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(512))]
+
+    #[test]
+    fn cluster_agrees_under_any_interleaving(cluster_size in 1..6usize,
+                                             actions in prop::collection::vec(arb_action(), 0..200)) {
+        let mut cluster = Cluster::new(cluster_size);
+        let mut rng = StepRng::new(0, 1);
+        for action in &actions {
+            cluster.apply(action, &mut rng)?;
+        }
+        let learned: HashSet<_> = cluster.learned.iter().filter_map(|v| *v).collect();
+        prop_assert!(learned.len() <= 1);
+    }
+}
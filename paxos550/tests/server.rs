@@ -0,0 +1,521 @@
+extern crate paxos550;
+extern crate serde;
+extern crate serde_yaml;
+
+use paxos550::client::LockClientBuilder;
+use paxos550::env::ManualClock;
+use paxos550::locker::{hash_locks, Digest, LogEntry, Operation};
+use paxos550::message::{Applied, Chunk, MessagePayload, Page, Query, Reassembly, MAX_CHUNKS};
+use paxos550::server::*;
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Free local addresses for the cluster, so that tests can run in parallel.
+fn addresses(count: usize) -> Vec<SocketAddr> {
+    let sockets: Vec<_> = (0..count).map(|_| UdpSocket::bind("127.0.0.1:0").unwrap()).collect();
+    sockets.iter().map(|s| s.local_addr().unwrap()).collect()
+}
+
+fn lock(key: &str) -> Operation {
+    Operation::Lock(key.to_string(), "client".to_string())
+}
+
+/// The operations of each instance `storage` saved.
+fn logged(storage: &MemoryStorage) -> Vec<(usize, Vec<Operation>)> {
+    storage.log().into_iter()
+        .map(|(id, batch)| (id, batch.into_iter().map(|request| request.op).collect()))
+        .collect()
+}
+
+/// Starts `count` servers that know each other. `configure` can change the builder of each.
+fn cluster<F: Fn(usize, ServerBuilder) -> ServerBuilder>(count: usize, configure: F) -> Vec<ServerHandle> {
+    let addrs = addresses(count);
+    (0..count).map(|i| {
+        let builder = ServerBuilder::new(format!("node{}", i), addrs[i]).timeout(Duration::from_millis(100));
+        let mut builder = configure(i, builder);
+        for (j, &addr) in addrs.iter().enumerate() {
+            if j != i {
+                builder = builder.peer(format!("node{}", j), addr);
+            }
+        }
+        let mut server = builder.build().unwrap();
+        server.start().unwrap();
+        server
+    }).collect()
+}
+
+fn wait_until<F: Fn() -> bool>(condition: F) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timed out");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn servers_in_one_process_agree() {
+    let storages = vec![MemoryStorage::new(); 3];
+    let servers = cluster(3, |i, b| b.storage(Box::new(storages[i].clone())));
+
+    servers[0].propose(lock("a")).unwrap();
+    wait_until(|| servers.iter().all(|s| s.status().applied == 1));
+    servers[2].propose(lock("b")).unwrap();
+    wait_until(|| servers.iter().all(|s| s.status().applied == 2));
+
+    for storage in &storages {
+        assert_eq!(logged(storage), vec![(1, vec![lock("a")]), (2, vec![lock("b")])]);
+    }
+}
+
+#[test]
+fn timers_follow_the_clock_of_the_server() {
+    let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+    peer.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let clock = ManualClock::new();
+    let mut server = ServerBuilder::new("node0".to_string(), addresses(1)[0])
+        .peer("node1".to_string(), peer.local_addr().unwrap())
+        .timeout(Duration::from_millis(100))
+        .batch_size(1)
+        .clock(Box::new(clock.clone()))
+        .build().unwrap();
+    server.start().unwrap();
+    server.propose(lock("a")).unwrap();
+
+    // the silent peer gets one Prepare, and no retry until the clock passes the timeout
+    let mut buf = vec![0u8; 65536];
+    let prepare = |buf: &mut [u8]| {
+        let (size, _) = peer.recv_from(buf).unwrap();
+        serde_yaml::from_slice::<MessagePayload<Operation>>(&buf[..size]).unwrap()
+    };
+    assert!(matches!(prepare(&mut buf), MessagePayload::PaxosMessage(_)));
+    assert!(peer.recv_from(&mut buf).is_err());
+    clock.advance(Duration::from_secs(1));
+    assert!(matches!(prepare(&mut buf), MessagePayload::PaxosMessage(_)));
+}
+
+#[test]
+fn operations_are_batched() {
+    let storages = vec![MemoryStorage::new(); 3];
+    let servers = cluster(3, |i, b| b.storage(Box::new(storages[i].clone())).batch_delay(Duration::from_secs(1)));
+    let ops: Vec<_> = (0..10).map(|i| lock(&format!("key{}", i))).collect();
+    for op in &ops {
+        servers[0].propose(op.clone()).unwrap();
+    }
+    wait_until(|| servers.iter().all(|s| s.status().applied_operations == 10));
+
+    for (server, storage) in servers.iter().zip(&storages) {
+        assert_eq!(server.status().applied, 1);
+        assert_eq!(logged(storage), vec![(1, ops.clone())]);
+    }
+}
+
+#[test]
+fn concurrent_proposers_lose_no_operations() {
+    let storages = vec![MemoryStorage::new(); 3];
+    let servers = cluster(3, |i, b| b.storage(Box::new(storages[i].clone())).batch_size(1));
+    // every server proposes for the same instances at the same time
+    let mut ops = Vec::new();
+    for i in 0..10 {
+        for (j, server) in servers.iter().enumerate() {
+            let op = lock(&format!("key{}-{}", i, j));
+            server.propose(op.clone()).unwrap();
+            ops.push(op);
+        }
+    }
+    wait_until(|| servers.iter().all(|s| s.status().applied_operations == ops.len()));
+
+    for storage in &storages {
+        let mut applied: Vec<_> = logged(storage).into_iter().flat_map(|(_, batch)| batch).collect();
+        applied.sort_by_key(|op| format!("{:?}", op));
+        ops.sort_by_key(|op| format!("{:?}", op));
+        assert_eq!(applied, ops);
+    }
+}
+
+#[test]
+fn window_bounds_undecided_instances() {
+    let servers = cluster(3, |_, b| b.batch_size(1).window(2));
+    for i in 0..20 {
+        servers[0].propose(lock(&format!("key{}", i))).unwrap();
+    }
+    wait_until(|| servers.iter().all(|s| s.status().applied == 20));
+
+    let status = servers[0].status();
+    assert_eq!(status.peak_in_flight, 2);
+    assert_eq!(status.in_flight, 0);
+    assert_eq!(status.queued_batches, 0);
+}
+
+#[test]
+fn adaptive_timeouts_follow_round_trip_times() {
+    let slow = Duration::from_secs(5);
+    let servers = cluster(3, |_, b| b.timeout(slow).adaptive_timeouts(true));
+    // local round trips are far below the configured timeout
+    wait_until(|| servers.iter().all(|s| {
+        let status = s.status();
+        status.round_trip_times.len() == 2 && status.prepare_timeout < slow
+    }));
+    servers[0].propose(lock("a")).unwrap();
+    wait_until(|| servers.iter().all(|s| s.status().applied == 1));
+
+    let fixed = cluster(1, |_, b| {
+        b.prepare_timeout(Duration::from_millis(100))
+            .propose_timeout(Duration::from_millis(200))
+            .learn_timeout(Duration::from_millis(300))
+    });
+    wait_until(|| fixed[0].status().learn_timeout == Duration::from_millis(300));
+    let status = fixed[0].status();
+    assert!(status.round_trip_times.is_empty());
+    assert_eq!((status.prepare_timeout, status.propose_timeout), (Duration::from_millis(100), Duration::from_millis(200)));
+}
+
+#[test]
+fn clients_get_a_reply_per_operation() {
+    let servers = cluster(3, |_, b| b);
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    // the same request sent twice is proposed once
+    let other = Operation::Lock("a".to_string(), "other".to_string());
+    for (request, op) in [(1, lock("a")), (1, lock("a")), (2, other.clone())] {
+        let message: MessagePayload<Operation> = MessagePayload::LockerMessage(request, op);
+        client.send_to(&serde_yaml::to_vec(&message).unwrap(), servers[1].local_addr()).unwrap();
+    }
+
+    // the second lock fails, since the first one holds the key
+    let mut buf = [0u8; 1024];
+    let mut replies = Vec::new();
+    for _ in 0..2 {
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        let applied: Applied = serde_yaml::from_slice(&buf[..size]).unwrap();
+        assert_eq!(applied.entry.op, if applied.request == 1 { lock("a") } else { other.clone() });
+        replies.push((applied.request, applied.entry.valid));
+    }
+    replies.sort();
+    assert_eq!(replies, vec![(1, true), (2, false)]);
+    wait_until(|| servers[1].status().applied_operations == 2);
+}
+
+#[test]
+fn requests_sent_to_two_servers_are_applied_once() {
+    let servers = cluster(3, |_, b| b);
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let mut buf = [0u8; 1024];
+    let mut ask = |server: &ServerHandle, request, op| {
+        let message: MessagePayload<Operation> = MessagePayload::LockerMessage(request, op);
+        client.send_to(&serde_yaml::to_vec(&message).unwrap(), server.local_addr()).unwrap();
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        serde_yaml::from_slice::<Applied>(&buf[..size]).unwrap().entry.valid
+    };
+    assert!(ask(&servers[0], 1, lock("a")));
+    assert!(ask(&servers[0], 2, Operation::Unlock("a".to_string(), "client".to_string())));
+    // the lock again, as if the reply to the first had not come: it does not take the lock again
+    assert!(ask(&servers[1], 1, lock("a")));
+    wait_until(|| servers.iter().all(|s| s.status().applied_operations == 3));
+    let mut digests = servers.iter().map(|server| {
+        let message: MessagePayload<Operation> = MessagePayload::Digest(0, 0);
+        client.send_to(&serde_yaml::to_vec(&message).unwrap(), server.local_addr()).unwrap();
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        serde_yaml::from_slice::<Digest>(&buf[..size]).unwrap()
+    });
+    assert!(digests.all(|digest| digest.entries == 2 && digest.locks == hash_locks(&HashMap::new()).unwrap()));
+}
+
+fn http_get(addr: SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn metrics_are_served_over_http() {
+    let local = "127.0.0.1:0".parse().unwrap();
+    let servers = cluster(3, |i, b| if i == 0 { b.metrics(Some(local)) } else { b });
+    let metrics_addr = servers[0].metrics_addr().unwrap();
+    assert!(servers[1].metrics_addr().is_none());
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let request = serde_yaml::to_vec(&MessagePayload::<Vec<Operation>>::LockerMessage(1, lock("a"))).unwrap();
+    client.send_to(&request, servers[0].local_addr()).unwrap();
+    client.recv_from(&mut [0u8; 1024]).unwrap();
+    client.send_to(b"not a message: [", servers[0].local_addr()).unwrap();
+    wait_until(|| http_get(metrics_addr, "/metrics").contains("paxos550_decode_failures_total 1"));
+
+    let response = http_get(metrics_addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    for line in &["paxos550_instances_started_total 1", "paxos550_instances_decided_total 1",
+                  "paxos550_proposal_rounds_count 1", "paxos550_applied_index 1", "paxos550_highest_instance 1",
+                  "paxos550_locks_held 1", "paxos550_lock_acquisition_seconds_count 1",
+                  "paxos550_in_flight 0", "paxos550_peak_in_flight 1", "paxos550_queued_batches 0",
+                  "paxos550_messages_received_total{peer=\"client\"} 1", "paxos550_messages_sent_total{peer=\"node1\"}"] {
+        assert!(response.contains(line), "no {} in {}", line, response);
+    }
+    assert!(http_get(metrics_addr, "/").starts_with("HTTP/1.1 404"));
+}
+
+#[test]
+fn clients_ask_for_the_status_of_a_server() {
+    let servers = cluster(3, |_, b| b);
+    servers[0].propose(lock("a")).unwrap();
+    wait_until(|| servers.iter().all(|s| s.status().applied == 1));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let request = serde_yaml::to_vec(&MessagePayload::<Vec<Operation>>::Status).unwrap();
+    client.send_to(&request, servers[1].local_addr()).unwrap();
+    let mut buf = vec![0u8; 65536];
+    let (size, _) = client.recv_from(&mut buf).unwrap();
+    let status: Status = serde_yaml::from_slice(&buf[..size]).unwrap();
+
+    assert_eq!(status.node_id, "node1");
+    assert_eq!(status.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(status.peers.len(), 2);
+    assert!(status.peers["node0"].last_heard.is_some());
+    assert_eq!(status.peers["node2"].address, servers[2].local_addr());
+    assert_eq!((status.highest_decided, status.next_log_to_apply), (1, 2));
+    assert!(status.undecided.is_empty());
+    assert_eq!(status.storage_bytes, None);
+}
+
+#[test]
+fn servers_redirect_clients_to_the_leader() {
+    let mut servers = cluster(3, |_, b| b.redirect_clients(true));
+    wait_until(|| servers.iter().all(|s| s.status().leader == Some("node0".to_string())));
+
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    let message: MessagePayload<Operation> = MessagePayload::LockerMessage(7, lock("a"));
+    let request = serde_yaml::to_vec(&message).unwrap();
+    client.send_to(&request, servers[2].local_addr()).unwrap();
+    let mut buf = vec![0u8; 65536];
+    let (size, _) = client.recv_from(&mut buf).unwrap();
+    let redirect: MessagePayload<Operation> = serde_yaml::from_slice(&buf[..size]).unwrap();
+    assert!(matches!(redirect, MessagePayload::Redirect(leader, addr)
+                     if leader == "node0" && addr == servers[0].local_addr()));
+
+    client.send_to(&request, servers[0].local_addr()).unwrap();
+    let (size, _) = client.recv_from(&mut buf).unwrap();
+    let applied: Applied = serde_yaml::from_slice(&buf[..size]).unwrap();
+    assert_eq!((applied.request, applied.entry.op, applied.entry.valid), (7, lock("a"), true));
+
+    // the next lowest ID takes over
+    servers[0].shutdown().unwrap();
+    wait_until(|| servers[1..].iter().all(|s| s.status().leader == Some("node1".to_string())));
+}
+
+/// Sends a `MessagePayload::Faults` to `addr` and returns the reply, or `None` if none came in time.
+fn faults(addr: SocketAddr, policy: Option<FaultPolicy>, timeout: Duration)
+        -> Option<std::result::Result<FaultPolicy, String>> {
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(timeout)).unwrap();
+    let request: MessagePayload<Operation> = MessagePayload::Faults(policy);
+    client.send_to(&serde_yaml::to_vec(&request).unwrap(), addr).unwrap();
+    let mut buf = vec![0u8; 65536];
+    client.recv_from(&mut buf).ok().map(|(size, _)| serde_yaml::from_slice(&buf[..size]).unwrap())
+}
+
+#[test]
+fn servers_inject_the_faults_they_are_told_to() {
+    let timeout = Duration::from_secs(10);
+    let server = cluster(1, |_, b| b);
+    assert!(matches!(faults(server[0].local_addr(), None, timeout), Some(Err(_))));
+
+    let servers = cluster(3, |_, b| b.fault_injection(true));
+    let isolated: FaultPolicy = serde_yaml::from_str("default: {disconnected: true}").unwrap();
+    assert_eq!(faults(servers[0].local_addr(), Some(isolated.clone()), timeout), Some(Ok(isolated.clone())));
+    assert_eq!(faults(servers[0].local_addr(), None, timeout), Some(Ok(isolated)));
+    let invalid: FaultPolicy = serde_yaml::from_str("peers: {node1: {drop_rate: 2}}").unwrap();
+    assert!(matches!(faults(servers[1].local_addr(), Some(invalid), timeout), Some(Err(_))));
+    let slow: FaultPolicy = serde_yaml::from_str(&format!("default: {{max_delay_ms: {}}}", u64::MAX)).unwrap();
+    assert!(matches!(faults(servers[1].local_addr(), Some(slow), timeout), Some(Err(_))));
+
+    // node0 hears from nobody, while the other two are a majority
+    servers[0].propose(lock("a")).unwrap();
+    servers[1].propose(lock("b")).unwrap();
+    wait_until(|| servers[1..].iter().all(|s| s.status().applied == 1));
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(servers[0].status().applied, 0);
+
+    // a paused server answers nothing but new faults, and proposes nothing, until it is resumed
+    let paused = FaultPolicy { paused: true, ..FaultPolicy::default() };
+    assert!(faults(servers[1].local_addr(), Some(paused), timeout).is_some());
+    servers[1].propose(lock("d")).unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(300))).unwrap();
+    let request = serde_yaml::to_vec(&MessagePayload::<Operation>::Status).unwrap();
+    client.send_to(&request, servers[1].local_addr()).unwrap();
+    let mut buf = vec![0u8; 65536];
+    assert!(client.recv_from(&mut buf).is_err());
+    servers[2].propose(lock("c")).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(servers[2].status().applied, 1);
+    assert_eq!(servers[1].status().applied, 1);
+
+    let lossy: FaultPolicy = serde_yaml::from_str(
+        "default: {duplicate_rate: 1.0, min_delay_ms: 10, max_delay_ms: 50}").unwrap();
+    assert!(faults(servers[1].local_addr(), Some(lossy), timeout).is_some());
+    client.set_read_timeout(Some(timeout)).unwrap();
+    let (size, _) = client.recv_from(&mut buf).unwrap();
+    assert_eq!(serde_yaml::from_slice::<Status>(&buf[..size]).unwrap().node_id, "node1");
+    wait_until(|| servers[1..].iter().all(|s| s.status().applied == 3));
+}
+
+/// Sends `request` and puts the chunks of the reply back together, in reverse order.
+fn ask_in_chunks<T: serde::de::DeserializeOwned>(addr: SocketAddr, request: MessagePayload<Operation>, id: u64)
+        -> (Page<T>, usize) {
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    client.send_to(&serde_yaml::to_vec(&request).unwrap(), addr).unwrap();
+    let mut buf = vec![0u8; 65536];
+    let (size, _) = client.recv_from(&mut buf).unwrap();
+    let first: Chunk = serde_yaml::from_slice(&buf[..size]).unwrap();
+    let mut chunks = vec![first.clone()];
+    while chunks.len() < first.count {
+        let (size, _) = client.recv_from(&mut buf).unwrap();
+        chunks.push(serde_yaml::from_slice(&buf[..size]).unwrap());
+    }
+    let count = chunks.len();
+    let mut reassembly = Reassembly::new(id);
+    assert_eq!(reassembly.add(Chunk { request: id + 1, ..first.clone() }), None);
+    assert_eq!(reassembly.add(Chunk { count: MAX_CHUNKS + 1, ..first }), None);
+    let mut text = None;
+    for chunk in chunks.into_iter().rev() {
+        assert!(text.is_none());
+        text = reassembly.add(chunk);
+    }
+    (serde_yaml::from_str(&text.unwrap()).unwrap(), count)
+}
+
+#[test]
+fn large_logs_are_paged_and_sent_in_chunks() {
+    let servers = cluster(1, |_, b| b);
+    let keys: Vec<_> = (0..500).map(|i| format!("key{:03}", i)).collect();
+    for key in &keys {
+        servers[0].propose(lock(key)).unwrap();
+    }
+    servers[0].propose(Operation::Unlock("key007".to_string(), "client".to_string())).unwrap();
+    wait_until(|| servers[0].status().applied_operations == 501);
+    let addr = servers[0].local_addr();
+
+    let (log, chunks): (Page<(usize, LogEntry)>, _) = ask_in_chunks(addr, MessagePayload::PrintLog(1, Query::default()), 1);
+    assert!(chunks > 1, "the log fits in {} chunk", chunks);
+    assert_eq!((log.total, log.items.len(), log.next_offset()), (501, 501, None));
+    assert_eq!((log.items[42].0, &log.items[42].1.op, log.items[42].1.valid), (42, &lock("key042"), true));
+
+    let query = Query { key: Some("key007".to_string()), offset: 1, limit: None };
+    let (log, _): (Page<(usize, LogEntry)>, _) = ask_in_chunks(addr, MessagePayload::PrintLog(2, query), 2);
+    assert_eq!((log.total, log.items.len()), (2, 1));
+    assert_eq!(log.items[0].0, 500);
+
+    let query = Query { offset: 10, limit: Some(5), key: None };
+    let (locks, chunks): (Page<(String, String)>, _) = ask_in_chunks(addr, MessagePayload::PrintLocks(3, query), 3);
+    assert_eq!(chunks, 1);
+    assert_eq!((locks.total, locks.next_offset()), (499, Some(15)));
+    let keys: Vec<_> = locks.items.iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, vec!["key011", "key012", "key013", "key014", "key015"]);
+}
+
+#[test]
+fn lost_chunks_are_sent_again() {
+    let servers = cluster(1, |_, b| b);
+    for i in 0..100 {
+        servers[0].propose(lock(&format!("a long key to make a long log {:04}", i))).unwrap();
+    }
+    wait_until(|| servers[0].status().applied_operations == 100);
+    let addr = servers[0].local_addr();
+    let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let mut buf = vec![0u8; 65536];
+    let mut receive = || -> Option<Chunk> {
+        let (size, _) = client.recv_from(&mut buf).ok()?;
+        Some(serde_yaml::from_slice(&buf[..size]).unwrap())
+    };
+    let ask = |request: MessagePayload<Operation>, from: &UdpSocket| {
+        from.send_to(&serde_yaml::to_vec(&request).unwrap(), addr).unwrap();
+    };
+
+    ask(MessagePayload::PrintLog(7, Query::default()), &client);
+    let mut count = 0;
+    while let Some(chunk) = receive() {
+        assert_eq!(chunk.request, 7);
+        count += 1;
+    }
+    assert!(count > 2, "the log fits in {} chunks", count);
+
+    // each chunk once, and only to the client that asked for the query
+    ask(MessagePayload::Resend(7, vec![2, 0, 2, count + 10]), &UdpSocket::bind("127.0.0.1:0").unwrap());
+    ask(MessagePayload::Resend(7, vec![2, 0, 2, count + 10]), &client);
+    let mut indexes: Vec<_> = std::iter::from_fn(&mut receive).map(|chunk| chunk.index).collect();
+    indexes.sort();
+    assert_eq!(indexes, vec![0, 2]);
+}
+
+#[test]
+fn large_replies_arrive_whole() {
+    let servers = cluster(1, |_, b| b);
+    for i in 0..5000 {
+        servers[0].propose(lock(&format!("a long key to make a long log {:04}", i))).unwrap();
+    }
+    wait_until(|| servers[0].status().applied_operations == 5000);
+
+    // more chunks than a receive buffer holds
+    let mut client = LockClientBuilder::new("client".to_string())
+        .server("node0".to_string(), servers[0].local_addr())
+        .attempts(1)
+        .build()
+        .unwrap();
+    let log = client.log(Query::default()).unwrap();
+    assert_eq!((log.total, log.items.len()), (5000, 5000));
+}
+
+#[test]
+fn shutdown_stops_the_server() {
+    let mut servers = cluster(3, |_, b| b);
+    servers[0].shutdown().unwrap();
+    assert!(servers[0].propose(lock("a")).is_err());
+
+    // the other two are still a majority
+    servers[1].propose(lock("a")).unwrap();
+    wait_until(|| servers[1..].iter().all(|s| s.status().applied == 1));
+    assert_eq!(servers[0].status().applied, 0);
+}
+
+#[test]
+fn restarted_servers_catch_up_on_what_they_missed() {
+    // clones share their log, so each server needs a storage of its own
+    let storages: Vec<_> = (0..3).map(|_| MemoryStorage::new()).collect();
+    let addrs = addresses(3);
+    let start = |i: usize| {
+        let builder = ServerBuilder::new(format!("node{}", i), addrs[i])
+            .timeout(Duration::from_millis(100))
+            .storage(Box::new(storages[i].clone()));
+        let mut server = (0..3).filter(|&j| j != i).fold(builder, |builder, j| builder.peer(format!("node{}", j), addrs[j]))
+            .build()
+            .unwrap();
+        server.start().unwrap();
+        server
+    };
+    let mut servers: Vec<_> = (0..3).map(start).collect();
+    servers[1].propose(lock("a")).unwrap();
+    wait_until(|| servers.iter().all(|s| s.status().applied == 1));
+
+    servers[0].shutdown().unwrap();
+    for (i, key) in ["b", "c", "d"].iter().enumerate() {
+        servers[1].propose(lock(key)).unwrap();
+        wait_until(|| servers[1].status().applied == i + 2);
+    }
+
+    // node0 applies its own log again, and fills the gaps up to the next instance it hears of
+    servers[0] = start(0);
+    assert_eq!(servers[0].status().applied, 1);
+    servers[2].propose(lock("e")).unwrap();
+    wait_until(|| servers.iter().all(|s| s.status().applied == 5));
+    assert_eq!(logged(&storages[0]), logged(&storages[1]));
+    assert_eq!(logged(&storages[0]).len(), 5);
+}